@@ -0,0 +1,473 @@
+use crate::opcodes::{Opcode, PRINT_WIDTH};
+use std::collections::HashMap;
+use std::fmt;
+
+// the text format has one opcode per line, loop bodies are indented and ; starts a comment
+//
+//     CHANGE +3
+//     JUMPIFZERO loop0
+//         MULTI @1 *2
+//         SETTO 0
+//         CHANGE -1 @2
+//     JUMPIFNZERO loop0
+//     PUTCHAR 1 @1
+//     PRINT 72 105 ; "Hi"
+//
+// a jump names the loop it belongs to, so the opening and closing jump of a loop share a label
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum AssemblyErrorKind {
+    UnknownOpcode(String),
+    MissingOperand,
+    BadOperand(String),
+    ExtraOperand(String),
+    // a JUMPIFNZERO (or ENDIF) that closes a different loop than the innermost open one, or none
+    UnmatchedClose(String),
+    UnclosedLoop(String),
+    DuplicateLabel(String),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AssemblyError {
+    // 1 based, like in an editor
+    pub line: usize,
+    pub kind: AssemblyErrorKind,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+
+        match &self.kind {
+            AssemblyErrorKind::UnknownOpcode(x) => write!(f, "unknown opcode {:?}", x),
+            AssemblyErrorKind::MissingOperand => write!(f, "missing operand"),
+            AssemblyErrorKind::BadOperand(x) => write!(f, "bad operand {:?}", x),
+            AssemblyErrorKind::ExtraOperand(x) => write!(f, "unexpected operand {:?}", x),
+            AssemblyErrorKind::UnmatchedClose(x) => {
+                write!(f, "closing {} does not match the innermost open loop", x)
+            }
+            AssemblyErrorKind::UnclosedLoop(x) => write!(f, "loop {} is never closed", x),
+            AssemblyErrorKind::DuplicateLabel(x) => write!(f, "label {} is used twice", x),
+        }
+    }
+}
+
+fn signed(value: i32) -> String {
+    format!("{:+}", value)
+}
+
+fn with_offset(text: String, offset: i32) -> String {
+    // the offset is left out when the opcode works on the cell under the data pointer
+    if offset == 0 {
+        text
+    } else {
+        format!("{} @{}", text, offset)
+    }
+}
+
+fn opcode_text(op: Opcode, label: &str) -> String {
+    match op {
+        Opcode::CHANGE { arg, offset } => with_offset(format!("CHANGE {}", signed(arg)), offset),
+        Opcode::MOVE { arg } => format!("MOVE {}", signed(arg)),
+        Opcode::PUTCHAR { arg, offset } => with_offset(format!("PUTCHAR {}", arg), offset),
+        Opcode::READCHAR { arg, offset } => with_offset(format!("READCHAR {}", arg), offset),
+        Opcode::JUMPIFZERO { .. } => format!("JUMPIFZERO {}", label),
+        Opcode::JUMPIFNZERO { .. } => format!("JUMPIFNZERO {}", label),
+        Opcode::ENDIF { .. } => format!("ENDIF {}", label),
+        Opcode::PRODUCT { arg1, arg2, arg3 } => format!("PRODUCT @{} @{} *{}", arg1, arg2, arg3),
+        Opcode::TRIANGLE { arg1, arg2 } => format!("TRIANGLE @{} *{}", arg1, arg2),
+        Opcode::COPY { arg1, arg2 } => format!("COPY @{} @{}", arg1, arg2),
+        Opcode::CARRY { arg1, arg2, arg3 } => format!("CARRY @{} @{} >={}", arg1, arg2, arg3),
+        Opcode::AND { arg1, arg2 } => format!("AND @{} @{}", arg1, arg2),
+        Opcode::COUNTDOWN { arg1, arg2 } => format!("COUNTDOWN @{} @{}", arg1, arg2),
+        Opcode::DIVMOD { arg1, arg2, arg3 } => format!("DIVMOD @{} @{} @{}", arg1, arg2, arg3),
+        Opcode::SETTO { arg, offset } => with_offset(format!("SETTO {}", arg), offset),
+        Opcode::SCANBY { arg } => format!("SCANBY {}", signed(arg)),
+        Opcode::MULTI { arg1, arg2 } => format!("MULTI @{} *{}", arg1, arg2),
+        Opcode::MOVINGCHANGE { arg1, arg2, arg3 } => format!(
+            "MOVINGCHANGE {} {} {}",
+            signed(arg1),
+            signed(arg2),
+            signed(arg3)
+        ),
+        Opcode::MOVINGSET { arg1, arg2, arg3 } => {
+            format!("MOVINGSET {} {} {}", signed(arg1), arg2, signed(arg3))
+        }
+        Opcode::PRINT { bytes, len } => {
+            // the text is only there to read, the numbers are what gets assembled
+            let bytes = &bytes[..usize::from(len)];
+            let numbers = bytes.iter().map(u8::to_string).collect::<Vec<_>>();
+            format!(
+                "PRINT {} ; {:?}",
+                numbers.join(" "),
+                String::from_utf8_lossy(bytes)
+            )
+        }
+    }
+}
+
+pub fn disassemble(opcodes: &[Opcode]) -> String {
+    disassemble_lines(opcodes)
+        .into_iter()
+        .map(|line| line + "\n")
+        .collect()
+}
+
+pub fn disassemble_lines(opcodes: &[Opcode]) -> Vec<String> {
+    // one indented line for each opcode, loops are named in the order they open, a closing jump
+    // gets the name of the jump it points back at
+    let mut labels = HashMap::new();
+    let mut output = Vec::with_capacity(opcodes.len());
+    let mut depth: usize = 0;
+
+    for (pos, &op) in opcodes.iter().enumerate() {
+        let label = match op {
+            Opcode::JUMPIFZERO { .. } => {
+                let label = format!("loop{}", labels.len());
+                labels.insert(pos, label.clone());
+                label
+            }
+            Opcode::JUMPIFNZERO { arg } | Opcode::ENDIF { arg } => {
+                depth = depth.saturating_sub(1);
+                labels
+                    .get(&arg)
+                    .cloned()
+                    .unwrap_or_else(|| format!("missing{}", arg))
+            }
+            _ => String::new(),
+        };
+
+        output.push(INDENT.repeat(depth) + &opcode_text(op, &label));
+
+        if matches!(op, Opcode::JUMPIFZERO { .. }) {
+            depth += 1;
+        }
+    }
+
+    output
+}
+
+struct Operands<'a> {
+    line: usize,
+    words: std::slice::Iter<'a, &'a str>,
+}
+
+impl<'a> Operands<'a> {
+    const fn error(&self, kind: AssemblyErrorKind) -> AssemblyError {
+        AssemblyError {
+            line: self.line,
+            kind,
+        }
+    }
+
+    fn next_word(&mut self) -> Result<&'a str, AssemblyError> {
+        self.words
+            .next()
+            .copied()
+            .ok_or_else(|| self.error(AssemblyErrorKind::MissingOperand))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, prefix: &str) -> Result<T, AssemblyError> {
+        let word = self.next_word()?;
+
+        word.strip_prefix(prefix)
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| self.error(AssemblyErrorKind::BadOperand(word.to_string())))
+    }
+
+    fn offset(&mut self) -> Result<i32, AssemblyError> {
+        // an offset is optional and always comes last
+        if self.words.as_slice().is_empty() {
+            return Ok(0);
+        }
+
+        self.number("@")
+    }
+
+    fn finish(&mut self) -> Result<(), AssemblyError> {
+        if let Some(word) = self.words.next() {
+            return Err(self.error(AssemblyErrorKind::ExtraOperand(word.to_string())));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn assemble(text: &str) -> Result<Vec<Opcode>, AssemblyError> {
+    let mut opcodes = vec![];
+    // the open loops, innermost last, with the line they were opened on
+    let mut loop_stack: Vec<(String, usize, usize)> = vec![];
+    let mut used_labels: HashMap<String, usize> = HashMap::new();
+
+    for (line_number, line) in text.lines().enumerate() {
+        let line_number = line_number + 1;
+        let code = line.split(';').next().unwrap_or_default();
+        let words = code.split_whitespace().collect::<Vec<_>>();
+
+        let Some((&name, rest)) = words.split_first() else {
+            continue;
+        };
+
+        let mut operands = Operands {
+            line: line_number,
+            words: rest.iter(),
+        };
+
+        let op = match name {
+            "CHANGE" => Opcode::CHANGE {
+                arg: operands.number("")?,
+                offset: operands.offset()?,
+            },
+            "MOVE" => Opcode::MOVE {
+                arg: operands.number("")?,
+            },
+            "PUTCHAR" => Opcode::PUTCHAR {
+                arg: operands.number("")?,
+                offset: operands.offset()?,
+            },
+            "READCHAR" => Opcode::READCHAR {
+                arg: operands.number("")?,
+                offset: operands.offset()?,
+            },
+            "SETTO" => Opcode::SETTO {
+                arg: operands.number("")?,
+                offset: operands.offset()?,
+            },
+            "SCANBY" => Opcode::SCANBY {
+                arg: operands.number("")?,
+            },
+            "MULTI" => Opcode::MULTI {
+                arg1: operands.number("@")?,
+                arg2: operands.number("*")?,
+            },
+            "MOVINGCHANGE" => Opcode::MOVINGCHANGE {
+                arg1: operands.number("")?,
+                arg2: operands.number("")?,
+                arg3: operands.number("")?,
+            },
+            "MOVINGSET" => Opcode::MOVINGSET {
+                arg1: operands.number("")?,
+                arg2: operands.number("")?,
+                arg3: operands.number("")?,
+            },
+            "PRODUCT" => Opcode::PRODUCT {
+                arg1: operands.number("@")?,
+                arg2: operands.number("@")?,
+                arg3: operands.number("*")?,
+            },
+            "TRIANGLE" => Opcode::TRIANGLE {
+                arg1: operands.number("@")?,
+                arg2: operands.number("*")?,
+            },
+            "COPY" => Opcode::COPY {
+                arg1: operands.number("@")?,
+                arg2: operands.number("@")?,
+            },
+            "CARRY" => Opcode::CARRY {
+                arg1: operands.number("@")?,
+                arg2: operands.number("@")?,
+                arg3: operands.number(">=")?,
+            },
+            "AND" => Opcode::AND {
+                arg1: operands.number("@")?,
+                arg2: operands.number("@")?,
+            },
+            "COUNTDOWN" => Opcode::COUNTDOWN {
+                arg1: operands.number("@")?,
+                arg2: operands.number("@")?,
+            },
+            "DIVMOD" => Opcode::DIVMOD {
+                arg1: operands.number("@")?,
+                arg2: operands.number("@")?,
+                arg3: operands.number("@")?,
+            },
+            "PRINT" => {
+                let mut bytes = [0; PRINT_WIDTH];
+                let mut len = 0;
+
+                while len < PRINT_WIDTH && !operands.words.as_slice().is_empty() {
+                    bytes[len] = operands.number("")?;
+                    len += 1;
+                }
+
+                if len == 0 {
+                    return Err(operands.error(AssemblyErrorKind::MissingOperand));
+                }
+
+                Opcode::PRINT {
+                    bytes,
+                    len: len as u8,
+                }
+            }
+            "JUMPIFZERO" => {
+                let label = operands.next_word()?.to_string();
+
+                if used_labels.insert(label.clone(), line_number).is_some() {
+                    return Err(operands.error(AssemblyErrorKind::DuplicateLabel(label)));
+                }
+
+                loop_stack.push((label, opcodes.len(), line_number));
+                Opcode::JUMPIFZERO { arg: 0 }
+            }
+            "JUMPIFNZERO" | "ENDIF" => {
+                let label = operands.next_word()?.to_string();
+
+                match loop_stack.last() {
+                    Some((open_label, _, _)) if *open_label == label => {}
+                    _ => return Err(operands.error(AssemblyErrorKind::UnmatchedClose(label))),
+                }
+
+                let (_, open_instr, _) = loop_stack.pop().unwrap();
+                opcodes[open_instr] = Opcode::JUMPIFZERO { arg: opcodes.len() };
+
+                if name == "ENDIF" {
+                    Opcode::ENDIF { arg: open_instr }
+                } else {
+                    Opcode::JUMPIFNZERO { arg: open_instr }
+                }
+            }
+            _ => {
+                return Err(operands.error(AssemblyErrorKind::UnknownOpcode(name.to_string())));
+            }
+        };
+
+        operands.finish()?;
+        opcodes.push(op);
+    }
+
+    if let Some((label, _, line)) = loop_stack.pop() {
+        return Err(AssemblyError {
+            line,
+            kind: AssemblyErrorKind::UnclosedLoop(label),
+        });
+    }
+
+    Ok(opcodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::strip_spans;
+    use crate::passes::PassManager;
+
+    const EXAMPLES: [&str; 6] = [
+        include_str!("../examples/hello_world.bf"),
+        include_str!("../examples/squares.bf"),
+        include_str!("../examples/divide.bf"),
+        include_str!("../examples/factor.bf"),
+        include_str!("../examples/rot13.b"),
+        include_str!("../examples/triangle.bf"),
+    ];
+
+    fn error_kind(text: &str) -> (usize, AssemblyErrorKind) {
+        let error = assemble(text).unwrap_err();
+        (error.line, error.kind)
+    }
+
+    #[test]
+    fn round_trip_is_stable() {
+        // opcodes -> text -> opcodes gives back the opcodes it started from, at every level
+        for level in 0..=3 {
+            for source in EXAMPLES {
+                let opcodes =
+                    strip_spans(&PassManager::with_level(level).compile(source.to_string()));
+                assert_eq!(
+                    assemble(&disassemble(&opcodes)),
+                    Ok(opcodes),
+                    "at -O{}",
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        let text = "\
+CHANGE -3 @2
+MOVE +4
+PUTCHAR 2 @-1
+READCHAR 1
+JUMPIFZERO loop0
+    SETTO 7 @1
+    SCANBY -2
+    MULTI @3 *-2
+    MOVINGCHANGE +1 +5 -1
+    MOVINGSET -2 9 +2
+    JUMPIFZERO loop1
+        PRODUCT @1 @2 *3
+        TRIANGLE @-1 *2
+    ENDIF loop1
+    COPY @1 @2
+    CARRY @1 @2 >=3
+    AND @1 @2
+    COUNTDOWN @-6 @-3
+    DIVMOD @2 @1 @3
+JUMPIFNZERO loop0
+PRINT 72 105 ; \"Hi\"
+";
+        let opcodes = assemble(text).unwrap();
+        assert_eq!(opcodes.len(), 21);
+        assert_eq!(opcodes[4], Opcode::JUMPIFZERO { arg: 19 });
+        assert_eq!(opcodes[13], Opcode::ENDIF { arg: 10 });
+        assert_eq!(disassemble(&opcodes), text);
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let opcodes = assemble("; a comment\n\n  CHANGE +1 ; one more\n").unwrap();
+        assert_eq!(opcodes, vec![Opcode::CHANGE { arg: 1, offset: 0 }]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            error_kind("MOVE +1\nJUMP loop0"),
+            (2, AssemblyErrorKind::UnknownOpcode("JUMP".to_string()))
+        );
+        assert_eq!(error_kind("CHANGE"), (1, AssemblyErrorKind::MissingOperand));
+        assert_eq!(
+            error_kind("PRINT ; nothing"),
+            (1, AssemblyErrorKind::MissingOperand)
+        );
+        assert_eq!(
+            error_kind("MULTI 3 *2"),
+            (1, AssemblyErrorKind::BadOperand("3".to_string()))
+        );
+        assert_eq!(
+            error_kind("PRINT 256"),
+            (1, AssemblyErrorKind::BadOperand("256".to_string()))
+        );
+        assert_eq!(
+            error_kind("MOVE +1 +2"),
+            (1, AssemblyErrorKind::ExtraOperand("+2".to_string()))
+        );
+        assert_eq!(
+            error_kind("PRINT 1 2 3 4 5 6 7 8 9"),
+            (1, AssemblyErrorKind::ExtraOperand("9".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_loops() {
+        assert_eq!(
+            error_kind("JUMPIFNZERO loop0"),
+            (1, AssemblyErrorKind::UnmatchedClose("loop0".to_string()))
+        );
+        assert_eq!(
+            error_kind("JUMPIFZERO a\nJUMPIFZERO b\nJUMPIFNZERO a\nJUMPIFNZERO b"),
+            (3, AssemblyErrorKind::UnmatchedClose("a".to_string()))
+        );
+        assert_eq!(
+            error_kind("JUMPIFZERO a\nJUMPIFZERO b\nENDIF b"),
+            (1, AssemblyErrorKind::UnclosedLoop("a".to_string()))
+        );
+        assert_eq!(
+            error_kind("JUMPIFZERO a\nJUMPIFNZERO a\nJUMPIFZERO a\nJUMPIFNZERO a"),
+            (3, AssemblyErrorKind::DuplicateLabel("a".to_string()))
+        );
+    }
+}
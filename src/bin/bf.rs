@@ -1,46 +1,317 @@
+use bf::assembly::{assemble, disassemble};
 use bf::bf_executor::BFExecuter;
+use bf::bytecode::{decode, encode, VM_CONFIG};
+use bf::decompiler::decompile;
+use bf::explain::explain_source;
+use bf::formatter::format_source;
+use bf::lint::{lint_source, Severity};
+use bf::minifier::minify_source;
 use bf::opcode_interpreter::BFOpcodeInterpreter;
-use bf::opcodes::compile_code;
-use bf::source_utils::{check_program_brackets, remove_no_coding_symbols};
+use bf::opcodes::strip_spans;
+use bf::passes::{Pass, PassManager};
+use bf::source_utils::{find_bracket_errors, render_bracket_errors};
+use bf::span::LineIndex;
 use std::path::Path;
 use std::process::exit;
 use std::{fs, time};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
-#[command(version, about = "Program that will compile and run a BF program to an IR and then run on a VM", long_about = None)]
+#[command(version, about = "Program that will compile and run a BF program to an IR and then run on a VM", long_about = None, subcommand_negates_reqs = true)]
 struct Args{
-    #[arg(short, long)]
-    bf_file_path: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(short, long, required = true)]
+    bf_file_path: Option<String>,
     #[arg(short = 'o', long, default_value = "false")]
-    gen_object_file: bool
+    gen_object_file: bool,
+    #[arg(short = 'O', long, global = true, value_parser = clap::value_parser!(u8).range(0..=3), help = "Optimization level, from 0 (none) to 3 (every pass until nothing changes), 2 if not given")]
+    opt_level: Option<u8>,
+    #[arg(long = "enable-pass", global = true, value_delimiter = ',', help = "Turn on passes by name on top of the optimization level")]
+    enable_passes: Vec<Pass>,
+    #[arg(long = "disable-pass", global = true, value_delimiter = ',', help = "Turn off passes by name")]
+    disable_passes: Vec<Pass>,
+    #[arg(short = 'r', long, help = "Print what each optimization pass did to the program")]
+    report: bool,
 }
 
-fn main() {
-
-    let args = Args::parse();
-
-    let program_path_str = args.bf_file_path;
-    let _ = args.gen_object_file;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a BF program for common mistakes without running it
+    Lint {
+        bf_file_path: String,
+    },
+    /// Re-indent a BF program by its loops, keeping the comments
+    Fmt {
+        bf_file_path: String,
+        /// Write the formatted program back to the file instead of printing it
+        #[arg(short, long)]
+        write: bool,
+    },
+    /// Strip the comments and print the shortest equivalent BF program
+    Minify {
+        bf_file_path: String,
+    },
+    /// Optimize a BF program and turn the optimized opcodes back into BF
+    Decompile {
+        bf_file_path: String,
+    },
+    /// Show the opcodes after every optimization pass, marking what each pass changed
+    Explain {
+        bf_file_path: String,
+    },
+    /// Print the optimized opcodes of a BF program in the textual IR format
+    Disasm {
+        bf_file_path: String,
+    },
+    /// Run a program written in the textual IR format on the VM
+    RunAsm {
+        asm_file_path: String,
+    },
+    /// Optimize a BF program and save the opcodes as a bytecode file
+    Compile {
+        bf_file_path: String,
+        #[arg(short = 'o', long, default_value = "a.bfc")]
+        output_path: String,
+    },
+    /// Run a bytecode file made by compile on the VM
+    Run {
+        bytecode_path: String,
+    },
+}
 
+fn read_program_source(program_path_str: &str) -> String {
     // generate the bf program from path
-    let program_path = Path::new(&program_path_str);
+    let program_path = Path::new(program_path_str);
 
     let program_source_result = fs::read_to_string(program_path);
 
-    let program_source = match program_source_result {
-        Ok(x) => remove_no_coding_symbols(x),
+    // the source is kept as is, so that positions in the opcodes line up with the file
+    match program_source_result {
+        Ok(x) => x,
         Err(_) => panic!("File at path {:?} does not exist", program_path_str),
+    }
+}
+
+fn lint_program(program_path_str: &str) {
+    let program_source = read_program_source(program_path_str);
+    let line_index = LineIndex::new(&program_source);
+
+    let findings = lint_source(&program_source);
+
+    for finding in &findings {
+        print!("{}", finding.render(&line_index));
+    }
+
+    println!("Found {} problems in {}", findings.len(), program_path_str);
+
+    if findings.iter().any(|x| x.severity == Severity::Error) {
+        exit(-1);
+    }
+}
+
+fn format_program(program_path_str: &str, write: bool) {
+    let program_source = read_program_source(program_path_str);
+
+    let formatted_source = match format_source(&program_source) {
+        Ok(x) => x,
+        Err(bracket_errors) => {
+            print!("{}", render_bracket_errors(&program_source, &bracket_errors));
+            println!("This program is not valid incorrect number of brackets opening and closing!");
+            exit(-1);
+        }
     };
 
-    if !check_program_brackets(&program_source) {
+    if !write {
+        print!("{}", formatted_source);
+        return;
+    }
+
+    match fs::write(program_path_str, formatted_source) {
+        Ok(_) => println!("Formatted {:?}", program_path_str),
+        Err(_) => panic!("Failed to write the formatted program to {:?}", program_path_str),
+    }
+}
+
+fn minify_program(program_path_str: &str) {
+    let program_source = read_program_source(program_path_str);
+
+    // no newline at the end, so that the output is exactly the minified program
+    match minify_source(&program_source) {
+        Ok(x) => print!("{}", x),
+        Err(bracket_errors) => {
+            print!("{}", render_bracket_errors(&program_source, &bracket_errors));
+            println!("This program is not valid incorrect number of brackets opening and closing!");
+            exit(-1);
+        }
+    }
+}
+
+fn decompile_program(program_path_str: &str, pass_manager: &PassManager) {
+    let program_source = read_program_source(program_path_str);
+
+    let bracket_errors = find_bracket_errors(&program_source);
+
+    if !bracket_errors.is_empty() {
+        print!("{}", render_bracket_errors(&program_source, &bracket_errors));
         println!("This program is not valid incorrect number of brackets opening and closing!");
         exit(-1);
     }
 
-    let compiled_code = compile_code(program_source);
+    match decompile(&strip_spans(&pass_manager.compile(program_source))) {
+        Ok(x) => println!("{}", x),
+        Err(error) => {
+            println!("Could not decompile the program, {}", error);
+            exit(-1);
+        }
+    }
+}
+
+fn explain_program(program_path_str: &str, pass_manager: &PassManager) {
+    let program_source = read_program_source(program_path_str);
+
+    match explain_source(&program_source, pass_manager) {
+        Ok(x) => print!("{}", x),
+        Err(bracket_errors) => {
+            print!("{}", render_bracket_errors(&program_source, &bracket_errors));
+            println!("This program is not valid incorrect number of brackets opening and closing!");
+            exit(-1);
+        }
+    }
+}
+
+fn disassemble_program(program_path_str: &str, pass_manager: &PassManager) {
+    let program_source = read_program_source(program_path_str);
+
+    let bracket_errors = find_bracket_errors(&program_source);
 
-    let mut bf_inter = BFOpcodeInterpreter::new(compiled_code);
+    if !bracket_errors.is_empty() {
+        print!("{}", render_bracket_errors(&program_source, &bracket_errors));
+        println!("This program is not valid incorrect number of brackets opening and closing!");
+        exit(-1);
+    }
+
+    print!("{}", disassemble(&strip_spans(&pass_manager.compile(program_source))));
+}
+
+fn run_assembly(asm_path_str: &str) {
+    let asm_source = read_program_source(asm_path_str);
+
+    let program = match assemble(&asm_source) {
+        Ok(x) => x,
+        Err(error) => {
+            println!("{}: {}", asm_path_str, error);
+            exit(-1);
+        }
+    };
+
+    let mut bf_inter = match BFOpcodeInterpreter::new_checked(program) {
+        Ok(x) => x,
+        Err(error) => {
+            println!("{}: {}", asm_path_str, error);
+            exit(-1);
+        }
+    };
+    bf_inter.execute();
+
+    if let Some(error) = bf_inter.runtime_error() {
+        println!(
+            "Encountered Illegal Data Pointer location at Instruction {:?}",
+            error.ip
+        );
+    }
+
+    println!("Instructions {:?}", bf_inter.instruction_count());
+}
+
+fn compile_to_bytecode(
+    program_path_str: &str,
+    output_path_str: &str,
+    pass_manager: &PassManager,
+) {
+    let program_source = read_program_source(program_path_str);
+
+    let bracket_errors = find_bracket_errors(&program_source);
+
+    if !bracket_errors.is_empty() {
+        print!("{}", render_bracket_errors(&program_source, &bracket_errors));
+        println!("This program is not valid incorrect number of brackets opening and closing!");
+        exit(-1);
+    }
+
+    let bytecode = encode(&strip_spans(&pass_manager.compile(program_source)), VM_CONFIG);
+
+    match fs::write(output_path_str, bytecode) {
+        Ok(_) => println!("Bytecode written to {:?}", output_path_str),
+        Err(_) => panic!("Failed to write bytecode to {:?}", output_path_str),
+    }
+}
+
+fn run_bytecode(bytecode_path_str: &str) {
+    let bytecode = match fs::read(bytecode_path_str) {
+        Ok(x) => x,
+        Err(_) => panic!("File at path {:?} does not exist", bytecode_path_str),
+    };
+
+    let program = match decode(&bytecode) {
+        Ok(x) => x,
+        Err(error) => {
+            println!("Could not load {:?}, {}", bytecode_path_str, error);
+            exit(-1);
+        }
+    };
+
+    let mut bf_inter = BFOpcodeInterpreter::new(program);
+
+    let start_time = time::Instant::now();
+
+    bf_inter.execute();
+
+    let program_run_time = time::Instant::now().duration_since(start_time).as_secs_f64();
+
+    // the spans aren't kept in the bytecode, so this can only point at the instruction
+    if let Some(error) = bf_inter.runtime_error() {
+        println!(
+            "Encountered Illegal Data Pointer location at Instruction {:?}",
+            error.ip
+        );
+    }
+
+    println!("Instructions {:?}", bf_inter.instruction_count());
+    println!("Time Elapsed {:?} sec.", program_run_time);
+}
+
+fn make_pass_manager(args: &Args) -> PassManager {
+    PassManager::from_flags(
+        args.opt_level.unwrap_or(2),
+        &args.enable_passes,
+        &args.disable_passes,
+    )
+}
+
+fn reject_pass_flags(args: &Args, command_name: &str) {
+    // these subcommands don't run the optimizer, so the flags for it would do nothing
+    if args.opt_level.is_some() || !args.enable_passes.is_empty() || !args.disable_passes.is_empty()
+    {
+        println!("The -O, --enable-pass and --disable-pass flags can't be used with {}", command_name);
+        exit(-1);
+    }
+}
+
+fn run_program(program_path_str: &str, pass_manager: &PassManager, report: bool) {
+    let program_source = read_program_source(program_path_str);
+
+    let bracket_errors = find_bracket_errors(&program_source);
+
+    if !bracket_errors.is_empty() {
+        print!("{}", render_bracket_errors(&program_source, &bracket_errors));
+        println!("This program is not valid incorrect number of brackets opening and closing!");
+        exit(-1);
+    }
+
+    let (compiled_code, optimization_report) = pass_manager.compile_with_report(program_source.clone());
+
+    let mut bf_inter = BFOpcodeInterpreter::with_spans(&compiled_code);
 
     let start_time = time::Instant::now();
 
@@ -50,6 +321,74 @@ fn main() {
 
     let program_run_time = end_time.duration_since(start_time).as_secs_f64();
 
+    if let Some(error) = bf_inter.runtime_error() {
+        match error.span {
+            Some(span) => {
+                let position = LineIndex::new(&program_source).position(span.start);
+                println!(
+                    "Encountered Illegal Data Pointer location at {}:{}:{}",
+                    program_path_str, position.line, position.column
+                );
+            }
+            None => println!(
+                "Encountered Illegal Data Pointer location at Instruction {:?}",
+                error.ip
+            ),
+        }
+    }
+
     println!("Instructions {:?}", bf_inter.instruction_count());
     println!("Time Elapsed {:?} sec.", program_run_time);
+
+    if report {
+        print!("{}", optimization_report);
+    }
+}
+
+fn main() {
+
+    let args = Args::parse();
+
+    let _ = args.gen_object_file;
+
+    match args.command {
+        Some(Command::Lint { ref bf_file_path }) => {
+            reject_pass_flags(&args, "lint");
+            lint_program(bf_file_path)
+        }
+        Some(Command::Fmt { ref bf_file_path, write }) => {
+            reject_pass_flags(&args, "fmt");
+            format_program(bf_file_path, write)
+        }
+        Some(Command::Minify { ref bf_file_path }) => {
+            reject_pass_flags(&args, "minify");
+            minify_program(bf_file_path)
+        }
+        Some(Command::Decompile { ref bf_file_path }) => {
+            decompile_program(bf_file_path, &make_pass_manager(&args))
+        }
+        Some(Command::Explain { ref bf_file_path }) => {
+            explain_program(bf_file_path, &make_pass_manager(&args))
+        }
+        Some(Command::Disasm { ref bf_file_path }) => {
+            disassemble_program(bf_file_path, &make_pass_manager(&args))
+        }
+        Some(Command::RunAsm { ref asm_file_path }) => {
+            reject_pass_flags(&args, "run-asm");
+            run_assembly(asm_file_path)
+        }
+        Some(Command::Compile { ref bf_file_path, ref output_path }) => {
+            compile_to_bytecode(bf_file_path, output_path, &make_pass_manager(&args))
+        }
+        Some(Command::Run { ref bytecode_path }) => {
+            reject_pass_flags(&args, "run, the passes were picked when it was compiled");
+            run_bytecode(bytecode_path)
+        }
+        // clap makes sure the path is there when there isn't a subcommand
+        None => run_program(
+            &args.bf_file_path.clone().unwrap(),
+            &make_pass_manager(&args),
+            args.report,
+        ),
+    }
 }
@@ -2,8 +2,10 @@ use std::fs;
 use std::path::Path;
 use std::process::exit;
 use clap::Parser;
-use bf::opcodes::Opcode;
-use bf::source_utils::{check_program_brackets, get_c_file_prefix, remove_no_coding_symbols};
+use bf::opcodes::{Opcode, SpannedOpcode};
+use bf::passes::{Pass, PassManager};
+use bf::source_utils::{find_bracket_errors, get_c_file_prefix, render_bracket_errors};
+use bf::span::{LineIndex, SourcePosition};
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Program that will transcompile a BF program to C.", long_about = None)]
@@ -12,52 +14,141 @@ struct Args{
     bf_file_path: String,
     #[arg(short = 'o', long, default_value = "a.c")]
     c_output_name: String,
+    #[arg(short = 'l', long, help = "Emit #line directives that point back to the BF source")]
+    line_directives: bool,
+    #[arg(short = 'O', long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(0..=3), help = "Optimization level, from 0 (none) to 3 (every pass until nothing changes)")]
+    opt_level: u8,
+    #[arg(long = "enable-pass", value_delimiter = ',', help = "Turn on passes by name on top of the optimization level")]
+    enable_passes: Vec<Pass>,
+    #[arg(long = "disable-pass", value_delimiter = ',', help = "Turn off passes by name")]
+    disable_passes: Vec<Pass>,
+    #[arg(short = 'r', long, help = "Print what each optimization pass did to the program")]
+    report: bool,
 }
 
 
+fn make_cell_address(offset: i32) -> String {
+    // the cell an opcode works on, relative to where the data pointer is
+    match offset {
+        0 => String::from("p"),
+        x if x > 0 => format!("p + {}", x),
+        x => format!("p - {}", x.unsigned_abs()),
+    }
+}
+
 fn make_opcode_string(op:Opcode) -> String{
     match op {
-        Opcode::CHANGE { arg } => {
-            String::from(format!("change(m, p, {});\n", arg))
+        Opcode::CHANGE { arg, offset } => {
+            format!("change(m, {}, {});\n", make_cell_address(offset), arg)
         }
         Opcode::MOVE { arg } => {
-            String::from(format!("p = move(p, {});\n", arg))
+            format!("p = move(p, {});\n", arg)
         }
-        Opcode::PUTCHAR { arg } => {
-            String::from("display_char(m, p);\n".to_string()).repeat(arg)
+        Opcode::PUTCHAR { arg, offset } => {
+            format!("display_char(m, {});\n", make_cell_address(offset)).repeat(arg)
         }
-        Opcode::READCHAR { arg } => {
-            String::from("m[p] = read_char();\n".to_string()).repeat(arg)
+        Opcode::READCHAR { arg, offset } => {
+            format!("m[{}] = read_char();\n", make_cell_address(offset)).repeat(arg)
         }
         Opcode::JUMPIFZERO { .. } => {
             String::from("while (m[p] != 0){\n")
         }
-        Opcode::JUMPIFNZERO { .. } => {
+        Opcode::JUMPIFNZERO { .. } | Opcode::ENDIF { .. } => {
             String::from("}\n")
         }
-        Opcode::SETTO { arg } => {
-            String::from(format!("set_to(m, p, {});\n", arg))
+        Opcode::SETTO { arg, offset } => {
+            format!("set_to(m, {}, {});\n", make_cell_address(offset), arg)
         }
+        // the C library's memchr and memrchr are usually vectorized already
+        Opcode::SCANBY { arg: 1 } => String::from("p = scan_right(m, p);\n"),
+        Opcode::SCANBY { arg: -1 } => String::from("p = scan_left(m, p);\n"),
         Opcode::SCANBY { arg } => {
-            String::from(format!("p = scan_by(m, p, {});\n", arg))
+            format!("p = scan_by(m, p, {});\n", arg)
         }
         Opcode::MULTI { arg1, arg2 } => {
-            String::from(format!("multi(m, p, {}, {});\n", arg1, arg2))
+            format!("multi(m, p, {}, {});\n", arg1, arg2)
         }
         Opcode::MOVINGCHANGE { arg1, arg2, arg3 } => {
-            String::from(format!("moving_change(m, p, {}, {}, {});\n", arg1, arg2, arg3))
+            format!("p = moving_change(m, p, {}, {}, {});\n", arg1, arg2, arg3)
         }
         Opcode::MOVINGSET { arg1, arg2, arg3 } => {
-            String::from(format!("moving_set(m, p, {}, {}, {});\n", arg1, arg2, arg3))
+            format!("p = moving_set(m, p, {}, {}, {});\n", arg1, arg2, arg3)
+        }
+        Opcode::PRINT { bytes, len } => {
+            make_print_string(&bytes[..usize::from(len)])
+        }
+        Opcode::PRODUCT { arg1, arg2, arg3 } => {
+            format!("product(m, p, {}, {}, {});\n", arg1, arg2, arg3)
+        }
+        Opcode::TRIANGLE { arg1, arg2 } => {
+            format!("triangle(m, p, {}, {});\n", arg1, arg2)
+        }
+        Opcode::COPY { arg1, arg2 } => {
+            format!("copy(m, p, {}, {});\n", arg1, arg2)
+        }
+        Opcode::CARRY { arg1, arg2, arg3 } => {
+            format!("carry(m, p, {}, {}, {});\n", arg1, arg2, arg3)
+        }
+        Opcode::AND { arg1, arg2 } => {
+            format!("and_into(m, p, {}, {});\n", arg1, arg2)
+        }
+        Opcode::COUNTDOWN { arg1, arg2 } => {
+            format!("count_down(m, p, {}, {});\n", arg1, arg2)
+        }
+        Opcode::DIVMOD { arg1, arg2, arg3 } => {
+            format!("divmod(m, p, {}, {}, {});\n", arg1, arg2, arg3)
+        }
+    }
+}
+
+fn make_print_string(bytes: &[u8]) -> String {
+    // every byte is escaped, so nothing in the text can end the string or run into an escape
+    let text = bytes.iter().map(|x| format!("\\x{:02x}", x)).collect::<String>();
+    format!("fwrite(\"{}\", 1, {}, stdout);\n", text, bytes.len())
+}
+
+fn make_ops_string(ops: &[SpannedOpcode], program: &[SpannedOpcode]) -> String {
+    // text split over several PRINT opcodes is still written out in one go, and a loop that
+    // ends in an ENDIF runs at most once so it doesn't need to go around again
+    match ops[0].op {
+        Opcode::JUMPIFZERO { arg } if matches!(program[arg].op, Opcode::ENDIF { .. }) => {
+            String::from("if (m[p] != 0){\n")
+        }
+        Opcode::PRINT { .. } => {
+            let bytes = ops
+                .iter()
+                .flat_map(|x| match x.op {
+                    Opcode::PRINT { bytes, len } => bytes[..usize::from(len)].to_vec(),
+                    _ => vec![],
+                })
+                .collect::<Vec<_>>();
+            make_print_string(&bytes)
         }
+        op => make_opcode_string(op),
     }
 }
 
+fn escape_c_string(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn make_line_directive(position: SourcePosition, file_name: &str) -> String {
+    // C has no way to give a column, so we leave it in a comment for humans to read
+    format!(
+        "#line {} \"{}\" /* column {} */\n",
+        position.line,
+        escape_c_string(file_name),
+        position.column
+    )
+}
 
 fn main() {
 
     let args = Args::parse();
 
+    let pass_manager =
+        PassManager::from_flags(args.opt_level, &args.enable_passes, &args.disable_passes);
+
     let program_path_str = args.bf_file_path;
     let c_output_path_str = args.c_output_name;
 
@@ -66,26 +157,59 @@ fn main() {
 
     let program_source_result = fs::read_to_string(program_path);
 
+    // the source is kept as is, so that positions in the opcodes line up with the file
     let program_source = match program_source_result {
-        Ok(x) => remove_no_coding_symbols(x),
+        Ok(x) => x,
         Err(_) => panic!("File at path {:?} does not exist", program_path_str),
     };
 
-    if !check_program_brackets(&program_source) {
+    let bracket_errors = find_bracket_errors(&program_source);
+
+    if !bracket_errors.is_empty() {
+        print!("{}", render_bracket_errors(&program_source, &bracket_errors));
         println!("This program is not valid incorrect number of brackets opening and closing!");
         exit(-1);
     }
 
-    let compiled_code = bf::opcodes::compile_code(program_source);
+    let (compiled_code, optimization_report) = pass_manager.compile_with_report(program_source.clone());
+    let line_index = LineIndex::new(&program_source);
 
     let mut c_code_output = vec![];
 
     let c_code_preamble = get_c_file_prefix();
     let c_code_postamble = String::from("return 0;\n}\n");
-    let c_code_ops = compiled_code.iter().map(|x| make_opcode_string(*x)).collect::<Vec<_>>();
+    let c_code_ops = compiled_code
+        .chunk_by(|a, b| matches!((a.op, b.op), (Opcode::PRINT { .. }, Opcode::PRINT { .. })))
+        .map(|ops| {
+            let x = ops[0];
+            let op_string = make_ops_string(ops, &compiled_code);
+
+            if !args.line_directives {
+                return op_string;
+            }
+
+            // every generated line gets its own directive, so that repeated statements don't drift
+            let directive = make_line_directive(line_index.position(x.span.start), &program_path_str);
+            op_string
+                .lines()
+                .map(|line| format!("{}{}\n", directive, line))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>();
 
     c_code_output.push(c_code_preamble);
     c_code_output.extend(c_code_ops);
+
+    if args.line_directives {
+        // point everything after the program back at the generated file
+        let next_line = c_code_output.iter().map(|x| x.matches('\n').count()).sum::<usize>() + 2;
+        c_code_output.push(format!(
+            "#line {} \"{}\"\n",
+            next_line,
+            escape_c_string(&c_output_path_str)
+        ));
+    }
+
     c_code_output.push(c_code_postamble);
 
     let c_output_path = Path::new(&c_output_path_str);
@@ -99,4 +223,8 @@ fn main() {
         Ok(_) => println!("C code written to {:?}", c_output_path_str),
         Err(_) => panic!("Failed to write C code to {:?}", c_output_path_str),
     }
+
+    if args.report {
+        print!("{}", optimization_report);
+    }
 }
@@ -1,8 +1,13 @@
 use bf::bf_executor::BFExecuter;
+use bf::explain::explain_source;
 use bf::opcode_interpreter::BFOpcodeInterpreter;
-use bf::opcodes::compile_code;
+use bf::opcodes::compile_code_with_spans;
+use bf::passes::PassManager;
 use bf::simple_interpreter::BFSimpleInterpreter;
-use bf::source_utils::{check_program_brackets, remove_no_coding_symbols};
+use bf::source_utils::{
+    find_bracket_errors, remove_no_coding_symbols, render_bracket_errors, render_source_snippet,
+};
+use bf::span::LineIndex;
 use std::io::Write;
 use std::process::exit;
 
@@ -10,6 +15,7 @@ enum Action {
     Exit,
     UseDebugInterpreter,
     UseFastInterpreter,
+    ToggleExplain,
 }
 
 enum InterpreterMode {
@@ -48,6 +54,10 @@ fn find_action(input: &str) -> Option<Action> {
         return Some(Action::UseFastInterpreter);
     }
 
+    if lowered_input.contains("explain") {
+        return Some(Action::ToggleExplain);
+    }
+
     None
 }
 
@@ -60,8 +70,10 @@ fn main() {
     println!("entering exit will exit the program");
     println!("entering debug will switch to debugging mode (default!)");
     println!("entering fast will switch to the fast interpreter");
+    println!("entering explain will toggle showing what each optimization pass does to the code");
 
     let mut interpreter_mode = InterpreterMode::UseDebugInterpreter;
+    let mut explain_passes = false;
 
     loop {
         let input = prompt_repl_input();
@@ -80,25 +92,61 @@ fn main() {
                     interpreter_mode = InterpreterMode::UseFastInterpreter;
                     println!(":: Fast mode on");
                 }
+                Action::ToggleExplain => {
+                    explain_passes = !explain_passes;
+                    if explain_passes {
+                        println!(":: Explain mode on");
+                    } else {
+                        println!(":: Explain mode off");
+                    }
+                }
             }
             continue;
         } else {
-            let sanitized_code = remove_no_coding_symbols(input);
+            // the brackets are checked before the comments are stripped, so columns match the input
+            let bracket_report = render_bracket_errors(&input, &find_bracket_errors(&input));
+            let sanitized_code = remove_no_coding_symbols(input.clone());
 
             if sanitized_code.is_empty() {
                 println!("There is no BF code here...");
                 continue;
             }
 
-            if !check_program_brackets(&sanitized_code) {
+            if !bracket_report.is_empty() {
+                print!("{}", bracket_report);
                 println!("This is not valid BF code! Check your brackets!");
                 continue;
             }
 
+            if explain_passes
+                && let Ok(explanation) = explain_source(&sanitized_code, &PassManager::default())
+            {
+                print!("{}", explanation);
+            }
+
             match interpreter_mode {
                 InterpreterMode::UseFastInterpreter => {
-                    let compiled_code = compile_code(sanitized_code);
-                    BFOpcodeInterpreter::new(compiled_code).execute()
+                    // the comments are left in, so the spans point into the line as typed
+                    let compiled_code = compile_code_with_spans(input.clone());
+                    let mut bf_inter = BFOpcodeInterpreter::with_spans(&compiled_code);
+                    bf_inter.execute();
+
+                    if let Some(span) = bf_inter.runtime_error().and_then(|x| x.span) {
+                        let line_index = LineIndex::new(&input);
+                        let position = line_index.position(span.start);
+                        println!(
+                            "Encountered Illegal Data Pointer location at {}:{}",
+                            position.line, position.column
+                        );
+                        print!(
+                            "{}",
+                            render_source_snippet(
+                                &line_index,
+                                position,
+                                input[span.start..span.end].chars().count()
+                            )
+                        );
+                    }
                 }
                 InterpreterMode::UseDebugInterpreter => {
                     BFSimpleInterpreter::new(sanitized_code).execute()
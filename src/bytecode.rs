@@ -0,0 +1,531 @@
+use crate::opcodes::{Opcode, PRINT_WIDTH};
+use crate::state::TAPE_SIZE;
+use crate::validation::{ValidationError, validate};
+use std::fmt;
+
+// a bytecode file is laid out as follows, all numbers are little endian
+//
+//     magic        4 bytes  "BFC\0"
+//     version      u16
+//     cell width   u8       bits per cell
+//     eof mode     u8       what reading past the end of the input does
+//     tape size    u32      number of cells
+//     count        u32      number of opcodes
+//     opcodes      a tag byte per opcode, followed by its operands as LEB128 varints,
+//                  signed operands are zigzag encoded first so small negative numbers stay small,
+//                  PRINT is followed by its length and then the bytes as they are
+
+pub const MAGIC: [u8; 4] = *b"BFC\0";
+pub const VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct BytecodeConfig {
+    pub cell_width: u8,
+    pub eof_mode: u8,
+    pub tape_size: u32,
+}
+
+// the only machine BFOpcodeInterpreter is, 8 bit wrapping cells on a 30000 cell tape, where
+// reading past the end of the input stops the program
+pub const VM_CONFIG: BytecodeConfig = BytecodeConfig {
+    cell_width: 8,
+    eof_mode: 0,
+    tape_size: TAPE_SIZE as u32,
+};
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    // the program was compiled for a different machine than the one loading it
+    MismatchedConfig(BytecodeConfig),
+    Truncated,
+    UnknownOpcode { tag: u8, ip: usize },
+    BadOperand { ip: usize },
+    TrailingBytes,
+    Invalid(ValidationError),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "this is not a BF bytecode file"),
+            Self::UnsupportedVersion(x) => write!(
+                f,
+                "bytecode version {} is not supported, expected version {}",
+                x, VERSION
+            ),
+            Self::MismatchedConfig(x) => write!(
+                f,
+                "the bytecode needs {} bit cells, eof mode {} and {} cells, this VM can not run it",
+                x.cell_width, x.eof_mode, x.tape_size
+            ),
+            Self::Truncated => write!(f, "the bytecode ends in the middle of the program"),
+            Self::UnknownOpcode { tag, ip } => {
+                write!(f, "unknown opcode tag {} at instruction {}", tag, ip)
+            }
+            Self::BadOperand { ip } => write!(f, "operand out of range at instruction {}", ip),
+            Self::TrailingBytes => write!(f, "there is data after the end of the program"),
+            Self::Invalid(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+const fn opcode_tag(op: Opcode) -> u8 {
+    match op {
+        Opcode::CHANGE { .. } => 0,
+        Opcode::MOVE { .. } => 1,
+        Opcode::PUTCHAR { .. } => 2,
+        Opcode::READCHAR { .. } => 3,
+        Opcode::JUMPIFZERO { .. } => 4,
+        Opcode::JUMPIFNZERO { .. } => 5,
+        Opcode::SETTO { .. } => 6,
+        Opcode::SCANBY { .. } => 7,
+        Opcode::MULTI { .. } => 8,
+        Opcode::MOVINGCHANGE { .. } => 9,
+        Opcode::MOVINGSET { .. } => 10,
+        Opcode::PRINT { .. } => 11,
+        Opcode::ENDIF { .. } => 12,
+        Opcode::PRODUCT { .. } => 13,
+        Opcode::TRIANGLE { .. } => 14,
+        Opcode::COPY { .. } => 15,
+        Opcode::CARRY { .. } => 16,
+        Opcode::AND { .. } => 17,
+        Opcode::COUNTDOWN { .. } => 18,
+        Opcode::DIVMOD { .. } => 19,
+    }
+}
+
+fn write_unsigned(output: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            output.push(byte);
+            return;
+        }
+
+        output.push(byte | 0x80);
+    }
+}
+
+fn write_signed(output: &mut Vec<u8>, value: i32) {
+    // zigzag, 0 -> 0, -1 -> 1, 1 -> 2, -2 -> 3, ...
+    let value = i64::from(value);
+    write_unsigned(output, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+pub fn encode(opcodes: &[Opcode], config: BytecodeConfig) -> Vec<u8> {
+    let mut output = Vec::with_capacity(HEADER_SIZE + opcodes.len() * 2);
+
+    output.extend_from_slice(&MAGIC);
+    output.extend_from_slice(&VERSION.to_le_bytes());
+    output.push(config.cell_width);
+    output.push(config.eof_mode);
+    output.extend_from_slice(&config.tape_size.to_le_bytes());
+    output.extend_from_slice(&(opcodes.len() as u32).to_le_bytes());
+
+    for &op in opcodes {
+        output.push(opcode_tag(op));
+
+        match op {
+            Opcode::CHANGE { arg, offset } | Opcode::SETTO { arg, offset } => {
+                write_signed(&mut output, arg);
+                write_signed(&mut output, offset);
+            }
+            Opcode::PUTCHAR { arg, offset } | Opcode::READCHAR { arg, offset } => {
+                write_unsigned(&mut output, arg as u64);
+                write_signed(&mut output, offset);
+            }
+            Opcode::MOVE { arg } | Opcode::SCANBY { arg } => write_signed(&mut output, arg),
+            Opcode::JUMPIFZERO { arg } | Opcode::JUMPIFNZERO { arg } | Opcode::ENDIF { arg } => {
+                write_unsigned(&mut output, arg as u64);
+            }
+            Opcode::MULTI { arg1, arg2 }
+            | Opcode::TRIANGLE { arg1, arg2 }
+            | Opcode::COPY { arg1, arg2 }
+            | Opcode::AND { arg1, arg2 }
+            | Opcode::COUNTDOWN { arg1, arg2 } => {
+                write_signed(&mut output, arg1);
+                write_signed(&mut output, arg2);
+            }
+            Opcode::MOVINGCHANGE { arg1, arg2, arg3 }
+            | Opcode::MOVINGSET { arg1, arg2, arg3 }
+            | Opcode::PRODUCT { arg1, arg2, arg3 }
+            | Opcode::CARRY { arg1, arg2, arg3 }
+            | Opcode::DIVMOD { arg1, arg2, arg3 } => {
+                write_signed(&mut output, arg1);
+                write_signed(&mut output, arg2);
+                write_signed(&mut output, arg3);
+            }
+            Opcode::PRINT { bytes, len } => {
+                write_unsigned(&mut output, u64::from(len));
+                output.extend_from_slice(&bytes[..usize::from(len)]);
+            }
+        }
+    }
+
+    output
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // the instruction being read, for errors
+    ip: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], BytecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..(self.pos + count))
+            .ok_or(BytecodeError::Truncated)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn unsigned(&mut self) -> Result<u64, BytecodeError> {
+        let mut value: u64 = 0;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(BytecodeError::BadOperand { ip: self.ip })
+    }
+
+    fn signed(&mut self) -> Result<i32, BytecodeError> {
+        let value = self.unsigned()?;
+        let value = ((value >> 1) as i64) ^ -((value & 1) as i64);
+
+        i32::try_from(value).map_err(|_| BytecodeError::BadOperand { ip: self.ip })
+    }
+
+    fn index(&mut self) -> Result<usize, BytecodeError> {
+        let value = self.unsigned()?;
+
+        usize::try_from(value).map_err(|_| BytecodeError::BadOperand { ip: self.ip })
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Opcode>, BytecodeError> {
+    // reads a bytecode file for this VM, anything that isn't exactly what encode would
+    // have written for a valid program is rejected
+    if bytes.get(..4) != Some(&MAGIC[..]) {
+        return Err(BytecodeError::BadMagic);
+    }
+
+    let mut reader = Reader {
+        bytes,
+        pos: 4,
+        ip: 0,
+    };
+
+    let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let cell_width = reader.byte()?;
+    let eof_mode = reader.byte()?;
+    let tape_size = reader.take(4)?;
+    let config = BytecodeConfig {
+        cell_width,
+        eof_mode,
+        tape_size: u32::from_le_bytes([tape_size[0], tape_size[1], tape_size[2], tape_size[3]]),
+    };
+
+    if config != VM_CONFIG {
+        return Err(BytecodeError::MismatchedConfig(config));
+    }
+
+    let count = reader.take(4)?;
+    let count = u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as usize;
+
+    // every opcode is at least a tag and an operand, so this also stops a bad count from
+    // asking for a huge allocation
+    if count > bytes.len() {
+        return Err(BytecodeError::Truncated);
+    }
+
+    let mut opcodes = Vec::with_capacity(count);
+
+    for ip in 0..count {
+        reader.ip = ip;
+
+        let op = match reader.byte()? {
+            0 => Opcode::CHANGE {
+                arg: reader.signed()?,
+                offset: reader.signed()?,
+            },
+            1 => Opcode::MOVE {
+                arg: reader.signed()?,
+            },
+            2 => Opcode::PUTCHAR {
+                arg: reader.index()?,
+                offset: reader.signed()?,
+            },
+            3 => Opcode::READCHAR {
+                arg: reader.index()?,
+                offset: reader.signed()?,
+            },
+            4 => Opcode::JUMPIFZERO {
+                arg: reader.index()?,
+            },
+            5 => Opcode::JUMPIFNZERO {
+                arg: reader.index()?,
+            },
+            6 => Opcode::SETTO {
+                arg: reader.signed()?,
+                offset: reader.signed()?,
+            },
+            7 => Opcode::SCANBY {
+                arg: reader.signed()?,
+            },
+            8 => Opcode::MULTI {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+            },
+            9 => Opcode::MOVINGCHANGE {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+                arg3: reader.signed()?,
+            },
+            10 => Opcode::MOVINGSET {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+                arg3: reader.signed()?,
+            },
+            11 => {
+                let len = reader.index()?;
+
+                if len > PRINT_WIDTH {
+                    return Err(BytecodeError::BadOperand { ip });
+                }
+
+                let mut bytes = [0; PRINT_WIDTH];
+                bytes[..len].copy_from_slice(reader.take(len)?);
+
+                Opcode::PRINT {
+                    bytes,
+                    len: len as u8,
+                }
+            }
+            12 => Opcode::ENDIF {
+                arg: reader.index()?,
+            },
+            13 => Opcode::PRODUCT {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+                arg3: reader.signed()?,
+            },
+            14 => Opcode::TRIANGLE {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+            },
+            15 => Opcode::COPY {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+            },
+            16 => Opcode::CARRY {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+                arg3: reader.signed()?,
+            },
+            17 => Opcode::AND {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+            },
+            18 => Opcode::COUNTDOWN {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+            },
+            19 => Opcode::DIVMOD {
+                arg1: reader.signed()?,
+                arg2: reader.signed()?,
+                arg3: reader.signed()?,
+            },
+            tag => return Err(BytecodeError::UnknownOpcode { tag, ip }),
+        };
+
+        opcodes.push(op);
+    }
+
+    if reader.pos != bytes.len() {
+        return Err(BytecodeError::TrailingBytes);
+    }
+
+    // the VM trusts the jump targets and operands, so they have to make sense
+    validate(&opcodes).map_err(BytecodeError::Invalid)?;
+
+    Ok(opcodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::strip_spans;
+    use crate::passes::PassManager;
+
+    const EXAMPLES: [&str; 6] = [
+        include_str!("../examples/hello_world.bf"),
+        include_str!("../examples/squares.bf"),
+        include_str!("../examples/divide.bf"),
+        include_str!("../examples/factor.bf"),
+        include_str!("../examples/rot13.b"),
+        include_str!("../examples/triangle.bf"),
+    ];
+
+    fn header(count: u32) -> Vec<u8> {
+        encode(&[], VM_CONFIG)[..HEADER_SIZE - 4]
+            .iter()
+            .copied()
+            .chain(count.to_le_bytes())
+            .collect()
+    }
+
+    fn with_body(count: u32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = header(count);
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    #[test]
+    fn round_trip_is_stable() {
+        for level in 0..=3 {
+            for source in EXAMPLES {
+                let opcodes =
+                    strip_spans(&PassManager::with_level(level).compile(source.to_string()));
+                assert_eq!(
+                    decode(&encode(&opcodes, VM_CONFIG)),
+                    Ok(opcodes),
+                    "at -O{}",
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn small_operands_take_a_byte() {
+        let bytes = encode(
+            &[Opcode::CHANGE {
+                arg: -1,
+                offset: 63,
+            }],
+            VM_CONFIG,
+        );
+        assert_eq!(bytes[HEADER_SIZE..], [0, 1, 126]);
+    }
+
+    #[test]
+    fn rejects_a_bad_header() {
+        assert_eq!(decode(b"BF"), Err(BytecodeError::BadMagic));
+        assert_eq!(decode(b"BFD\0\x01\x00"), Err(BytecodeError::BadMagic));
+
+        let mut version = encode(&[Opcode::MOVE { arg: 1 }], VM_CONFIG);
+        version[4] = 2;
+        assert_eq!(decode(&version), Err(BytecodeError::UnsupportedVersion(2)));
+
+        let config = BytecodeConfig {
+            cell_width: 16,
+            ..VM_CONFIG
+        };
+        assert_eq!(
+            decode(&encode(&[Opcode::MOVE { arg: 1 }], config)),
+            Err(BytecodeError::MismatchedConfig(config))
+        );
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let opcodes = strip_spans(&PassManager::with_level(3).compile(EXAMPLES[0].to_string()));
+        let bytes = encode(&opcodes, VM_CONFIG);
+
+        for len in 4..bytes.len() {
+            assert_eq!(
+                decode(&bytes[..len]),
+                Err(BytecodeError::Truncated),
+                "at {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_opcodes() {
+        assert_eq!(
+            decode(&with_body(2, &[1, 2, 99])),
+            Err(BytecodeError::UnknownOpcode { tag: 99, ip: 1 })
+        );
+        // a varint that never ends, and one that doesn't fit an i32
+        assert_eq!(
+            decode(&with_body(
+                1,
+                &[
+                    1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff
+                ]
+            )),
+            Err(BytecodeError::BadOperand { ip: 0 })
+        );
+        assert_eq!(
+            decode(&with_body(1, &[1, 0x80, 0x80, 0x80, 0x80, 0x10])),
+            Err(BytecodeError::BadOperand { ip: 0 })
+        );
+        assert_eq!(
+            decode(&with_body(1, &[11, 9, 1, 2, 3, 4, 5, 6, 7, 8, 9])),
+            Err(BytecodeError::BadOperand { ip: 0 })
+        );
+        assert_eq!(
+            decode(&with_body(1, &[1, 2, 0])),
+            Err(BytecodeError::TrailingBytes)
+        );
+        // a bad count can't make it allocate more than the file could hold
+        assert_eq!(
+            decode(&with_body(u32::MAX, &[1, 2])),
+            Err(BytecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn rejects_programs_the_vm_can_not_run() {
+        let bytes = encode(
+            &[Opcode::JUMPIFZERO { arg: 1 }, Opcode::MOVE { arg: 1 }],
+            VM_CONFIG,
+        );
+        assert_eq!(
+            decode(&bytes),
+            Err(BytecodeError::Invalid(ValidationError::UnmatchedJump {
+                ip: 0
+            }))
+        );
+        // more chars than the VM can count, which it would otherwise panic on
+        let bytes = encode(
+            &[Opcode::PUTCHAR {
+                arg: 1 << 33,
+                offset: 0,
+            }],
+            VM_CONFIG,
+        );
+        assert_eq!(
+            decode(&bytes),
+            Err(BytecodeError::Invalid(ValidationError::CountOutOfRange {
+                ip: 0
+            }))
+        );
+    }
+}
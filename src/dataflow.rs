@@ -0,0 +1,620 @@
+use crate::ir::{Node, build_tree, lower_tree};
+use crate::opcodes::{
+    Opcode, PRINT_WIDTH, SpannedOpcode, carry, count_down, divmod, reset_bracket, triangle,
+    wrap_cell_value,
+};
+use crate::state::TAPE_SIZE;
+use std::collections::HashMap;
+
+// the most opcodes a loop with a known trip count is unrolled into
+const MAX_UNROLLED_OPCODES: usize = 64;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct CellState {
+    // what we know about the tape at some point in the program, cells are kept by position,
+    // which is counted from the start of the program for as long as we know where the data
+    // pointer is, and from wherever we lost track of it after that
+    pointer: i32,
+    // None for a cell we know nothing about
+    cells: HashMap<i32, Option<u8>>,
+    // while we still know where we are on the tape, any cell we haven't touched is zero
+    untouched_is_zero: bool,
+}
+
+impl CellState {
+    #[must_use]
+    pub fn new() -> Self {
+        // the state at the start of the program, every cell is zero
+        Self {
+            pointer: 0,
+            cells: HashMap::new(),
+            untouched_is_zero: true,
+        }
+    }
+
+    #[must_use]
+    pub fn value(&self, offset: i32) -> Option<u8> {
+        // the value of the cell at the offset from the data pointer, if we know it
+        let pos = self.pointer + offset;
+
+        match self.cells.get(&pos) {
+            Some(&value) => value,
+            // cells off the tape are an error when they are used, not zero
+            None if self.untouched_is_zero && pos >= 0 && (pos as usize) < TAPE_SIZE => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, offset: i32, value: Option<u8>) {
+        self.cells.insert(self.pointer + offset, value);
+    }
+
+    pub fn forget_everything(&mut self) {
+        // after the data pointer has moved some unknown amount we don't know anything anymore
+        self.pointer = 0;
+        self.cells.clear();
+        self.untouched_is_zero = false;
+    }
+
+    pub fn apply(&mut self, op: Opcode) {
+        match op {
+            Opcode::CHANGE { arg, offset } => {
+                let value = self.value(offset).map(|x| x.wrapping_add(arg as u8));
+                self.set(offset, value);
+            }
+            Opcode::MOVE { arg } => self.pointer += arg,
+            Opcode::SETTO { arg, offset } => self.set(offset, Some(arg as u8)),
+            Opcode::READCHAR { offset, .. } => self.set(offset, None),
+            Opcode::PUTCHAR { .. } | Opcode::PRINT { .. } => {}
+            Opcode::MULTI { arg1, arg2 } => {
+                let value = match (self.value(0), self.value(arg1)) {
+                    (Some(0), target) => target,
+                    (Some(source), Some(target)) => {
+                        Some(target.wrapping_add(source.wrapping_mul(arg2 as u8)))
+                    }
+                    _ => None,
+                };
+                self.set(arg1, value);
+            }
+            Opcode::PRODUCT { arg1, arg2, arg3 } => {
+                let value = match (self.value(0), self.value(arg2), self.value(arg1)) {
+                    (Some(0), _, target) | (_, Some(0), target) => target,
+                    (Some(source), Some(other), Some(target)) => Some(target.wrapping_add(
+                        source.wrapping_mul(other).wrapping_mul(arg3 as u8),
+                    )),
+                    _ => None,
+                };
+                self.set(arg1, value);
+            }
+            Opcode::TRIANGLE { arg1, arg2 } => {
+                let value = match (self.value(0), self.value(arg1)) {
+                    (Some(0), target) => target,
+                    (Some(source), Some(target)) => {
+                        Some(target.wrapping_add(triangle(source).wrapping_mul(arg2 as u8)))
+                    }
+                    _ => None,
+                };
+                self.set(arg1, value);
+            }
+            Opcode::COPY { arg1, arg2 } => {
+                let source = self.value(0);
+                let target = match (source, self.value(arg1)) {
+                    (Some(0), target) => target,
+                    (Some(x), Some(y)) => Some(x.wrapping_add(y)),
+                    _ => None,
+                };
+                let value = match (source, self.value(arg2)) {
+                    (Some(x), Some(y)) => Some(x.wrapping_add(y)),
+                    (Some(0), temp) => temp,
+                    (source, Some(0)) => source,
+                    _ => None,
+                };
+                self.set(arg1, target);
+                self.set(0, value);
+                self.set(arg2, Some(0));
+            }
+            Opcode::CARRY { arg1, arg2, arg3 } => {
+                let (x, y) = self.value(0).map_or((None, None), |value| {
+                    let (x, y) = carry(value, arg3 as u8);
+                    (Some(x), Some(y))
+                });
+                let target = match (x, self.value(arg1)) {
+                    (Some(0), z) => z,
+                    (Some(x), Some(z)) => Some(z.wrapping_add(x)),
+                    _ => None,
+                };
+                let carried = match (y, self.value(arg2)) {
+                    (Some(0), z) => z,
+                    (Some(y), Some(z)) => Some(z.wrapping_add(y)),
+                    _ => None,
+                };
+                self.set(arg1, target);
+                self.set(arg2, carried);
+                self.set(0, Some(0));
+            }
+            Opcode::AND { arg1, arg2 } => {
+                // if the cell under the data pointer might be zero, only what comes out the same
+                // either way is known
+                let flag = self.value(arg1).map(|x| u8::from(x != 0));
+                let (target, other) = match self.value(0) {
+                    Some(0) => (self.value(arg2), self.value(arg1)),
+                    Some(_) => (flag, Some(0)),
+                    None => (
+                        flag.filter(|&x| Some(x) == self.value(arg2)),
+                        self.value(arg1).filter(|&x| x == 0),
+                    ),
+                };
+                self.set(arg2, target);
+                self.set(arg1, other);
+                self.set(0, Some(0));
+            }
+            Opcode::COUNTDOWN { arg1, arg2 } => {
+                let (x, y) = match (self.value(0), self.value(arg1), self.value(arg2)) {
+                    (Some(0), x, y) => (x, y),
+                    (Some(_), Some(x), Some(y)) => {
+                        let (x, y) = count_down(x, y);
+                        (Some(x), Some(y))
+                    }
+                    _ => (None, None),
+                };
+                self.set(arg1, x);
+                self.set(arg2, y);
+                self.set(0, Some(0));
+            }
+            Opcode::DIVMOD { arg1, arg2, arg3 } => {
+                let values = (self.value(arg1), self.value(arg2), self.value(arg3));
+                let (quotient, left) = match (self.value(0), values) {
+                    (Some(0), (quotient, _, left)) => (quotient, left),
+                    (Some(value), (Some(quotient), Some(divisor), Some(left))) => {
+                        divmod(value, divisor, left).map_or((None, None), |(x, y)| {
+                            (Some(quotient.wrapping_add(x)), Some(y))
+                        })
+                    }
+                    _ => (None, None),
+                };
+                self.set(arg1, quotient);
+                self.set(arg3, left);
+                self.set(0, Some(0));
+            }
+            // these move the data pointer until they find a zero, we only know where they stop
+            // if they don't run at all
+            Opcode::SCANBY { .. } | Opcode::MOVINGCHANGE { .. } | Opcode::MOVINGSET { .. } => {
+                if self.value(0) != Some(0) {
+                    self.forget_everything();
+                    self.set(0, Some(0));
+                }
+            }
+            // loops are handled by apply_loop as a whole
+            Opcode::JUMPIFZERO { .. } | Opcode::JUMPIFNZERO { .. } | Opcode::ENDIF { .. } => {}
+        }
+    }
+
+    pub fn apply_loop(&mut self, body: &[Node]) {
+        // the state after a loop that might run any number of times, everything the body
+        // might change is unknown and the cell the loop tests is zero
+        match written_offsets(body) {
+            Some(offsets) => {
+                for offset in offsets {
+                    self.set(offset, None);
+                }
+            }
+            None => self.forget_everything(),
+        }
+
+        self.set(0, Some(0));
+    }
+
+    #[must_use]
+    pub fn loop_body_state(&self, body: &[Node]) -> Self {
+        // the body has to be right for every time around the loop, so at its start we only know
+        // about the cells the body doesn't change
+        let mut state = self.clone();
+        state.apply_loop(body);
+        state.set(0, None);
+        state
+    }
+}
+
+impl Default for CellState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn written_offsets(body: &[Node]) -> Option<Vec<i32>> {
+    // the cells a loop body might write to, relative to where the loop starts, if the body
+    // always ends up where it started, otherwise we can't know
+    let mut offset = 0;
+    let mut offsets = vec![];
+
+    for node in body {
+        match node {
+            Node::Op(op) => match op.op {
+                Opcode::MOVE { arg } => offset += arg,
+                Opcode::CHANGE { offset: x, .. }
+                | Opcode::SETTO { offset: x, .. }
+                | Opcode::READCHAR { offset: x, .. } => offsets.push(offset + x),
+                Opcode::MULTI { arg1, .. }
+                | Opcode::PRODUCT { arg1, .. }
+                | Opcode::TRIANGLE { arg1, .. } => offsets.push(offset + arg1),
+                Opcode::COPY { arg1, arg2 }
+                | Opcode::CARRY { arg1, arg2, .. }
+                | Opcode::AND { arg1, arg2 }
+                | Opcode::COUNTDOWN { arg1, arg2 } => {
+                    offsets.extend([offset, offset + arg1, offset + arg2]);
+                }
+                // the divisor is left as it is
+                Opcode::DIVMOD { arg1, arg3, .. } => {
+                    offsets.extend([offset, offset + arg1, offset + arg3]);
+                }
+                Opcode::PUTCHAR { .. } | Opcode::PRINT { .. } => {}
+                _ => return None,
+            },
+            Node::Loop { body, .. } | Node::If { body, .. } => {
+                offsets.extend(written_offsets(body)?.into_iter().map(|x| x + offset));
+            }
+        }
+    }
+
+    if offset != 0 {
+        return None;
+    }
+
+    Some(offsets)
+}
+
+fn simplify(op: Opcode, state: &CellState) -> Option<Opcode> {
+    // the simplest opcode that does the same as this one given what we know, or None if it
+    // does nothing at all
+    match op {
+        Opcode::CHANGE { arg, .. } if arg.rem_euclid(256) == 0 => None,
+        // a change to a known value is just setting the new value
+        Opcode::CHANGE { arg, offset } => Some(state.value(offset).map_or(op, |value| {
+            Opcode::SETTO {
+                arg: wrap_cell_value(i32::from(value) + arg),
+                offset,
+            }
+        })),
+        Opcode::SETTO { arg, offset } if state.value(offset) == Some(arg as u8) => None,
+        Opcode::MULTI { arg1, arg2 } => match state.value(0) {
+            Some(0) => None,
+            // with a known source this is adding a known amount to the target
+            Some(source) => simplify(
+                Opcode::CHANGE {
+                    arg: wrap_cell_value(i32::from(source) * arg2),
+                    offset: arg1,
+                },
+                state,
+            ),
+            None => Some(op),
+        },
+        Opcode::SCANBY { .. }
+        | Opcode::MOVINGCHANGE { .. }
+        | Opcode::MOVINGSET { .. }
+        | Opcode::PRODUCT { .. }
+        | Opcode::TRIANGLE { .. }
+        | Opcode::CARRY { .. }
+        | Opcode::AND { .. }
+        | Opcode::COUNTDOWN { .. }
+        | Opcode::DIVMOD { .. }
+            if state.value(0) == Some(0) =>
+        {
+            None
+        }
+        _ => Some(op),
+    }
+}
+
+fn unroll_loop(body: &[Node], state: &CellState) -> Option<Vec<Node>> {
+    // a loop without loops inside of it on a known cell runs a known number of times, if it
+    // doesn't run too many times we can write it out, e.g. +++[>.<-] becomes >.<->.<->.<-
+    let ops = body
+        .iter()
+        .map(|x| match x {
+            Node::Op(op) => Some(*op),
+            Node::Loop { .. } | Node::If { .. } => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    if ops.is_empty() {
+        return None;
+    }
+
+    let mut state = state.clone();
+    let mut unrolled = vec![];
+
+    while state.value(0)? != 0 {
+        if unrolled.len() + ops.len() > MAX_UNROLLED_OPCODES {
+            return None;
+        }
+
+        for op in &ops {
+            state.apply(op.op);
+        }
+        unrolled.extend(ops.iter().map(|&x| Node::Op(x)));
+    }
+
+    Some(unrolled)
+}
+
+fn propagate_block(block: Vec<Node>, state: &mut CellState) -> Vec<Node> {
+    let mut output = Vec::with_capacity(block.len());
+
+    for node in block {
+        match node {
+            Node::Op(op) => {
+                if let Some(new_op) = simplify(op.op, state) {
+                    output.push(Node::Op(SpannedOpcode::new(new_op, op.span)));
+                }
+                state.apply(op.op);
+            }
+            Node::Loop { body, open, close } => {
+                // a loop on a zero cell is never entered
+                if state.value(0) == Some(0) {
+                    continue;
+                }
+
+                if let Some(unrolled) = unroll_loop(&body, state) {
+                    output.extend(propagate_block(unrolled, state));
+                    continue;
+                }
+
+                let mut body_state = state.loop_body_state(&body);
+                state.apply_loop(&body);
+
+                let body = propagate_block(body, &mut body_state);
+                output.push(Node::Loop { body, open, close });
+            }
+            Node::If { body, open, close } => match state.value(0) {
+                Some(0) => {}
+                // the body runs exactly once, and leaves the cell at zero on its own
+                Some(_) => output.extend(propagate_block(body, state)),
+                None => {
+                    let mut body_state = state.clone();
+                    body_state.set(0, None);
+                    state.apply_loop(&body);
+
+                    let body = propagate_block(body, &mut body_state);
+                    output.push(Node::If { body, open, close });
+                }
+            },
+        }
+    }
+
+    output
+}
+
+pub fn propagate_constants(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // follows what is known about the cells from the start of the program, and uses it to
+    // fold changes into known values, drop loops on cells that are zero, write out loops that
+    // run a known number of times and turn MULTI on a known cell into a plain change
+    let tree = build_tree(&opcodes);
+    let tree = propagate_block(tree, &mut CellState::new());
+
+    lower_tree(&tree)
+}
+
+fn print_opcodes(text: &[u8]) -> Vec<Opcode> {
+    text.chunks(PRINT_WIDTH)
+        .map(|chunk| {
+            let mut bytes = [0; PRINT_WIDTH];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+
+            Opcode::PRINT {
+                bytes,
+                len: chunk.len() as u8,
+            }
+        })
+        .collect()
+}
+
+fn print_block(block: Vec<Node>, state: &mut CellState) -> Vec<Node> {
+    let mut output = Vec::with_capacity(block.len());
+
+    for node in block {
+        match node {
+            Node::Op(op) => {
+                if let Opcode::PUTCHAR { arg, offset } = op.op
+                    && let Some(value) = state.value(offset)
+                {
+                    let text = vec![value; arg];
+                    output.extend(
+                        print_opcodes(&text)
+                            .into_iter()
+                            .map(|x| Node::Op(SpannedOpcode::new(x, op.span))),
+                    );
+                } else {
+                    output.push(Node::Op(op));
+                }
+
+                state.apply(op.op);
+            }
+            Node::Loop { body, open, close } => {
+                let mut body_state = state.loop_body_state(&body);
+                state.apply_loop(&body);
+
+                let body = print_block(body, &mut body_state);
+                output.push(Node::Loop { body, open, close });
+            }
+            Node::If { body, open, close } => {
+                let mut body_state = state.clone();
+                body_state.set(0, None);
+                state.apply_loop(&body);
+
+                let body = print_block(body, &mut body_state);
+                output.push(Node::If { body, open, close });
+            }
+        }
+    }
+
+    output
+}
+
+fn is_overwritten(rest: &[SpannedOpcode], offset: i32) -> bool {
+    // whether the cell at the offset is set again before anything reads it, we only look as far
+    // as the data pointer stays where it is
+    for opcode in rest {
+        match opcode.op {
+            Opcode::SETTO { offset: x, .. } | Opcode::READCHAR { offset: x, .. } if x == offset => {
+                return true;
+            }
+            Opcode::CHANGE { offset: x, .. } | Opcode::PUTCHAR { offset: x, .. } if x == offset => {
+                return false;
+            }
+            Opcode::MULTI { arg1, .. } if offset == 0 || arg1 == offset => return false,
+            Opcode::CHANGE { .. }
+            | Opcode::SETTO { .. }
+            | Opcode::PUTCHAR { .. }
+            | Opcode::READCHAR { .. }
+            | Opcode::MULTI { .. }
+            | Opcode::PRINT { .. } => {}
+            _ => return false,
+        }
+    }
+
+    // nothing reads anything after the end of the program
+    true
+}
+
+fn remove_dead_stores(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // once the text is printed straight out, what was put in the cells to print it is often
+    // never used again
+    (0..opcodes.len())
+        .filter(|&pos| match opcodes[pos].op {
+            Opcode::CHANGE { offset, .. } | Opcode::SETTO { offset, .. } => {
+                !is_overwritten(&opcodes[(pos + 1)..], offset)
+            }
+            _ => true,
+        })
+        .map(|pos| opcodes[pos])
+        .collect()
+}
+
+fn merge_prints(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // PRINTs next to each other are packed into as few as possible
+    let mut output = Vec::with_capacity(opcodes.len());
+
+    for run in opcodes.chunk_by(|a, b| {
+        matches!((a.op, b.op), (Opcode::PRINT { .. }, Opcode::PRINT { .. }))
+    }) {
+        if !matches!(run[0].op, Opcode::PRINT { .. }) {
+            output.extend_from_slice(run);
+            continue;
+        }
+
+        let span = run[0].span.merge(run[run.len() - 1].span);
+        let text = run
+            .iter()
+            .flat_map(|x| match x.op {
+                Opcode::PRINT { bytes, len } => bytes[..usize::from(len)].to_vec(),
+                _ => vec![],
+            })
+            .collect::<Vec<_>>();
+
+        output.extend(print_opcodes(&text).into_iter().map(|x| SpannedOpcode::new(x, span)));
+    }
+
+    output
+}
+
+pub fn gen_print_ops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // a PUTCHAR on a cell we know the value of prints the same thing every time, so the text can
+    // be printed as it is, without the cells that held it
+    let tree = build_tree(&opcodes);
+    let tree = print_block(tree, &mut CellState::new());
+
+    reset_bracket(merge_prints(remove_dead_stores(lower_tree(&tree))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opcodes::{Opcode, PRINT_WIDTH, strip_spans};
+    use crate::partial_eval::run_to_end;
+    use crate::passes::{Pass, PassManager};
+
+    const PROGRAMS: [&str; 7] = [
+        "+++[>++<-]>.",
+        "[->+<]>+.<[>.<-]",
+        "++[>+++[>++++<-]<-]>>.",
+        "+++++[>+++++<-]>[>+>++<<-]>.>.>.",
+        ">+<[>[-]<]>.",
+        include_str!("../examples/hello_world.bf"),
+        include_str!("../examples/99.bf"),
+    ];
+
+    fn compile(source: &str, level: u8, enabled: &[Pass]) -> Vec<Opcode> {
+        let opcodes = PassManager::from_flags(level, enabled, &[]).compile(source.to_string());
+        let expected = run_to_end(&PassManager::new().compile(source.to_string()), 10_000_000);
+        assert!(expected.is_some(), "{:?} doesn't finish", source);
+        assert_eq!(
+            run_to_end(&opcodes, 10_000_000),
+            expected,
+            "{:?} at -O{} with {:?}",
+            source,
+            level,
+            enabled
+        );
+        strip_spans(&opcodes)
+    }
+
+    #[test]
+    fn constants_keep_the_output() {
+        for source in PROGRAMS {
+            for level in 0..=2 {
+                compile(source, level, &[Pass::PropagateConstants]);
+                compile(
+                    source,
+                    level,
+                    &[Pass::PropagateConstants, Pass::GenPrinting],
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn known_loops_are_unrolled() {
+        assert_eq!(
+            compile("+++[>++<-]>.", 1, &[Pass::PropagateConstants]),
+            [
+                Opcode::SETTO { arg: 3, offset: 0 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::SETTO { arg: 2, offset: 0 },
+                Opcode::MOVE { arg: -1 },
+                Opcode::SETTO { arg: 2, offset: 0 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::SETTO { arg: 4, offset: 0 },
+                Opcode::MOVE { arg: -1 },
+                Opcode::SETTO { arg: 1, offset: 0 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::SETTO { arg: 6, offset: 0 },
+                Opcode::MOVE { arg: -1 },
+                Opcode::SETTO { arg: 0, offset: 0 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::PUTCHAR { arg: 1, offset: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_on_zero_cells_are_dropped() {
+        let opcodes = compile("[->+<]>+.", 1, &[Pass::PropagateConstants]);
+        assert!(
+            !opcodes
+                .iter()
+                .any(|x| matches!(x, Opcode::JUMPIFZERO { .. }))
+        );
+    }
+
+    #[test]
+    fn known_text_is_printed_as_it_is() {
+        let opcodes = compile(
+            "++++++++[>++++++++<-]>+.+.[-]",
+            1,
+            &[Pass::PropagateConstants, Pass::GenPrinting],
+        );
+        let mut bytes = [0; PRINT_WIDTH];
+        bytes[..2].copy_from_slice(b"AB");
+        assert_eq!(opcodes.last(), Some(&Opcode::PRINT { bytes, len: 2 }));
+        assert!(!opcodes.iter().any(|x| matches!(x, Opcode::PUTCHAR { .. })));
+    }
+}
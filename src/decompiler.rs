@@ -0,0 +1,713 @@
+use crate::dataflow::CellState;
+use crate::idioms::all_different;
+use crate::ir::{Node, build_tree};
+use crate::opcodes::{Opcode, SpannedOpcode};
+use crate::span::Span;
+use crate::validation::{ValidationError, validate};
+use std::fmt;
+
+// how far from the data pointer to look for a cell to print from or count with
+const MAX_SCRATCH_DISTANCE: i32 = 64;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DecompileError {
+    // BF can only multiply by emptying the source cell, so a MULTI has to be followed by a SETTO
+    // or have a cell known to be zero nearby to keep the source in
+    UnterminatedMulti { ip: usize },
+    // a MULTI that adds into its own source cell can't be written as a loop
+    SelfMulti { ip: usize },
+    // BF can only print what is in a cell, and there is no cell nearby we know the value of
+    Print { ip: usize },
+    // PRODUCT and TRIANGLE stand in for nested loops, which need two cells known to be zero to
+    // count with
+    NestedLoop { ip: usize },
+    // AND, COUNTDOWN and DIVMOD work on several cells at once, which can't be written as loops
+    // when two of them are the same cell
+    SharedCells { ip: usize },
+    // opcodes that don't hold together, like a jump without its partner or a CARRY at 0
+    Invalid(ValidationError),
+}
+
+impl fmt::Display for DecompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnterminatedMulti { ip } => {
+                write!(f, "the MULTI at {} is not followed by a SETTO", ip)
+            }
+            Self::SelfMulti { ip } => write!(f, "the MULTI at {} adds to its own cell", ip),
+            Self::Print { ip } => {
+                write!(f, "the PRINT at {} has no spare cell to print from", ip)
+            }
+            Self::NestedLoop { ip } => {
+                write!(f, "the nested loop at {} has no spare cells to count with", ip)
+            }
+            Self::SharedCells { ip } => {
+                write!(f, "the opcode at {} uses the same cell twice", ip)
+            }
+            Self::Invalid(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+pub fn decompile(opcodes: &[Opcode]) -> Result<String, DecompileError> {
+    // lowers opcodes back into plain BF, assuming 8 bit cells, every opcode keeps its position
+    // as its span so errors can point at it, they don't have to come from the compiler so they
+    // are checked first
+    validate(opcodes).map_err(DecompileError::Invalid)?;
+
+    let opcodes = opcodes
+        .iter()
+        .enumerate()
+        .map(|(ip, &op)| SpannedOpcode::new(op, Span::new(ip, ip + 1)))
+        .collect::<Vec<_>>();
+
+    let mut output = String::new();
+    decompile_block(&build_tree(&opcodes), &mut CellState::new(), &mut output)?;
+
+    Ok(output)
+}
+
+fn decompile_block(
+    block: &[Node],
+    state: &mut CellState,
+    output: &mut String,
+) -> Result<(), DecompileError> {
+    // what is known about the cells is followed along, as PRINT, PRODUCT, TRIANGLE, COUNTDOWN
+    // and DIVMOD need cells to work in that the program isn't using at the time
+    let mut pos = 0;
+
+    while pos < block.len() {
+        let op = match &block[pos] {
+            Node::Op(op) => *op,
+            Node::Loop { body, .. } | Node::If { body, .. } => {
+                // an if is written as a loop, the body zeroes the cell so it stops there anyway,
+                // and as it runs once at most it starts with everything we know before it
+                let mut body_state = match &block[pos] {
+                    Node::If { .. } => state.clone(),
+                    _ => state.loop_body_state(body),
+                };
+                state.apply_loop(body);
+
+                output.push('[');
+                decompile_block(body, &mut body_state, output)?;
+                output.push(']');
+
+                pos += 1;
+                continue;
+            }
+        };
+
+        let ip = op.span.start;
+
+        match op.op {
+            Opcode::MULTI { .. } => {
+                // a run of MULTI followed by a SETTO is what is left of a multiplication loop
+                let multis = block[pos..]
+                    .iter()
+                    .map_while(|x| match x {
+                        Node::Op(x) if matches!(x.op, Opcode::MULTI { .. }) => Some(x.op),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                // changes to other cells and sets of cells the loop doesn't add to can come in
+                // between, as the loop can be run before them all the same
+                let targets = multis
+                    .iter()
+                    .filter_map(|x| match *x {
+                        Opcode::MULTI { arg1, .. } => Some(arg1),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let changes = block[(pos + multis.len())..]
+                    .iter()
+                    .map_while(|x| match x {
+                        Node::Op(x) => match x.op {
+                            Opcode::CHANGE { offset, .. } if offset != 0 => Some(x.op),
+                            Opcode::SETTO { offset, .. }
+                                if offset != 0 && !targets.contains(&offset) =>
+                            {
+                                Some(x.op)
+                            }
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                let Some(&Node::Op(SpannedOpcode {
+                    op: clear @ Opcode::SETTO { arg, offset: 0 },
+                    ..
+                })) = block.get(pos + multis.len() + changes.len())
+                else {
+                    // without a SETTO to empty the source cell it has to be put back afterwards
+                    let code = multi_copy_to_bf(&multis, ip, state)?;
+                    output.push_str(&code);
+
+                    for &multi in &multis {
+                        state.apply(multi);
+                    }
+
+                    pos += multis.len();
+                    continue;
+                };
+
+                output.push_str(&multi_loop_to_bf(&multis, ip)?);
+                output.extend(changes.iter().map(|&x| opcode_to_bf(x)));
+                output.push_str(&change_to_bf(arg));
+
+                for &op in multis.iter().chain(&changes) {
+                    state.apply(op);
+                }
+                state.apply(clear);
+
+                pos += multis.len() + changes.len() + 1;
+                continue;
+            }
+            Opcode::PRINT { bytes, len } => {
+                let code = print_to_bf(&bytes[..usize::from(len)], state)
+                    .ok_or(DecompileError::Print { ip })?;
+                output.push_str(&code);
+            }
+            Opcode::PRODUCT { arg1, arg2, arg3 } => {
+                let code = find_zero_cells(state, &[0, arg1, arg2])
+                    .filter(|_| arg1 != 0 && arg1 != arg2)
+                    .map(|[x, y]| product_to_bf(arg1, arg2, arg3, x, y))
+                    .ok_or(DecompileError::NestedLoop { ip })?;
+                output.push_str(&code);
+            }
+            Opcode::TRIANGLE { arg1, arg2 } => {
+                let code = find_zero_cells(state, &[0, arg1])
+                    .filter(|_| arg1 != 0)
+                    .map(|[x, y]| triangle_to_bf(arg1, arg2, x, y))
+                    .ok_or(DecompileError::NestedLoop { ip })?;
+                output.push_str(&code);
+            }
+            Opcode::AND { arg1, arg2 } => {
+                if !all_different(&[0, arg1, arg2]) {
+                    return Err(DecompileError::SharedCells { ip });
+                }
+
+                output.push_str(&and_to_bf(arg1, arg2));
+            }
+            Opcode::COUNTDOWN { arg1, arg2 } => {
+                if !all_different(&[0, arg1, arg2]) {
+                    return Err(DecompileError::SharedCells { ip });
+                }
+
+                let [x, y] = find_zero_cells(state, &[0, arg1, arg2])
+                    .ok_or(DecompileError::NestedLoop { ip })?;
+                output.push_str(&count_down_to_bf(arg1, arg2, x, y));
+            }
+            Opcode::DIVMOD { arg1, arg2, arg3 } => {
+                if !all_different(&[0, arg1, arg2, arg3]) {
+                    return Err(DecompileError::SharedCells { ip });
+                }
+
+                let [x, y, flag] = find_zero_cells(state, &[0, arg1, arg2, arg3])
+                    .ok_or(DecompileError::NestedLoop { ip })?;
+                output.push_str(&divmod_to_bf(arg1, arg2, arg3, [x, y, flag]));
+            }
+            other => output.push_str(&opcode_to_bf(other)),
+        }
+
+        state.apply(op.op);
+        pos += 1;
+    }
+
+    Ok(())
+}
+
+pub fn change_to_bf(amount: i32) -> String {
+    // with wrapping cells, adding k is the same as subtracting 256 - k
+    let amount = amount.rem_euclid(256) as usize;
+
+    if amount <= 128 {
+        "+".repeat(amount)
+    } else {
+        "-".repeat(256 - amount)
+    }
+}
+
+pub fn move_to_bf(amount: i32) -> String {
+    if amount >= 0 {
+        ">".repeat(amount as usize)
+    } else {
+        "<".repeat(amount.unsigned_abs() as usize)
+    }
+}
+
+fn multi_loop_to_bf(multis: &[Opcode], start: usize) -> Result<String, DecompileError> {
+    // e.g. MULTI(1, 2), MULTI(3, 1) becomes [->++>>+<<<]
+    let mut output = String::from("[-");
+    let mut offset = 0;
+
+    for (pos, &op) in multis.iter().enumerate() {
+        if let Opcode::MULTI { arg1, arg2 } = op {
+            if arg1 == 0 {
+                return Err(DecompileError::SelfMulti { ip: start + pos });
+            }
+
+            output.push_str(&move_to_bf(arg1 - offset));
+            output.push_str(&change_to_bf(arg2));
+            offset = arg1;
+        }
+    }
+
+    output.push_str(&move_to_bf(-offset));
+    output.push(']');
+
+    Ok(output)
+}
+
+fn multi_copy_to_bf(
+    multis: &[Opcode],
+    start: usize,
+    state: &CellState,
+) -> Result<String, DecompileError> {
+    // e.g. MULTI(1, 2) with the cell at 2 known to be zero becomes [->++>+<<]>>[-<<+>>]<<, the
+    // source cell is emptied into the targets and a temporary cell that is emptied back into it
+    let mut targets = vec![];
+
+    for (pos, &op) in multis.iter().enumerate() {
+        if let Opcode::MULTI { arg1, arg2 } = op {
+            if arg1 == 0 {
+                return Err(DecompileError::SelfMulti { ip: start + pos });
+            }
+
+            targets.push((arg1, arg2));
+        }
+    }
+
+    let in_use = targets
+        .iter()
+        .map(|&(x, _)| x)
+        .chain([0])
+        .collect::<Vec<_>>();
+    let [temp] =
+        find_zero_cells(state, &in_use).ok_or(DecompileError::UnterminatedMulti { ip: start })?;
+
+    targets.push((temp, 1));
+
+    Ok(add_into(0, &targets) + &add_into(temp, &[(0, 1)]))
+}
+
+fn at_offset(offset: i32, code: &str) -> String {
+    // go over to the cell the opcode works on and come back again afterwards
+    format!("{}{}{}", move_to_bf(offset), code, move_to_bf(-offset))
+}
+
+fn nearby_offsets() -> impl Iterator<Item = i32> {
+    // the cells around the data pointer, closest first
+    (1..=MAX_SCRATCH_DISTANCE).flat_map(|x| [x, -x])
+}
+
+fn find_zero_cells<const N: usize>(state: &CellState, in_use: &[i32]) -> Option<[i32; N]> {
+    nearby_offsets()
+        .filter(|x| !in_use.contains(x) && state.value(*x) == Some(0))
+        .take(N)
+        .collect::<Vec<_>>()
+        .try_into()
+        .ok()
+}
+
+fn add_into(source: i32, targets: &[(i32, i32)]) -> String {
+    // empties the cell at source into the targets, each one times its factor
+    let mut body = String::from("[-");
+    let mut offset = source;
+
+    for &(x, factor) in targets {
+        body.push_str(&move_to_bf(x - offset));
+        body.push_str(&change_to_bf(factor));
+        offset = x;
+    }
+
+    body.push_str(&move_to_bf(source - offset));
+    body.push(']');
+
+    at_offset(source, &body)
+}
+
+fn print_to_bf(text: &[u8], state: &CellState) -> Option<String> {
+    // the text is put together in a cell we know the value of, which is put back afterwards,
+    // e.g. PRINT 72 105 on a cell that is zero adds 72 and prints it, adds 33 and prints it and
+    // then takes the 105 back off
+    let (offset, value) = std::iter::once(0)
+        .chain(nearby_offsets())
+        .find_map(|x| Some((x, state.value(x)?)))?;
+
+    let mut code = String::new();
+    let mut current = value;
+
+    for &byte in text {
+        code.push_str(&change_to_bf(i32::from(byte) - i32::from(current)));
+        code.push('.');
+        current = byte;
+    }
+
+    code.push_str(&change_to_bf(i32::from(value) - i32::from(current)));
+
+    Some(at_offset(offset, &code))
+}
+
+fn product_to_bf(target: i32, other: i32, factor: i32, counter: i32, temp: i32) -> String {
+    // the cell under the data pointer is copied into the counter, and every time around it
+    // the other cell is added to the target through the temporary cell, which puts it back
+    let mut output = add_into(0, &[(counter, 1), (temp, 1)]);
+    output.push_str(&add_into(temp, &[(0, 1)]));
+
+    let body = add_into(other, &[(target, factor), (temp, 1)]) + &add_into(temp, &[(other, 1)]);
+    let body = at_offset(-counter, &body);
+    output.push_str(&at_offset(counter, &format!("[-{}]", body)));
+
+    output
+}
+
+fn triangle_to_bf(target: i32, factor: i32, counter: i32, temp: i32) -> String {
+    // the cell under the data pointer is copied into the counter, which is added to the target
+    // and counted down by one until it gets to zero
+    let mut output = add_into(0, &[(counter, 1), (temp, 1)]);
+    output.push_str(&add_into(temp, &[(0, 1)]));
+
+    let body = add_into(counter, &[(target, factor), (temp, 1)]) + &add_into(temp, &[(counter, 1)]);
+    let body = at_offset(-counter, &body);
+    output.push_str(&at_offset(counter, &format!("[{}-]", body)));
+
+    output
+}
+
+fn carry_to_bf(target: i32, carried: i32, limit: u8) -> String {
+    // e.g. CARRY(1, 3, 3) becomes [->+<[->+<[->-->>+<<<[->+<]]]]
+    let mut output = format!("[-{}", at_offset(target, "+")).repeat(usize::from(limit) - 1);
+
+    output.push_str(&format!(
+        "[-{}{}[-{}]",
+        at_offset(target, &change_to_bf(1 - i32::from(limit))),
+        at_offset(carried, "+"),
+        at_offset(target, "+")
+    ));
+    output.push_str(&"]".repeat(usize::from(limit)));
+
+    output
+}
+
+fn copy_to_bf(source: i32, target: i32, temp: i32) -> String {
+    // copies the source cell into the target through the temporary cell, which is emptied back
+    add_into(source, &[(target, 1), (temp, 1)]) + &add_into(temp, &[(source, 1)])
+}
+
+fn and_to_bf(other: i32, target: i32) -> String {
+    // e.g. AND(1, 2) becomes [>>[-]<[>[-]+<[-]]<[-]]
+    let inner = format!("[{}[-]]", at_offset(target - other, "[-]+"));
+    format!(
+        "[{}{}[-]]",
+        at_offset(target, "[-]"),
+        at_offset(other, &inner)
+    )
+}
+
+fn flag_to_bf(first: i32, second: i32, x: i32, y: i32, flag: i32) -> String {
+    // sets the flag to whether both cells are non zero, through copies of them in x and y
+    let mut output = at_offset(flag, "[-]");
+    output.push_str(&copy_to_bf(first, x, flag));
+    output.push_str(&copy_to_bf(second, y, flag));
+    output.push_str(&at_offset(x, &and_to_bf(y - x, flag - x)));
+    output.push_str(&at_offset(y, "[-]"));
+
+    output
+}
+
+fn count_down_to_bf(first: i32, second: i32, x: i32, y: i32) -> String {
+    // the cell under the data pointer is the flag, which takes one off both cells every time
+    // around and is set again for as long as neither of them is zero
+    format!(
+        "[{}{}{}]",
+        at_offset(first, "-"),
+        at_offset(second, "-"),
+        flag_to_bf(first, second, x, y, 0)
+    )
+}
+
+fn divmod_to_bf(quotient: i32, divisor: i32, left: i32, [x, y, flag]: [i32; 3]) -> String {
+    // every time around one is added to the quotient and the divisor is copied into the cell
+    // for what is left, which is then counted down together with the cell under the data pointer
+    let body = format!(
+        "{}{}{}{}",
+        at_offset(quotient, "+"),
+        copy_to_bf(divisor, left, x),
+        flag_to_bf(0, left, x, y, flag),
+        at_offset(
+            flag,
+            &count_down_to_bf(-flag, left - flag, x - flag, y - flag)
+        )
+    );
+
+    format!("[{}]", body)
+}
+
+fn opcode_to_bf(op: Opcode) -> String {
+    match op {
+        Opcode::CHANGE { arg, offset } => at_offset(offset, &change_to_bf(arg)),
+        Opcode::MOVE { arg } => move_to_bf(arg),
+        Opcode::PUTCHAR { arg, offset } => at_offset(offset, &".".repeat(arg)),
+        Opcode::READCHAR { arg, offset } => at_offset(offset, &",".repeat(arg)),
+        // every clear loop comes out the same way
+        Opcode::SETTO { arg, offset } => {
+            at_offset(offset, &format!("[-]{}", change_to_bf(arg)))
+        }
+        Opcode::SCANBY { arg } => format!("[{}]", move_to_bf(arg)),
+        Opcode::MOVINGCHANGE { arg1, arg2, arg3 } => format!(
+            "[{}{}{}]",
+            move_to_bf(arg1),
+            change_to_bf(arg2),
+            move_to_bf(arg3)
+        ),
+        Opcode::MOVINGSET { arg1, arg2, arg3 } => format!(
+            "[{}[-]{}{}]",
+            move_to_bf(arg1),
+            change_to_bf(arg2),
+            move_to_bf(arg3)
+        ),
+        // the temporary cell is emptied back into the cell under the data pointer
+        Opcode::COPY { arg1, arg2 } => format!(
+            "[-{}{}]{}",
+            at_offset(arg1, "+"),
+            at_offset(arg2, "+"),
+            at_offset(arg2, &format!("[-{}]", at_offset(-arg2, "+")))
+        ),
+        Opcode::CARRY { arg1, arg2, arg3 } => carry_to_bf(arg1, arg2, arg3 as u8),
+        Opcode::JUMPIFZERO { .. }
+        | Opcode::JUMPIFNZERO { .. }
+        | Opcode::ENDIF { .. }
+        | Opcode::MULTI { .. }
+        | Opcode::PRINT { .. }
+        | Opcode::PRODUCT { .. }
+        | Opcode::TRIANGLE { .. }
+        | Opcode::AND { .. }
+        | Opcode::COUNTDOWN { .. }
+        | Opcode::DIVMOD { .. } => {
+            unreachable!("Expected {:?} to be decompiled by decompile_block", op)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::{PRINT_WIDTH, strip_spans};
+    use crate::partial_eval::run_to_end;
+    use crate::passes::{Pass, PassManager};
+
+    const EXAMPLES: [&str; 6] = [
+        include_str!("../examples/hello_world.bf"),
+        include_str!("../examples/squares.bf"),
+        include_str!("../examples/divide.bf"),
+        include_str!("../examples/factor.bf"),
+        include_str!("../examples/rot13.b"),
+        include_str!("../examples/triangle.bf"),
+    ];
+
+    fn compile(source: &str, level: u8) -> Vec<Opcode> {
+        strip_spans(&PassManager::with_level(level).compile(source.to_string()))
+    }
+
+    #[test]
+    fn round_trip_is_stable() {
+        // source -> opcodes -> BF -> opcodes gives back the opcodes it started from
+        for level in 0..=2 {
+            for source in EXAMPLES {
+                let opcodes = compile(source, level);
+                let decompiled = decompile(&opcodes).unwrap();
+                assert_eq!(compile(&decompiled, level), opcodes, "at -O{}", level);
+            }
+        }
+    }
+
+    #[test]
+    fn changes_take_the_short_way_around() {
+        assert_eq!(change_to_bf(3), "+++");
+        assert_eq!(change_to_bf(-2), "--");
+        assert_eq!(change_to_bf(255), "-");
+        assert_eq!(change_to_bf(-255), "+");
+        assert_eq!(change_to_bf(256), "");
+    }
+
+    #[test]
+    fn multi_groups_become_loops() {
+        let opcodes = [
+            Opcode::MULTI { arg1: 1, arg2: 2 },
+            Opcode::MULTI { arg1: 3, arg2: -1 },
+            Opcode::SETTO { arg: 0, offset: 0 },
+        ];
+        assert_eq!(decompile(&opcodes).unwrap(), "[->++>>-<<<]");
+    }
+
+    #[test]
+    fn keeps_the_source_of_a_multi_without_clear() {
+        let opcodes = [Opcode::MULTI { arg1: 1, arg2: 2 }, Opcode::MOVE { arg: 1 }];
+        assert_eq!(decompile(&opcodes).unwrap(), "[->++>+<<]>>[-<<+>>]<<>");
+    }
+
+    #[test]
+    fn rejects_multi_into_its_own_cell() {
+        let opcodes = [
+            Opcode::MULTI { arg1: 0, arg2: 2 },
+            Opcode::SETTO { arg: 0, offset: 0 },
+        ];
+        assert_eq!(decompile(&opcodes), Err(DecompileError::SelfMulti { ip: 0 }));
+    }
+
+    fn runs_the_same(source: &str, manager: &PassManager) -> Vec<Opcode> {
+        // decompiles the optimized program and checks it prints what the source does
+        let opcodes = strip_spans(&manager.compile(source.to_string()));
+        let decompiled = decompile(&opcodes).unwrap();
+        let run = |x: &str| run_to_end(&PassManager::new().compile(x.to_string()), 10_000_000);
+        let expected = run(source);
+
+        assert!(expected.is_some(), "{:?} doesn't finish", source);
+        assert_eq!(run(&decompiled), expected, "{:?}", source);
+        opcodes
+    }
+
+    #[test]
+    fn programs_are_decompiled_at_o3() {
+        let sources = [
+            include_str!("../examples/hello_world.bf"),
+            include_str!("../examples/triangle.bf"),
+            include_str!("../examples/99.bf"),
+        ];
+
+        for source in sources {
+            runs_the_same(source, &PassManager::with_level(3));
+        }
+
+        // without the prefix pass the nested loops and the text are left for the other passes
+        let manager = PassManager::from_flags(3, &[], &[Pass::EvaluatePrefix]);
+        let product = runs_the_same("+++>+++++<[>[->+>+<<]>>[-<<+>>]<<<-]>>.", &manager);
+        assert!(product.iter().any(|x| matches!(x, Opcode::PRODUCT { .. })));
+        let triangle = runs_the_same("++++++[[->+>+<<]>>[-<<+>>]<<-]>.", &manager);
+        assert!(triangle.iter().any(|x| matches!(x, Opcode::TRIANGLE { .. })));
+        let print = runs_the_same(">++++++++[>++++++++<-]>+.+.", &manager);
+        assert!(print.iter().any(|x| matches!(x, Opcode::PRINT { .. })));
+    }
+
+    #[test]
+    fn divisions_are_decompiled() {
+        // divide.bf doesn't print anything, so the cells it leaves are printed instead
+        let source = String::from(include_str!("../examples/divide.bf")) + "<<<<.>.>.>.>.>.>.";
+        let opcodes = runs_the_same(&source, &PassManager::with_level(2));
+
+        assert!(opcodes.iter().any(|x| matches!(x, Opcode::AND { .. })));
+        assert!(
+            opcodes
+                .iter()
+                .any(|x| matches!(x, Opcode::COUNTDOWN { .. }))
+        );
+        assert!(opcodes.iter().any(|x| matches!(x, Opcode::DIVMOD { .. })));
+    }
+
+    #[test]
+    fn prints_from_a_cell_it_knows() {
+        let mut bytes = [0; PRINT_WIDTH];
+        bytes[..2].copy_from_slice(b"Hi");
+        let expected = format!("{}.{}.{}", "+".repeat(72), "+".repeat(33), "-".repeat(105));
+
+        assert_eq!(
+            decompile(&[Opcode::PRINT { bytes, len: 2 }]).unwrap(),
+            expected
+        );
+        // the cell under the data pointer is unknown after the input, so the next one is used
+        assert_eq!(
+            decompile(&[
+                Opcode::READCHAR { arg: 1, offset: 0 },
+                Opcode::PRINT { bytes, len: 1 },
+            ])
+            .unwrap(),
+            format!(",>{}.{}<", "+".repeat(72), "-".repeat(72))
+        );
+    }
+
+    #[test]
+    fn rejects_opcodes_without_spare_cells() {
+        // after a loop that moves the data pointer we don't know where we are anymore
+        let unknown = [
+            Opcode::JUMPIFZERO { arg: 2 },
+            Opcode::MOVE { arg: 1 },
+            Opcode::JUMPIFNZERO { arg: 0 },
+            Opcode::READCHAR { arg: 1, offset: 0 },
+        ];
+        let with = |op| [&unknown[..], &[op]].concat();
+
+        assert_eq!(
+            decompile(&with(Opcode::PRINT {
+                bytes: [65; PRINT_WIDTH],
+                len: 1
+            })),
+            Err(DecompileError::Print { ip: 4 })
+        );
+        assert_eq!(
+            decompile(&with(Opcode::PRODUCT {
+                arg1: 1,
+                arg2: 2,
+                arg3: 1
+            })),
+            Err(DecompileError::NestedLoop { ip: 4 })
+        );
+        assert_eq!(
+            decompile(&with(Opcode::TRIANGLE { arg1: 1, arg2: 1 })),
+            Err(DecompileError::NestedLoop { ip: 4 })
+        );
+        assert_eq!(
+            decompile(&with(Opcode::COUNTDOWN { arg1: 1, arg2: 2 })),
+            Err(DecompileError::NestedLoop { ip: 4 })
+        );
+        assert_eq!(
+            decompile(&with(Opcode::DIVMOD {
+                arg1: 1,
+                arg2: 2,
+                arg3: 3
+            })),
+            Err(DecompileError::NestedLoop { ip: 4 })
+        );
+        assert_eq!(
+            decompile(&with(Opcode::MULTI { arg1: 1, arg2: 1 })),
+            Err(DecompileError::UnterminatedMulti { ip: 4 })
+        );
+    }
+
+    #[test]
+    fn rejects_opcodes_that_share_cells() {
+        assert_eq!(
+            decompile(&[Opcode::AND { arg1: 1, arg2: 1 }]),
+            Err(DecompileError::SharedCells { ip: 0 })
+        );
+        assert_eq!(
+            decompile(&[Opcode::COUNTDOWN { arg1: 0, arg2: 2 }]),
+            Err(DecompileError::SharedCells { ip: 0 })
+        );
+        assert_eq!(
+            decompile(&[Opcode::DIVMOD {
+                arg1: 1,
+                arg2: 2,
+                arg3: 1
+            }]),
+            Err(DecompileError::SharedCells { ip: 0 })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_opcodes() {
+        assert_eq!(
+            decompile(&[Opcode::CARRY {
+                arg1: 1,
+                arg2: 2,
+                arg3: 0
+            }]),
+            Err(DecompileError::Invalid(ValidationError::ZeroLimit { ip: 0 }))
+        );
+        assert_eq!(
+            decompile(&[Opcode::JUMPIFNZERO { arg: 0 }]),
+            Err(DecompileError::Invalid(ValidationError::UnmatchedJump { ip: 0 }))
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use crate::assembly::disassemble_lines;
+use crate::opcodes::{Opcode, SpannedOpcode, reset_bracket, strip_spans, tokenize_instructions};
+use crate::passes::PassManager;
+use crate::source_utils::{BracketDiagnostic, find_bracket_errors};
+use crate::span::LineIndex;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const OPCODE_WIDTH: usize = 44;
+const MAX_SOURCE_WIDTH: usize = 24;
+
+pub fn explain_source(
+    program_code: &str,
+    pass_manager: &PassManager,
+) -> Result<String, Vec<BracketDiagnostic>> {
+    // lists the opcodes after every pass, the ones a pass changed are marked with a * and every
+    // opcode is shown in the same text as disasm next to where it is in the source and the BF it
+    // came from, e.g.
+    // fold (iteration 1): 7 -> 3 opcodes, 3 changed
+    //   *     0  CHANGE +3                                    1:1    +++
+    let bracket_errors = find_bracket_errors(program_code);
+
+    if !bracket_errors.is_empty() {
+        return Err(bracket_errors);
+    }
+
+    let line_index = LineIndex::new(program_code);
+    let tokens = tokenize_instructions(program_code.to_string());
+
+    let mut output = format!("tokenize: {} opcodes\n", tokens.len());
+    render_listing(program_code, &line_index, &tokens, &[], &mut output);
+
+    let _ = pass_manager.run_with_observer(tokens, &mut |pass, iteration, before, after| {
+        // the passes leave fixing the jump targets until the end, so do it here to show them
+        let before = reset_bracket(before.to_vec());
+        let after = reset_bracket(after.to_vec());
+
+        let changed = find_changed(&before, &after);
+        let changed_count = changed.iter().filter(|&&x| x).count();
+
+        if changed_count == 0 && before.len() == after.len() {
+            let _ = writeln!(output, "{} (iteration {}): no changes", pass, iteration);
+            return;
+        }
+
+        let _ = writeln!(
+            output,
+            "{} (iteration {}): {} -> {} opcodes, {} changed",
+            pass,
+            iteration,
+            before.len(),
+            after.len(),
+            changed_count
+        );
+        render_listing(program_code, &line_index, &after, &changed, &mut output);
+    });
+
+    Ok(output)
+}
+
+const fn without_jump_targets(opcode: SpannedOpcode) -> SpannedOpcode {
+    // jump targets move around whenever anything before them is added or removed
+    let op = match opcode.op {
+        Opcode::JUMPIFZERO { .. } => Opcode::JUMPIFZERO { arg: 0 },
+        Opcode::JUMPIFNZERO { .. } => Opcode::JUMPIFNZERO { arg: 0 },
+        Opcode::ENDIF { .. } => Opcode::ENDIF { arg: 0 },
+        op => op,
+    };
+
+    SpannedOpcode::new(op, opcode.span)
+}
+
+fn find_changed(before: &[SpannedOpcode], after: &[SpannedOpcode]) -> Vec<bool> {
+    // an opcode is unchanged if the same opcode from the same part of the source was there
+    // before the pass
+    let mut remaining = HashMap::new();
+
+    for &opcode in before {
+        *remaining.entry(without_jump_targets(opcode)).or_insert(0_usize) += 1;
+    }
+
+    after
+        .iter()
+        .map(|&opcode| match remaining.get_mut(&without_jump_targets(opcode)) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+fn source_text(program_code: &str, opcode: SpannedOpcode) -> String {
+    // the BF that the opcode came from, without the comments around it
+    let text = program_code[opcode.span.start..opcode.span.end]
+        .chars()
+        .filter(|c| matches!(c, '+' | '-' | '<' | '>' | '.' | ',' | '[' | ']'))
+        .collect::<String>();
+
+    if text.chars().count() <= MAX_SOURCE_WIDTH {
+        return text;
+    }
+
+    let mut shortened = text.chars().take(MAX_SOURCE_WIDTH - 3).collect::<String>();
+    shortened.push_str("...");
+    shortened
+}
+
+fn render_listing(
+    program_code: &str,
+    line_index: &LineIndex,
+    opcodes: &[SpannedOpcode],
+    changed: &[bool],
+    output: &mut String,
+) {
+    let lines = disassemble_lines(&strip_spans(opcodes));
+
+    for (pos, (&opcode, text)) in opcodes.iter().zip(lines).enumerate() {
+        let marker = if changed.get(pos) == Some(&true) { '*' } else { ' ' };
+        let position = line_index.position(opcode.span.start);
+        let location = format!("{}:{}", position.line, position.column);
+
+        let _ = writeln!(
+            output,
+            "  {} {:>5}  {:<width$} {:<6} {}",
+            marker,
+            pos,
+            text,
+            location,
+            source_text(program_code, opcode),
+            width = OPCODE_WIDTH
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::Pass;
+
+    fn marked_lines(explanation: &str, pass: &str) -> Vec<String> {
+        // the opcodes marked as changed in the listing after the pass
+        explanation
+            .lines()
+            .skip_while(|x| !x.starts_with(pass))
+            .skip(1)
+            .take_while(|x| x.starts_with("  "))
+            .filter(|x| x.starts_with("  *"))
+            .map(|x| x.split_whitespace().skip(2).collect::<Vec<_>>().join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn only_what_a_pass_changed_is_marked() {
+        let passes = [Pass::CompressFoldable, Pass::GenClearing];
+        let manager = PassManager::from_flags(0, &passes, &[]);
+        let explanation = explain_source("+>[-+-]<+++.", &manager).unwrap();
+
+        assert!(explanation.starts_with("tokenize: 12 opcodes\n"));
+        assert_eq!(marked_lines(&explanation, "tokenize"), [] as [String; 0]);
+
+        // folding keeps the last - of the loop as it was, only the +++ is new
+        assert!(explanation.contains("fold (iteration 1): 12 -> 8 opcodes, 1 changed\n"));
+        assert_eq!(marked_lines(&explanation, "fold"), ["CHANGE +3 1:9 +++"]);
+
+        // the loop is cleared, the opcodes after it moved up but aren't changed
+        assert!(explanation.contains("clear (iteration 1): 8 -> 6 opcodes, 1 changed\n"));
+        assert_eq!(marked_lines(&explanation, "clear"), ["SETTO 0 1:3 [-+-]"]);
+    }
+
+    #[test]
+    fn passes_that_change_nothing_say_so() {
+        let manager = PassManager::from_flags(0, &[Pass::GenClearing], &[]);
+        let explanation = explain_source("+[>+<-]>.", &manager).unwrap();
+        assert!(explanation.ends_with("clear (iteration 1): no changes\n"));
+
+        assert!(explain_source("+[>+<-", &manager).is_err());
+    }
+
+    #[test]
+    fn moved_jump_targets_are_not_changes() {
+        // the two + are folded into one, which moves the loop back by one
+        let before = reset_bracket(tokenize_instructions(String::from("++[>+<-]")));
+        let mut after = before[2..].to_vec();
+        after.insert(
+            0,
+            SpannedOpcode::new(
+                Opcode::CHANGE { arg: 2, offset: 0 },
+                before[0].span.merge(before[1].span),
+            ),
+        );
+        let after = reset_bracket(after);
+
+        assert_ne!(strip_spans(&before[2..]), strip_spans(&after[1..]));
+        assert_eq!(
+            find_changed(&before, &after),
+            [true, false, false, false, false, false, false]
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use crate::source_utils::{BracketDiagnostic, find_bracket_errors};
+
+const INDENT: &str = "    ";
+const MAX_LINE_WIDTH: usize = 80;
+const MAX_INLINE_LOOP_WIDTH: usize = 40;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum Token {
+    Run(char, usize),
+    Open,
+    Close,
+    // a comment on its own line, or one that follows code on the same line
+    Comment(String),
+    TrailingComment(String),
+    Blank,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum Item {
+    Run(char, usize),
+    Loop(Vec<Self>),
+    Comment(String),
+    TrailingComment(String),
+    Blank,
+}
+
+pub fn format_source(program_code: &str) -> Result<String, Vec<BracketDiagnostic>> {
+    // the layout is based on the loops, so we can't do anything with broken brackets
+    let bracket_errors = find_bracket_errors(program_code);
+
+    if !bracket_errors.is_empty() {
+        return Err(bracket_errors);
+    }
+
+    let tokens = tokenize_layout(program_code);
+    let items = build_items(&mut tokens.into_iter());
+
+    let mut output = vec![];
+    render_block(&items, 0, &mut output);
+
+    // blank lines at the edges of the file are never useful
+    while output.last().is_some_and(String::is_empty) {
+        output.pop();
+    }
+
+    let mut formatted = output.join("\n");
+    formatted.push('\n');
+
+    Ok(formatted)
+}
+
+fn tokenize_layout(program_code: &str) -> Vec<Token> {
+    // splits the source into runs of the same command, brackets and the comments between them,
+    // the line breaks in the source only matter for where comments are
+    let mut tokens = vec![];
+    let mut region = String::new();
+    let mut seen_code = false;
+
+    for c in program_code.chars() {
+        match c {
+            '+' | '-' | '<' | '>' | '.' | ',' | '[' | ']' => {
+                tokenize_region(&region, seen_code, &mut tokens);
+                region.clear();
+                seen_code = true;
+
+                match (c, tokens.last_mut()) {
+                    ('[', _) => tokens.push(Token::Open),
+                    (']', _) => tokens.push(Token::Close),
+                    (_, Some(Token::Run(run_char, count))) if *run_char == c => *count += 1,
+                    _ => tokens.push(Token::Run(c, 1)),
+                }
+            }
+            _ => region.push(c),
+        }
+    }
+
+    tokenize_region(&region, seen_code, &mut tokens);
+
+    tokens
+}
+
+fn tokenize_region(region: &str, follows_code: bool, tokens: &mut Vec<Token>) {
+    // a region is everything between two commands, the first line of it continues the line the
+    // last command was on, and a line in the middle with nothing on it is a blank line
+    let lines = region.split('\n').collect::<Vec<_>>();
+    let last_line = lines.len() - 1;
+
+    for (line_number, line) in lines.iter().enumerate() {
+        let text = line.trim();
+
+        if !text.is_empty() {
+            if line_number == 0 && follows_code {
+                tokens.push(Token::TrailingComment(text.to_string()));
+            } else {
+                tokens.push(Token::Comment(text.to_string()));
+            }
+        } else if line_number != 0 && line_number != last_line && tokens.last() != Some(&Token::Blank)
+        {
+            tokens.push(Token::Blank);
+        }
+    }
+}
+
+fn build_items(tokens: &mut impl Iterator<Item = Token>) -> Vec<Item> {
+    // nest the tokens by loop, the brackets are known to match
+    let mut items = vec![];
+
+    while let Some(token) = tokens.next() {
+        let item = match token {
+            Token::Run(c, count) => Item::Run(c, count),
+            Token::Open => Item::Loop(build_items(tokens)),
+            Token::Close => break,
+            Token::Comment(text) => Item::Comment(text),
+            Token::TrailingComment(text) => Item::TrailingComment(text),
+            Token::Blank => Item::Blank,
+        };
+
+        items.push(item);
+    }
+
+    items
+}
+
+fn inline_loop_text(body: &[Item]) -> Option<String> {
+    // loops with nothing but commands in them are short enough to keep on one line
+    let runs = body
+        .iter()
+        .map(|x| match x {
+            Item::Run(c, count) => Some(c.to_string().repeat(*count)),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let text = format!("[{}]", runs.join(" "));
+
+    if text.len() <= MAX_INLINE_LOOP_WIDTH {
+        Some(text)
+    } else {
+        None
+    }
+}
+
+fn render_block(items: &[Item], depth: usize, output: &mut Vec<String>) {
+    let indent = INDENT.repeat(depth);
+
+    let mut line = String::new();
+    // the closing bracket of a loop is on a line of its own, only a comment can follow it
+    let mut line_is_closed = false;
+
+    let flush = |line: &mut String, line_is_closed: &mut bool, output: &mut Vec<String>| {
+        if !line.is_empty() {
+            output.push(format!("{}{}", indent, line));
+            line.clear();
+        }
+        *line_is_closed = false;
+    };
+
+    for item in items {
+        let inline_text = match item {
+            Item::Run(c, count) => Some(c.to_string().repeat(*count)),
+            Item::Loop(body) => inline_loop_text(body),
+            _ => None,
+        };
+
+        if let Some(text) = inline_text {
+            let width = indent.len() + line.len() + 1 + text.len();
+            if line_is_closed || (!line.is_empty() && width > MAX_LINE_WIDTH) {
+                flush(&mut line, &mut line_is_closed, output);
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&text);
+            continue;
+        }
+
+        match item {
+            Item::Loop(body) => {
+                flush(&mut line, &mut line_is_closed, output);
+
+                // a comment straight after the opening bracket stays next to it
+                let (first_comment, body) = match body.split_first() {
+                    Some((Item::TrailingComment(text), rest)) => (Some(text), rest),
+                    _ => (None, body.as_slice()),
+                };
+
+                match first_comment {
+                    Some(text) => output.push(format!("{}[ {}", indent, text)),
+                    None => output.push(format!("{}[", indent)),
+                }
+
+                render_block(body, depth + 1, output);
+
+                line.push(']');
+                line_is_closed = true;
+            }
+            Item::TrailingComment(text) => {
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.push_str(text);
+                flush(&mut line, &mut line_is_closed, output);
+            }
+            Item::Comment(text) => {
+                flush(&mut line, &mut line_is_closed, output);
+                output.push(format!("{}{}", indent, text));
+            }
+            Item::Blank => {
+                flush(&mut line, &mut line_is_closed, output);
+                if output.last().is_some_and(|x| !x.is_empty()) {
+                    output.push(String::new());
+                }
+            }
+            Item::Run(..) => {}
+        }
+    }
+
+    flush(&mut line, &mut line_is_closed, output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source_utils::remove_no_coding_symbols;
+
+    const EXAMPLES: [&str; 5] = [
+        include_str!("../examples/hello_world.bf"),
+        include_str!("../examples/factor.bf"),
+        include_str!("../examples/mandelbrot.bf"),
+        include_str!("../examples/99.bf"),
+        include_str!("../examples/divide.bf"),
+    ];
+
+    fn comment_text(source: &str) -> String {
+        // everything that isn't code or space, in order
+        source
+            .chars()
+            .filter(|c| !"+-<>.,[]".contains(*c) && !c.is_whitespace())
+            .collect()
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        for source in EXAMPLES {
+            let formatted = format_source(source).unwrap();
+            assert_eq!(format_source(&formatted).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn keeps_the_code_and_the_comments() {
+        for source in EXAMPLES {
+            let formatted = format_source(source).unwrap();
+            assert_eq!(
+                remove_no_coding_symbols(formatted.clone()),
+                remove_no_coding_symbols(source.to_string())
+            );
+            assert_eq!(comment_text(&formatted), comment_text(source));
+        }
+    }
+
+    #[test]
+    fn lays_out_comments_by_the_code_they_are_next_to() {
+        let source = "set up ++ with a note\n\n\nthe loop [ counts down\n- > + < ] done";
+        assert_eq!(
+            format_source(source).unwrap(),
+            "set up\n++ with a note\n\nthe loop\n[ counts down\n    - > + <\n] done\n"
+        );
+    }
+
+    #[test]
+    fn short_loops_stay_inline() {
+        assert_eq!(format_source("++[->+<]>.").unwrap(), "++ [- > + <] > .\n");
+        assert_eq!(format_source("[[-]>]").unwrap(), "[\n    [-] >\n]\n");
+
+        // loops longer than the inline width are split over lines
+        let long = format!("[{}]", "+>".repeat(21));
+        let formatted = format_source(&long).unwrap();
+        assert!(formatted.starts_with("[\n    + > + >"));
+        assert!(formatted.ends_with("\n]\n"));
+    }
+
+    #[test]
+    fn rejects_unbalanced_brackets() {
+        assert!(format_source("+[->+<").is_err());
+        assert_eq!(format_source("+]-").unwrap_err().len(), 1);
+    }
+}
@@ -0,0 +1,599 @@
+use crate::dataflow::CellState;
+use crate::ir::{Node, body_opcodes, build_tree, lower_tree};
+use crate::opcodes::{Opcode, SpannedOpcode, reset_bracket, wrap_cell_value};
+
+// what a loop body does, with the cells relative to where the body starts
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Step<'a> {
+    Change { cell: i32, arg: i32 },
+    Set { cell: i32, arg: i32 },
+    // the cell it goes through is added back to the one copied from
+    Copy { from: i32, to: i32, via: i32 },
+    And { cell: i32, other: i32, target: i32 },
+    CountDown { cell: i32, x: i32, y: i32 },
+    Loop { cell: i32, body: &'a [Node] },
+}
+
+fn match_copy(ops: &[Opcode]) -> Option<(i32, i32)> {
+    // a non destructive copy like [->+>+<<]>>[-<<+>>], which the loops pass leaves as two MULTI,
+    // a clear, a move to the temporary cell and a MULTI and clear moving it back
+    let [
+        Opcode::MULTI { arg1: x, arg2: 1 },
+        Opcode::MULTI { arg1: y, arg2: 1 },
+        Opcode::SETTO { arg: 0, offset: 0 },
+        Opcode::MOVE { arg: temp },
+        Opcode::MULTI { arg1: back, arg2: 1 },
+        Opcode::SETTO { arg: 0, offset: 0 },
+    ] = *ops
+    else {
+        return None;
+    };
+
+    if back != -temp || x == y {
+        return None;
+    }
+
+    // the temporary cell can be either of the two the first loop adds to
+    match temp {
+        _ if temp == y => Some((x, y)),
+        _ if temp == x => Some((y, x)),
+        _ => None,
+    }
+}
+
+fn gen_copy_ops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    let mut output = Vec::with_capacity(opcodes.len());
+    let mut pos = 0;
+
+    while pos < opcodes.len() {
+        let window = &opcodes[pos..opcodes.len().min(pos + 6)];
+        let ops = window.iter().map(|x| x.op).collect::<Vec<_>>();
+
+        if let Some((target, temp)) = match_copy(&ops) {
+            let span = window[0].span.merge(window[5].span);
+            output.push(SpannedOpcode::new(
+                Opcode::COPY {
+                    arg1: target,
+                    arg2: temp,
+                },
+                span,
+            ));
+            output.push(SpannedOpcode::new(Opcode::MOVE { arg: temp }, span));
+            pos += 6;
+        } else {
+            output.push(opcodes[pos]);
+            pos += 1;
+        }
+    }
+
+    output
+}
+
+fn cell_changes(ops: &[Opcode]) -> Option<Vec<(i32, i32)>> {
+    // the cells a run of MOVE and CHANGE adds to, relative to where it starts and ends
+    let mut offset = 0;
+    let mut changes = vec![];
+
+    for &op in ops {
+        match op {
+            Opcode::MOVE { arg } => offset += arg,
+            Opcode::CHANGE { arg, offset: x } => changes.push((offset + x, arg)),
+            _ => return None,
+        }
+    }
+
+    (offset == 0).then_some(changes)
+}
+
+fn match_carry_step(body: &[Node]) -> Option<(i32, i32, i32, i32)> {
+    // one loop of a chain like [->+<[->+<[->-->>+<<<[->+<]]]], which counts a cell down into the
+    // next one and carries once it reaches the length of the chain, gives the cell counted into,
+    // the cell carried into, how deep the chain is from here and what the innermost loop takes
+    // off the cell counted into
+    if let [ops @ .., Node::Loop { body: inner, .. }] = body {
+        let changes = cell_changes(&body_opcodes(ops)?)?;
+
+        let target = match *changes {
+            [(0, -1), (x, 1)] | [(x, 1), (0, -1)] if x != 0 => x,
+            _ => return None,
+        };
+
+        let (inner_target, carry, depth, taken) = match_carry_step(inner)?;
+
+        return (inner_target == target).then_some((target, carry, depth + 1, taken));
+    }
+
+    // the innermost loop takes one more off the counter, carries one and moves what is left of
+    // the counter into the cell counted into
+    let ops = body_opcodes(body)?;
+
+    let [
+        ref changes @ ..,
+        Opcode::MULTI { arg1: target, arg2: 1 },
+        Opcode::SETTO { arg: 0, offset: 0 },
+    ] = *ops
+    else {
+        return None;
+    };
+
+    let mut counter = None;
+    let mut carry = None;
+    let mut taken = 0;
+
+    for (offset, arg) in cell_changes(changes)? {
+        match (offset, arg) {
+            (0, -1) if counter.is_none() => counter = Some(()),
+            (x, _) if x == target && taken == 0 => taken = -arg,
+            (x, 1) if x != 0 && carry.is_none() => carry = Some(x),
+            _ => return None,
+        }
+    }
+
+    counter?;
+
+    Some((target, carry?, 1, taken))
+}
+
+fn match_carry(body: &[Node]) -> Option<Opcode> {
+    let (target, carry, depth, taken) = match_carry_step(body)?;
+
+    // every loop but the innermost adds one to the cell counted into, which the innermost loop
+    // has to take back off for this to carry at the length of the chain
+    if target == carry || depth > 255 || wrap_cell_value(taken) != wrap_cell_value(depth - 1) {
+        return None;
+    }
+
+    Some(Opcode::CARRY {
+        arg1: target,
+        arg2: carry,
+        arg3: depth,
+    })
+}
+
+fn body_steps(body: &[Node]) -> Option<Vec<Step<'_>>> {
+    // the steps of a loop body that ends on the cell it started on
+    let mut offset = 0;
+    let mut steps = vec![];
+
+    for node in body {
+        let step = match node {
+            Node::Op(op) => match op.op {
+                Opcode::MOVE { arg } => {
+                    offset += arg;
+                    continue;
+                }
+                Opcode::CHANGE { arg, offset: x } => Step::Change {
+                    cell: offset + x,
+                    arg,
+                },
+                Opcode::SETTO { arg, offset: x } => Step::Set {
+                    cell: offset + x,
+                    arg,
+                },
+                Opcode::COPY { arg1, arg2 } => Step::Copy {
+                    from: offset,
+                    to: offset + arg1,
+                    via: offset + arg2,
+                },
+                Opcode::AND { arg1, arg2 } => Step::And {
+                    cell: offset,
+                    other: offset + arg1,
+                    target: offset + arg2,
+                },
+                Opcode::COUNTDOWN { arg1, arg2 } => Step::CountDown {
+                    cell: offset,
+                    x: offset + arg1,
+                    y: offset + arg2,
+                },
+                _ => return None,
+            },
+            Node::Loop { body, .. } | Node::If { body, .. } => Step::Loop { cell: offset, body },
+        };
+
+        steps.push(step);
+    }
+
+    (offset == 0).then_some(steps)
+}
+
+pub fn all_different(cells: &[i32]) -> bool {
+    cells
+        .iter()
+        .enumerate()
+        .all(|(pos, x)| !cells[pos + 1..].contains(x))
+}
+
+fn same_cells([x, y]: [i32; 2], other: [i32; 2]) -> bool {
+    // the two cells in either order
+    other == [x, y] || other == [y, x]
+}
+
+fn match_and(body: &[Node]) -> Option<Opcode> {
+    // a comparison like [>>[-]<[>[-]+<[-]]<[-]], which sets the cell at 2 to whether the cell at
+    // 1 is non zero, if the cell under the data pointer is too
+    let steps = body_steps(body)?;
+
+    let [
+        Step::Set {
+            cell: target,
+            arg: 0,
+        },
+        Step::Loop { cell: other, body },
+        Step::Set { cell: 0, arg: 0 },
+    ] = *steps
+    else {
+        return None;
+    };
+
+    let inner = body_steps(body)?;
+
+    let [Step::Set { cell, arg: 1 }, Step::Set { cell: 0, arg: 0 }] = *inner else {
+        return None;
+    };
+
+    (cell == target - other && all_different(&[0, other, target])).then_some(Opcode::AND {
+        arg1: other,
+        arg2: target,
+    })
+}
+
+fn match_flag(steps: &[Step]) -> Option<([i32; 2], [i32; 2], i32)> {
+    // clears a flag, copies two cells into two scratch cells through it and ANDs them into it,
+    // which leaves the flag telling whether both cells are non zero and the scratch cells
+    // empty again, gives the two cells, the two scratch cells and the flag
+    let [
+        Step::Set { cell: flag, arg: 0 },
+        Step::Copy {
+            from: first,
+            to: x,
+            via: first_via,
+        },
+        Step::Copy {
+            from: second,
+            to: y,
+            via: second_via,
+        },
+        ref rest @ ..,
+    ] = *steps
+    else {
+        return None;
+    };
+
+    // the flag can be cleared again after the copies
+    let rest = match rest {
+        [Step::Set { cell, arg: 0 }, rest @ ..] if *cell == flag => rest,
+        _ => rest,
+    };
+
+    // the AND only clears the other cell if the one it is on isn't zero
+    let [
+        Step::And {
+            cell,
+            other,
+            target,
+        },
+        Step::Set {
+            cell: cleared,
+            arg: 0,
+        },
+    ] = *rest
+    else {
+        return None;
+    };
+
+    let is_flag = first_via == flag && second_via == flag && target == flag;
+
+    (is_flag
+        && same_cells([x, y], [cell, other])
+        && cleared == other
+        && all_different(&[first, second, x, y, flag]))
+    .then_some(([first, second], [x, y], flag))
+}
+
+fn match_count_down(body: &[Node], state: &CellState) -> Option<Opcode> {
+    // a loop that takes one off two cells and then clears itself unless both of them are still
+    // non zero, which needs the scratch cells of the comparison to start out empty
+    let steps = body_steps(body)?;
+
+    let [
+        Step::Change { cell: x, arg: -1 },
+        Step::Change { cell: y, arg: -1 },
+        ref rest @ ..,
+    ] = *steps
+    else {
+        return None;
+    };
+
+    let (cells, scratch, flag) = match_flag(rest)?;
+    let is_zero = scratch.iter().all(|&x| state.value(x) == Some(0));
+
+    (flag == 0 && same_cells([x, y], cells) && is_zero && all_different(&[0, x, y]))
+        .then_some(Opcode::COUNTDOWN { arg1: x, arg2: y })
+}
+
+fn match_divmod(body: &[Node], state: &CellState) -> Option<Opcode> {
+    // division by taking the divisor off until nothing is left, e.g. in divide.bf every time
+    // around adds one to the quotient, copies the divisor into the cell for what is left and
+    // counts that down together with the cell under the data pointer
+    let steps = body_steps(body)?;
+
+    let [
+        Step::Change {
+            cell: quotient,
+            arg: 1,
+        },
+        Step::Copy {
+            from: divisor,
+            to: left,
+            via: temp,
+        },
+        ref rest @ ..,
+        Step::CountDown { cell: flag, x, y },
+    ] = *steps
+    else {
+        return None;
+    };
+
+    let (cells, scratch, compared) = match_flag(rest)?;
+    let counted = same_cells([0, left], cells) && same_cells([0, left], [x, y]);
+
+    // the scratch cells and the one the copy goes through have to start out empty, they are
+    // left empty every time around
+    let is_zero = [scratch[0], scratch[1], temp]
+        .iter()
+        .all(|&x| state.value(x) == Some(0));
+    let cells = [0, quotient, divisor, left, scratch[0], scratch[1], flag];
+
+    (compared == flag
+        && counted
+        && is_zero
+        && all_different(&cells)
+        && ![0, quotient, divisor, left].contains(&temp))
+    .then_some(Opcode::DIVMOD {
+        arg1: quotient,
+        arg2: divisor,
+        arg3: left,
+    })
+}
+
+fn rewrite_block(block: Vec<Node>, state: &mut CellState) -> Vec<Node> {
+    // works from the innermost loops outwards, following what is known about the cells as the
+    // comparisons need cells known to be empty to work in
+    let mut output = Vec::with_capacity(block.len());
+
+    for node in block {
+        match node {
+            Node::Op(op) => {
+                state.apply(op.op);
+                output.push(Node::Op(op));
+            }
+            Node::Loop { body, open, close } => {
+                let mut body_state = state.loop_body_state(&body);
+                let body = rewrite_block(body, &mut body_state);
+
+                let idiom = match_carry(&body)
+                    .or_else(|| match_and(&body))
+                    .or_else(|| match_count_down(&body, state))
+                    .or_else(|| match_divmod(&body, state));
+
+                if let Some(op) = idiom {
+                    state.apply(op);
+                    output.push(Node::Op(SpannedOpcode::new(op, open.merge(close))));
+                } else {
+                    state.apply_loop(&body);
+                    output.push(Node::Loop { body, open, close });
+                }
+            }
+            Node::If { body, open, close } => {
+                let mut body_state = state.loop_body_state(&body);
+                state.apply_loop(&body);
+
+                let body = rewrite_block(body, &mut body_state);
+                output.push(Node::If { body, open, close });
+            }
+        }
+    }
+
+    output
+}
+
+pub fn gen_idiom_ops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // well known BF idioms that take several loops to write get an opcode of their own, the
+    // copies come first as the comparisons are made out of them
+    let tree = build_tree(&gen_copy_ops(opcodes));
+    let tree = rewrite_block(tree, &mut CellState::new());
+
+    reset_bracket(lower_tree(&tree))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::strip_spans;
+    use crate::partial_eval::run_to_end;
+    use crate::passes::{Pass, PassManager};
+
+    fn compile(source: &str) -> Vec<Opcode> {
+        // the idioms are matched on the MULTI and SETTO the loops pass leaves, and on the [-]+
+        // the pointless pass turns into a single SETTO, checked against what the program printed
+        // before
+        let passes = [
+            Pass::CompressFoldable,
+            Pass::RemovePointless,
+            Pass::LoopTransformations,
+            Pass::GenIdioms,
+        ];
+        let manager = PassManager::from_flags(0, &passes, &[]);
+        let opcodes = manager.compile(source.to_string());
+        let expected = run_to_end(&PassManager::new().compile(source.to_string()), 10_000_000);
+
+        assert!(expected.is_some(), "{:?} doesn't finish", source);
+        assert_eq!(run_to_end(&opcodes, 10_000_000), expected, "{:?}", source);
+        strip_spans(&opcodes)
+    }
+
+    fn count_loops(opcodes: &[Opcode]) -> usize {
+        opcodes
+            .iter()
+            .filter(|x| matches!(x, Opcode::JUMPIFZERO { .. }))
+            .count()
+    }
+
+    #[test]
+    fn copies_become_a_copy() {
+        let opcodes = compile("+++++[->+>+<<]>>[-<<+>>]<<.>.>.");
+        assert!(opcodes.contains(&Opcode::COPY { arg1: 1, arg2: 2 }));
+        assert_eq!(count_loops(&opcodes), 0);
+
+        // the temporary cell can be the nearer one
+        let opcodes = compile("+++++[->>+<+<]>[-<+>]<.>.>.");
+        assert!(opcodes.contains(&Opcode::COPY { arg1: 2, arg2: 1 }));
+    }
+
+    #[test]
+    fn copies_that_do_not_come_back_are_kept() {
+        // the second loop moves the temporary cell somewhere else
+        let opcodes = compile("+++++[->+>+<<]>>[-<+>]<<.>.>.");
+        assert!(!opcodes.iter().any(|x| matches!(x, Opcode::COPY { .. })));
+    }
+
+    #[test]
+    fn chains_of_loops_become_a_carry() {
+        let chain = "[->+<[->+<[->-->>+<<<[->+<]]]]>.>>.";
+
+        // below, at and above the length of the chain
+        for count in [0, 2, 3, 5, 7, 255] {
+            let opcodes = compile(&("+".repeat(count) + chain));
+            assert_eq!(
+                opcodes[usize::from(count > 0)],
+                Opcode::CARRY {
+                    arg1: 1,
+                    arg2: 3,
+                    arg3: 3
+                },
+                "{}",
+                count
+            );
+            assert_eq!(count_loops(&opcodes), 0);
+        }
+
+        // a chain of one loop carries at one
+        let opcodes = compile("+++[->>+<<[->+<]]>.>.");
+        assert!(opcodes.contains(&Opcode::CARRY {
+            arg1: 1,
+            arg2: 2,
+            arg3: 1
+        }));
+    }
+
+    #[test]
+    fn chains_that_do_not_carry_are_kept() {
+        for source in [
+            // the innermost loop takes the wrong amount back off
+            "+++++[->+<[->+<[->->>+<<<[->+<]]]]>.>>.",
+            // and here it carries into the cell it counts down
+            "+++++[->+<[->+<[->--<+[->+<]]]]>.",
+            // the loops count into different cells
+            "+++++[->+<[->>+<<[->-->>+<<<[->+<]]]]>.>.>.",
+        ] {
+            // a shorter chain inside the loop can still be a CARRY, but the loop itself is kept
+            let opcodes = compile(source);
+            assert!(count_loops(&opcodes) > 0, "{}", source);
+        }
+    }
+
+    // the loops in divide.bf that compare two cells and divide one by the other
+    const AND: &str = "[>>[-]<[>[-]+<[-]]<[-]]";
+    const COUNT_DOWN: &str = "[<<<<<<->>>->>>[-]<<<<<<[>>>>+>>+<<<<<<-]>>>>>>[<<<<<<+>>>>>>-]<<<\
+        [>>+>+<<<-]>>>[<<<+>>>-][-]<<[>>[-]<[>[-]+<[-]]<[-]]>[-]>]";
+    const DIVIDE: &str = "[>>+<[>>+>+<<<-]>>>[<<<+>>>-]>>[-]<<<<<<[>>>>+>>+<<<<<<-]>>>>>>\
+        [<<<<<<+>>>>>>-]<<<[>>+>+<<<-]>>>[<<<+>>>-][-]<<[>>[-]<[>[-]+<[-]]<[-]]>[-]>\
+        [<<<<<<->>>->>>[-]<<<<<<[>>>>+>>+<<<<<<-]>>>>>>[<<<<<<+>>>>>>-]<<<[>>+>+<<<-]>>>\
+        [<<<+>>>-][-]<<[>>[-]<[>[-]+<[-]]<[-]]>[-]>]<<<<<<]";
+
+    #[test]
+    fn comparisons_become_an_and() {
+        for (flag, other) in [(0, 0), (0, 3), (2, 0), (2, 3)] {
+            let setup = format!("{}>{}>+++<<", "+".repeat(flag), "+".repeat(other));
+            let opcodes = compile(&(setup + AND + ".>.>."));
+            assert!(opcodes.contains(&Opcode::AND { arg1: 1, arg2: 2 }));
+            assert_eq!(count_loops(&opcodes), 0);
+        }
+    }
+
+    #[test]
+    fn count_downs_become_a_countdown() {
+        for (x, y) in [(1, 1), (5, 3), (3, 5), (0, 4), (200, 7)] {
+            let setup = format!("{}>>>{}>>>+", "+".repeat(x), "+".repeat(y));
+            let opcodes = compile(&(setup + COUNT_DOWN + "<<<<<<.>>>.>.>.>."));
+            assert!(opcodes.contains(&Opcode::COUNTDOWN { arg1: -6, arg2: -3 }));
+            assert_eq!(count_loops(&opcodes), 0);
+        }
+    }
+
+    #[test]
+    fn divisions_become_a_divmod() {
+        // dividing with nothing left over, with something left over, by more than the value and
+        // by one
+        for (value, divisor) in [
+            (20, 5),
+            (20, 3),
+            (7, 7),
+            (3, 200),
+            (0, 4),
+            (255, 1),
+            (255, 2),
+        ] {
+            let setup = format!("{}>{}<", "+".repeat(value), "+".repeat(divisor));
+            let opcodes = compile(&(setup + DIVIDE + ".>.>.>.>.>.>."));
+            assert!(opcodes.contains(&Opcode::DIVMOD {
+                arg1: 2,
+                arg2: 1,
+                arg3: 3
+            }));
+            assert_eq!(count_loops(&opcodes), 0);
+        }
+    }
+
+    #[test]
+    fn comparisons_need_empty_scratch_cells() {
+        // the cell the count down copies into already holds something
+        let opcodes = compile(&(String::from("+++>>>++>+>>+") + COUNT_DOWN + "<<<<<<.>>>.>.>."));
+        assert!(
+            !opcodes
+                .iter()
+                .any(|x| matches!(x, Opcode::COUNTDOWN { .. }))
+        );
+
+        // and here the one the division copies the divisor through, but the loops inside it can
+        // still be replaced
+        let opcodes = compile(&(String::from("++++++>++>>>+<<<<") + DIVIDE + ".>.>.>.>."));
+        assert!(!opcodes.iter().any(|x| matches!(x, Opcode::DIVMOD { .. })));
+        assert!(opcodes.iter().any(|x| matches!(x, Opcode::AND { .. })));
+    }
+
+    #[test]
+    fn divide_keeps_the_output() {
+        // divide.bf doesn't print anything, so the cells it leaves are printed instead
+        let source = include_str!("../examples/divide.bf");
+
+        for (value, divisor) in [(20, 5), (20, 3), (5, 20), (0, 1), (255, 16)] {
+            let source = source
+                .replacen("[-]+++++>", &format!("[-]{}>", "+".repeat(divisor)), 1)
+                .replacen(
+                    &format!("[-]{}>", "+".repeat(20)),
+                    &format!("[-]{}>", "+".repeat(value)),
+                    1,
+                );
+            let opcodes = compile(&(source + "<<<<.>.>.>.>.>.>."));
+            assert!(opcodes.iter().any(|x| matches!(x, Opcode::DIVMOD { .. })));
+        }
+    }
+
+    #[test]
+    fn keeps_the_output() {
+        compile(include_str!("../examples/hello_world.bf"));
+        compile(include_str!("../examples/99.bf"));
+        compile(include_str!("../examples/triangle.bf"));
+    }
+}
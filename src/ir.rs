@@ -0,0 +1,236 @@
+use crate::opcodes::{Opcode, SpannedOpcode};
+use crate::span::Span;
+
+// a program is a block of nodes, where every loop holds the block that makes up its body,
+// this way passes can work on whole loops (nested or not) without fixing up jump targets
+pub type Block = Vec<Node>;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Node {
+    // any opcode other than the two jumps
+    Op(SpannedOpcode),
+    // the spans are the ones of the opening and closing bracket
+    Loop { body: Block, open: Span, close: Span },
+    // a loop that runs at most once, as its body always leaves the cell it tests at zero
+    If { body: Block, open: Span, close: Span },
+}
+
+impl Node {
+    #[must_use]
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Op(op) => op.span,
+            Self::Loop { open, close, .. } | Self::If { open, close, .. } => open.merge(*close),
+        }
+    }
+}
+
+pub fn build_tree(opcodes: &[SpannedOpcode]) -> Block {
+    // the jump targets in the flat form are ignored, only the nesting of the brackets matters
+    let mut blocks: Vec<(Block, Span)> = vec![];
+    let mut current = vec![];
+
+    for &opcode in opcodes {
+        match opcode.op {
+            Opcode::JUMPIFZERO { .. } => {
+                blocks.push((current, opcode.span));
+                current = vec![];
+            }
+            Opcode::JUMPIFNZERO { .. } => {
+                let (mut outer, open) = blocks
+                    .pop()
+                    .expect("Expected every closing bracket to have an opening bracket");
+                outer.push(Node::Loop {
+                    body: current,
+                    open,
+                    close: opcode.span,
+                });
+                current = outer;
+            }
+            Opcode::ENDIF { .. } => {
+                let (mut outer, open) = blocks
+                    .pop()
+                    .expect("Expected every closing bracket to have an opening bracket");
+                outer.push(Node::If {
+                    body: current,
+                    open,
+                    close: opcode.span,
+                });
+                current = outer;
+            }
+            _ => current.push(Node::Op(opcode)),
+        }
+    }
+
+    assert!(
+        blocks.is_empty(),
+        "Expected every opening bracket to have a closing bracket"
+    );
+
+    current
+}
+
+pub fn lower_tree(block: &[Node]) -> Vec<SpannedOpcode> {
+    // turn the tree back into the flat opcodes the VM runs, with the jump targets filled in
+    let mut output = vec![];
+    lower_block(block, &mut output);
+    output
+}
+
+fn lower_block(block: &[Node], output: &mut Vec<SpannedOpcode>) {
+    for node in block {
+        match node {
+            Node::Op(op) => output.push(*op),
+            Node::Loop { body, open, close } => {
+                let open_instr = output.len();
+                output.push(SpannedOpcode::new(Opcode::JUMPIFZERO { arg: 0 }, *open));
+
+                lower_block(body, output);
+
+                let close_instr = output.len();
+                output.push(SpannedOpcode::new(
+                    Opcode::JUMPIFNZERO { arg: open_instr },
+                    *close,
+                ));
+                output[open_instr].op = Opcode::JUMPIFZERO { arg: close_instr };
+            }
+            Node::If { body, open, close } => {
+                let open_instr = output.len();
+                output.push(SpannedOpcode::new(Opcode::JUMPIFZERO { arg: 0 }, *open));
+
+                lower_block(body, output);
+
+                let close_instr = output.len();
+                output.push(SpannedOpcode::new(Opcode::ENDIF { arg: open_instr }, *close));
+                output[open_instr].op = Opcode::JUMPIFZERO { arg: close_instr };
+            }
+        }
+    }
+}
+
+pub fn body_opcodes(body: &[Node]) -> Option<Vec<Opcode>> {
+    // the opcodes in a loop body, if it doesn't have any loops inside of it
+    body.iter()
+        .map(|x| match x {
+            Node::Op(op) => Some(op.op),
+            Node::Loop { .. } | Node::If { .. } => None,
+        })
+        .collect()
+}
+
+pub fn rewrite_loops<F>(block: Block, rewrite: &mut F) -> Block
+where
+    F: FnMut(&[Node], Span) -> Option<Block>,
+{
+    // works from the innermost loops outwards, the rewrite gets the (already rewritten) body of
+    // each loop with the span of the whole loop, and can give back nodes to stand in for it
+    let mut output = Vec::with_capacity(block.len());
+
+    for node in block {
+        match node {
+            Node::Loop { body, open, close } => {
+                let body = rewrite_loops(body, rewrite);
+
+                match rewrite(&body, open.merge(close)) {
+                    Some(replacement) => output.extend(replacement),
+                    None => output.push(Node::Loop { body, open, close }),
+                }
+            }
+            Node::If { body, open, close } => {
+                let body = rewrite_loops(body, rewrite);
+                output.push(Node::If { body, open, close });
+            }
+            op => output.push(op),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::tokenize_instructions;
+    use crate::passes::{Pass, PassManager};
+
+    fn op(op: Opcode, start: usize) -> SpannedOpcode {
+        SpannedOpcode::new(op, Span::new(start, start + 1))
+    }
+
+    #[test]
+    fn lowering_the_tree_gives_back_the_opcodes() {
+        // a loop holding a loop that holds an if, every opcode with a span of its own
+        let opcodes = vec![
+            op(Opcode::CHANGE { arg: 1, offset: 0 }, 0),
+            op(Opcode::JUMPIFZERO { arg: 11 }, 1),
+            op(Opcode::MOVE { arg: 1 }, 2),
+            op(Opcode::SETTO { arg: 0, offset: 0 }, 3),
+            op(Opcode::MOVE { arg: -1 }, 4),
+            op(Opcode::JUMPIFZERO { arg: 10 }, 5),
+            op(Opcode::CHANGE { arg: -1, offset: 0 }, 6),
+            op(Opcode::CHANGE { arg: 1, offset: 1 }, 7),
+            op(Opcode::JUMPIFZERO { arg: 9 }, 8),
+            op(Opcode::ENDIF { arg: 8 }, 9),
+            op(Opcode::JUMPIFNZERO { arg: 5 }, 10),
+            op(Opcode::JUMPIFNZERO { arg: 1 }, 11),
+            op(Opcode::MOVE { arg: 1 }, 12),
+        ];
+        let tree = build_tree(&opcodes);
+
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree[1].span(), Span::new(1, 12));
+        assert!(matches!(
+            &tree[1],
+            Node::Loop { body, .. } if matches!(&body[3], Node::Loop { body, .. }
+                if matches!(body[2], Node::If { .. }))
+        ));
+        assert_eq!(lower_tree(&tree), opcodes);
+    }
+
+    #[test]
+    fn compiled_programs_round_trip() {
+        let sources = [
+            include_str!("../examples/mandelbrot.bf"),
+            include_str!("../examples/hanoi.bf"),
+            include_str!("../examples/factor.bf"),
+        ];
+
+        for source in sources {
+            let unoptimized = tokenize_instructions(source.to_string());
+            assert_eq!(lower_tree(&build_tree(&unoptimized)), unoptimized);
+
+            let with_ifs = PassManager::from_flags(2, &[Pass::GenIfs], &[]);
+            let optimized = with_ifs.compile(source.to_string());
+            assert_eq!(lower_tree(&build_tree(&optimized)), optimized);
+        }
+    }
+
+    #[test]
+    fn loops_are_rewritten_from_the_inside_out() {
+        // +[[<]>] with the inner loop rewritten to a SCANBY, which the outer loop then sees
+        let opcodes = tokenize_instructions(String::from("+[[<]>]"));
+        let mut seen = vec![];
+
+        let tree = rewrite_loops(build_tree(&opcodes), &mut |body, span| {
+            seen.push(span);
+            match body {
+                [Node::Op(x)] if x.op == (Opcode::MOVE { arg: -1 }) => Some(vec![Node::Op(
+                    SpannedOpcode::new(Opcode::SCANBY { arg: -1 }, span),
+                )]),
+                _ => None,
+            }
+        });
+
+        assert_eq!(seen, [Span::new(2, 5), Span::new(1, 7)]);
+        assert_eq!(
+            lower_tree(&tree).iter().map(|x| x.op).collect::<Vec<_>>(),
+            [
+                Opcode::CHANGE { arg: 1, offset: 0 },
+                Opcode::JUMPIFZERO { arg: 4 },
+                Opcode::SCANBY { arg: -1 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::JUMPIFNZERO { arg: 1 },
+            ]
+        );
+    }
+}
@@ -1,8 +1,25 @@
 #![warn(clippy::all, clippy::cargo, clippy::nursery)]
 
+pub mod assembly;
 pub mod bf_executor;
+pub mod bytecode;
+pub mod dataflow;
+pub mod decompiler;
+pub mod explain;
+pub mod formatter;
+pub mod idioms;
+pub mod ir;
+pub mod lint;
+pub mod minifier;
 pub mod opcode_interpreter;
 pub mod opcodes;
+pub mod packed;
+pub mod partial_eval;
+pub mod passes;
+pub mod polynomial;
+pub mod scan;
 pub mod simple_interpreter;
 pub mod source_utils;
+pub mod span;
 pub mod state;
+pub mod validation;
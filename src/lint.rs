@@ -0,0 +1,379 @@
+use crate::opcodes::{Opcode, SpannedOpcode, find_dead_loops, tokenize_instructions};
+use crate::source_utils::{find_bracket_errors, render_source_snippet};
+use crate::span::{LineIndex, Span};
+use std::fmt;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+            Self::Info => write!(f, "info"),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum LintCode {
+    UnmatchedBracket,
+    DeadLoop,
+    MovesLeftOfStart,
+    LoopNeverChangesCell,
+    NoOpSequence,
+}
+
+impl LintCode {
+    #[must_use]
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::UnmatchedBracket => "BF000",
+            Self::DeadLoop => "BF001",
+            Self::MovesLeftOfStart => "BF002",
+            Self::LoopNeverChangesCell => "BF003",
+            Self::NoOpSequence => "BF004",
+        }
+    }
+
+    #[must_use]
+    pub const fn severity(self) -> Severity {
+        match self {
+            Self::UnmatchedBracket | Self::MovesLeftOfStart => Severity::Error,
+            Self::LoopNeverChangesCell | Self::NoOpSequence => Severity::Warning,
+            // initial loops are the usual way to write comments, so these are only informative
+            Self::DeadLoop => Severity::Info,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct LintFinding {
+    pub code: LintCode,
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+}
+
+impl LintFinding {
+    const fn new(code: LintCode, span: Span, message: String) -> Self {
+        Self {
+            code,
+            severity: code.severity(),
+            span,
+            message,
+        }
+    }
+
+    #[must_use]
+    pub fn render(&self, line_index: &LineIndex) -> String {
+        // e.g.
+        // warning[BF004]: these changes cancel out and have no effect at line 1, column 4
+        //  1 | +++-+.
+        //    |    ^^
+        let position = line_index.position(self.span.start);
+
+        let mut output = format!(
+            "{}[{}]: {} at line {}, column {}\n",
+            self.severity,
+            self.code.code(),
+            self.message,
+            position.line,
+            position.column
+        );
+        output.push_str(&render_source_snippet(
+            line_index,
+            position,
+            self.span.end - self.span.start,
+        ));
+
+        output
+    }
+}
+
+pub fn lint_source(program_code: &str) -> Vec<LintFinding> {
+    // the rest of the checks need to know where the loops are, so they need matching brackets
+    let bracket_errors = find_bracket_errors(program_code);
+
+    if !bracket_errors.is_empty() {
+        return bracket_errors
+            .iter()
+            .map(|x| {
+                LintFinding::new(
+                    LintCode::UnmatchedBracket,
+                    Span::new(x.offset, x.offset + 1),
+                    String::from("this bracket has no partner"),
+                )
+            })
+            .collect();
+    }
+
+    // work on the unfolded opcodes, so every finding points at exactly the code it is about
+    let ops = tokenize_instructions(program_code.to_string());
+
+    let dead_loops = find_dead_loops(&ops);
+
+    let mut is_dead = vec![false; ops.len()];
+    for &(start, end) in &dead_loops {
+        is_dead[start..=end].fill(true);
+    }
+
+    let mut findings = dead_loops
+        .iter()
+        .map(|&(start, end)| {
+            LintFinding::new(
+                LintCode::DeadLoop,
+                ops[start].span.merge(ops[end].span),
+                String::from("this loop can never run, the cell it tests is always zero here"),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    findings.extend(find_move_left_of_start(&ops, &is_dead));
+    findings.extend(find_loops_never_changing_cell(&ops, &is_dead));
+    findings.extend(find_no_op_sequences(&ops, &is_dead));
+
+    findings.sort_by_key(|x| (x.span.start, x.severity));
+    findings
+}
+
+fn net_movement(ops: &[SpannedOpcode], start: usize, end: usize) -> Option<i32> {
+    // how far the body of a loop moves the data pointer, if every loop inside of it
+    // moves the data pointer nowhere, otherwise we can't know
+    let mut movement = 0;
+    let mut pos = start + 1;
+
+    while pos < end {
+        match ops[pos].op {
+            Opcode::MOVE { arg } => movement += arg,
+            Opcode::JUMPIFZERO { arg } => {
+                if net_movement(ops, pos, arg)? != 0 {
+                    return None;
+                }
+                pos = arg;
+            }
+            _ => {}
+        }
+        pos += 1;
+    }
+
+    Some(movement)
+}
+
+fn find_move_left_of_start(ops: &[SpannedOpcode], is_dead: &[bool]) -> Option<LintFinding> {
+    // follow the data pointer from the start of the program for as long as we know where it is
+
+    let mut position = 0;
+    let mut pos = 0;
+
+    while pos < ops.len() {
+        match ops[pos].op {
+            Opcode::JUMPIFZERO { arg } if is_dead[pos] => {
+                pos = arg;
+            }
+            // a loop that moves nowhere overall can be followed through its body,
+            // anything else could leave the data pointer anywhere
+            Opcode::JUMPIFZERO { arg } if net_movement(ops, pos, arg) != Some(0) => {
+                return None;
+            }
+            Opcode::MOVE { arg } => {
+                position += arg;
+                if position < 0 {
+                    return Some(LintFinding::new(
+                        LintCode::MovesLeftOfStart,
+                        ops[pos].span,
+                        String::from("this moves the data pointer left of the first cell"),
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        pos += 1;
+    }
+
+    None
+}
+
+fn loop_changes_tested_cell(ops: &[SpannedOpcode], start: usize, end: usize) -> Option<bool> {
+    // checks if anything in the body of the loop can change the cell the loop is testing,
+    // this returns None if we can't tell
+    let mut offset = 0;
+
+    for op in &ops[(start + 1)..end] {
+        match op.op {
+            Opcode::MOVE { arg } => offset += arg,
+            Opcode::CHANGE { .. } | Opcode::READCHAR { .. } | Opcode::SETTO { .. }
+                if offset == 0 =>
+            {
+                return Some(true);
+            }
+            // an inner loop on the tested cell only ends once the cell is zero
+            Opcode::JUMPIFZERO { .. } => return if offset == 0 { Some(true) } else { None },
+            _ => {}
+        }
+    }
+
+    if offset == 0 { Some(false) } else { None }
+}
+
+fn find_loops_never_changing_cell(ops: &[SpannedOpcode], is_dead: &[bool]) -> Vec<LintFinding> {
+    let mut findings = vec![];
+
+    for (pos, op) in ops.iter().enumerate() {
+        if let Opcode::JUMPIFZERO { arg } = op.op
+            && !is_dead[pos]
+            && loop_changes_tested_cell(ops, pos, arg) == Some(false)
+        {
+            findings.push(LintFinding::new(
+                LintCode::LoopNeverChangesCell,
+                op.span.merge(ops[arg].span),
+                String::from("this loop never changes the cell it tests, once entered it never ends"),
+            ));
+        }
+    }
+
+    findings
+}
+
+fn find_no_op_sequences(ops: &[SpannedOpcode], is_dead: &[bool]) -> Vec<LintFinding> {
+    // look for runs of + and - (or < and >) that partly or entirely undo each other
+    let mut findings = vec![];
+    let mut pos = 0;
+
+    while pos < ops.len() {
+        let run_length = ops[pos..]
+            .iter()
+            .take_while(|x| std::mem::discriminant(&x.op) == std::mem::discriminant(&ops[pos].op))
+            .count();
+
+        let steps = ops[pos..(pos + run_length)]
+            .iter()
+            .filter_map(|x| match x.op {
+                Opcode::CHANGE { arg, .. } | Opcode::MOVE { arg } => Some(arg),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let has_both_directions = steps.iter().any(|&x| x > 0) && steps.iter().any(|&x| x < 0);
+
+        if has_both_directions && !is_dead[pos] {
+            let net = steps.iter().sum::<i32>();
+            let (name, forward, backward) = match ops[pos].op {
+                Opcode::CHANGE { .. } => ("changes", "+", "-"),
+                _ => ("moves", ">", "<"),
+            };
+
+            let message = if net == 0 {
+                format!("these {} cancel out and have no effect", name)
+            } else {
+                let symbol = if net > 0 { forward } else { backward };
+                format!(
+                    "these {} partly cancel out, this is the same as `{}`",
+                    name,
+                    symbol.repeat(net.unsigned_abs() as usize)
+                )
+            };
+
+            findings.push(LintFinding::new(
+                LintCode::NoOpSequence,
+                ops[pos].span.merge(ops[pos + run_length - 1].span),
+                message,
+            ));
+        }
+
+        pos += run_length;
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(source: &str) -> Vec<LintCode> {
+        lint_source(source).iter().map(|x| x.code).collect()
+    }
+
+    #[test]
+    fn finds_unmatched_brackets() {
+        assert_eq!(codes("+[->+<"), [LintCode::UnmatchedBracket]);
+        assert_eq!(codes("+]"), [LintCode::UnmatchedBracket]);
+        assert!(codes("+[->+<]").is_empty());
+    }
+
+    #[test]
+    fn finds_dead_loops() {
+        let findings = lint_source("[a comment, with + and -]+.");
+        assert_eq!(findings[0].code, LintCode::DeadLoop);
+        assert_eq!(findings[0].span, Span::new(0, 25));
+        assert_eq!(findings[0].severity, Severity::Info);
+
+        // the +- in the comment isn't reported, as it never runs
+        assert_eq!(codes("[+-<<]+."), [LintCode::DeadLoop]);
+        assert!(codes("+[-].").is_empty());
+    }
+
+    #[test]
+    fn finds_moves_left_of_the_start() {
+        let findings = lint_source(">+<<+");
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].code, LintCode::MovesLeftOfStart);
+        assert_eq!(findings[0].span, Span::new(3, 4));
+
+        // loops that move nowhere overall can be followed, other loops can't
+        assert_eq!(codes("+[->+<]<"), [LintCode::MovesLeftOfStart]);
+        assert!(codes(">+<-").is_empty());
+        assert!(codes("+[>+]<<").is_empty());
+    }
+
+    #[test]
+    fn finds_loops_that_never_change_their_cell() {
+        let findings = lint_source("+[>+<]");
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].code, LintCode::LoopNeverChangesCell);
+        assert_eq!(findings[0].span, Span::new(1, 6));
+
+        assert!(codes("+[->+<]").is_empty());
+        assert!(codes("+[>[-]<,]").is_empty());
+        // a loop that moves is a scan, which ends on some other cell
+        assert!(codes("+[>]").is_empty());
+    }
+
+    #[test]
+    fn finds_no_op_sequences() {
+        let findings = lint_source("+-.>>><<.");
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].code, LintCode::NoOpSequence);
+        assert_eq!(
+            findings[0].message,
+            "these changes cancel out and have no effect"
+        );
+        assert_eq!(findings[0].span, Span::new(0, 2));
+        assert_eq!(
+            findings[1].message,
+            "these moves partly cancel out, this is the same as `>`"
+        );
+
+        assert!(codes("++.>>.").is_empty());
+        // the + and - are split by the output, so they aren't one run
+        assert!(codes("+.-.").is_empty());
+    }
+
+    #[test]
+    fn renders_the_finding_under_its_code() {
+        let source = "\n+++-+.";
+        let findings = lint_source(source);
+        assert_eq!(
+            findings[0].render(&LineIndex::new(source)),
+            "warning[BF004]: these changes partly cancel out, this is the same as `+++` at line 2, \
+             column 1\n 2 | +++-+.\n   | ^^^^^\n"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use crate::decompiler::decompile;
+use crate::opcodes::{
+    compress_foldable_opcodes, remove_dead_loops, remove_pointless_code_alteration, strip_spans,
+    tokenize_instructions,
+};
+use crate::source_utils::{BracketDiagnostic, find_bracket_errors, remove_no_coding_symbols};
+
+pub fn minify_source(program_code: &str) -> Result<String, Vec<BracketDiagnostic>> {
+    // strips the comments and gives the shortest BF that does the same thing, assuming 8 bit cells
+    let bracket_errors = find_bracket_errors(program_code);
+
+    if !bracket_errors.is_empty() {
+        return Err(bracket_errors);
+    }
+
+    let program_code = remove_no_coding_symbols(program_code.to_string());
+
+    let mut code = tokenize_instructions(program_code);
+
+    // taking out dead code can leave things next to each other that fold, so keep going until
+    // there is nothing left to do
+    loop {
+        let code_length = code.len();
+
+        code = compress_foldable_opcodes(code);
+        code = remove_pointless_code_alteration(code);
+        code = remove_dead_loops(code);
+
+        if code.len() == code_length {
+            break;
+        }
+    }
+
+    // none of the passes above make a MULTI, which is the only thing that can't be decompiled
+    Ok(decompile(&strip_spans(&code)).expect("Expected the minified opcodes to decompile"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancels_opposite_runs() {
+        assert_eq!(minify_source("+-+><>>-<+.").unwrap(), "+>>-<+.");
+        assert_eq!(minify_source("+<>-.").unwrap(), ".");
+    }
+
+    #[test]
+    fn drops_comments_and_dead_loops() {
+        assert_eq!(minify_source("[a comment, with. code]+ add one\n.").unwrap(), "+.");
+        assert_eq!(minify_source("+.[-][-]").unwrap(), "+.[-]");
+    }
+
+    #[test]
+    fn never_longer_than_stripped_source() {
+        for source in [
+            include_str!("../examples/hello_world.bf"),
+            include_str!("../examples/divide.bf"),
+            include_str!("../examples/simple_hard.bf"),
+            include_str!("../examples/squares.bf"),
+        ] {
+            let stripped = remove_no_coding_symbols(source.to_string());
+            assert!(minify_source(source).unwrap().len() <= stripped.len());
+        }
+    }
+
+    #[test]
+    fn rejects_unbalanced_brackets() {
+        assert!(minify_source("[[]").is_err());
+    }
+}
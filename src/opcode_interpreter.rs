@@ -1,97 +1,303 @@
 use crate::bf_executor::BFExecuter;
-use crate::opcodes::Opcode;
-use crate::state::ProgramState;
-use std::io::{Read, Stdin, Stdout, Write};
+use crate::opcodes::{Opcode, SpannedOpcode, carry, count_down, divmod, triangle};
+use crate::packed::{PackedOp, PackedProgram};
+use crate::scan::find_zero;
+use crate::span::Span;
+use crate::state::{ProgramState, TAPE_SIZE};
+use crate::validation::{ValidationError, validate};
+use std::io::{ErrorKind, Read, Stdin, Stdout, Write};
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct RuntimeError {
+    // the instruction that tried to leave the tape, and where it came from in the source if known
+    pub ip: usize,
+    pub span: Option<Span>,
+}
 
 pub struct BFOpcodeInterpreter {
-    program: Vec<Opcode>,
+    program: PackedProgram,
+    spans: Vec<Span>,
     pub machine: ProgramState,
     stdin: Stdin,
     stdout: Stdout,
     inst_evaluated: usize,
+    error: Option<RuntimeError>,
 }
 
 impl BFOpcodeInterpreter {
     pub fn new(program_source: Vec<Opcode>) -> Self {
         Self {
-            program: program_source,
+            program: PackedProgram::new(&program_source),
+            spans: vec![],
             machine: ProgramState::new(),
             stdin: std::io::stdin(),
             stdout: std::io::stdout(),
             inst_evaluated: 0,
+            error: None,
         }
     }
+
+    pub fn new_checked(program_source: Vec<Opcode>) -> Result<Self, ValidationError> {
+        // the VM trusts the jump targets and operands it is given, so anything that didn't come
+        // straight out of the compiler should come in this way
+        validate(&program_source)?;
+        Ok(Self::new(program_source))
+    }
+
+    pub fn with_spans(program_source: &[SpannedOpcode]) -> Self {
+        // keeping the spans around lets runtime errors point back at the source
+        let mut interpreter = Self::new(program_source.iter().map(|x| x.op).collect());
+        interpreter.spans = program_source.iter().map(|x| x.span).collect();
+        interpreter
+    }
+
+    #[must_use]
+    pub fn source_span(&self, ip: usize) -> Option<Span> {
+        self.spans.get(ip).copied()
+    }
+
+    #[must_use]
+    pub const fn runtime_error(&self) -> Option<RuntimeError> {
+        self.error
+    }
+
+    fn leave_tape(&mut self) {
+        // records an error against the current instruction for going off the tape
+        self.error = Some(RuntimeError {
+            ip: self.machine.ip,
+            span: self.source_span(self.machine.ip),
+        });
+    }
+}
+
+const fn cell_at(dp: usize, offset: i16) -> Option<usize> {
+    // the cell an opcode with an offset works on, if it is on the tape
+    let pos = dp.wrapping_add_signed(offset as isize);
+
+    if pos >= TAPE_SIZE {
+        return None;
+    }
+
+    Some(pos)
+}
+
+// the io is kept out of line, it is rare enough that it is better off not taking up registers in
+// the VM's loop
+#[inline(never)]
+fn read_byte(mut input: impl Read) -> Option<u8> {
+    // there is nothing left to read at the end of the input
+    let mut byte = [0_u8];
+    match input.read_exact(&mut byte) {
+        Ok(()) => Some(byte[0]),
+        Err(x) if x.kind() == ErrorKind::UnexpectedEof => None,
+        Err(x) => panic!("Expected to be able to read a single char: {}", x),
+    }
+}
+
+#[inline(never)]
+fn write_bytes(mut stdout: &Stdout, bytes: &[u8]) {
+    stdout
+        .write_all(bytes)
+        .expect("Expected to be able to write to stdout");
 }
 
 impl BFExecuter for BFOpcodeInterpreter {
     fn execute(&mut self) {
-        while self.machine.ip < self.program.len() {
-            match self.program[self.machine.ip] {
-                Opcode::CHANGE { arg } => {
-                    let new_state = self.machine.memory[self.machine.dp] as i32 + arg;
-                    self.machine.memory[self.machine.dp] = new_state as u8;
+        // the registers and the tape are kept in locals while the program runs, so that the
+        // compiler can keep them out of memory, and are only written back once it stops
+        let code = self.program.code.as_slice();
+        let memory = &mut self.machine.memory;
+        let mut ip = self.machine.ip;
+        let mut dp = self.machine.dp;
+        let mut inst_evaluated = 0;
+
+        let off_tape = 'execution: loop {
+            if ip >= code.len() {
+                break false;
+            }
+
+            match code[ip] {
+                PackedOp::CHANGE { arg, offset } => {
+                    let Some(pos) = cell_at(dp, offset) else {
+                        break 'execution true;
+                    };
+                    memory[pos] = memory[pos].wrapping_add(arg);
                 }
-                Opcode::MOVE { arg } => {
-                    self.machine.dp = (self.machine.dp as i32 + arg) as usize;
+                PackedOp::MOVE { arg } => {
+                    dp = dp.wrapping_add_signed(arg.into());
+                    if dp >= TAPE_SIZE {
+                        break 'execution true;
+                    }
                 }
-                Opcode::PUTCHAR { arg } => {
+                PackedOp::PUTCHAR { arg, offset } => {
+                    let Some(pos) = cell_at(dp, offset) else {
+                        break 'execution true;
+                    };
                     for _ in 0..arg {
-                        self.write_char();
+                        write_bytes(&self.stdout, &[memory[pos]]);
                     }
                 }
-                Opcode::READCHAR { arg } => {
+                PackedOp::READCHAR { arg, offset } => {
+                    let Some(pos) = cell_at(dp, offset) else {
+                        break 'execution true;
+                    };
                     for _ in 0..arg {
-                        self.read_char();
+                        // reading past the end of the input stops the program, as VM_CONFIG
+                        // says, the same as running off the end of the code
+                        let Some(byte) = read_byte(self.stdin.lock()) else {
+                            ip = code.len();
+                            break 'execution false;
+                        };
+                        memory[pos] = byte;
                     }
                 }
-                Opcode::JUMPIFZERO { arg } => {
-                    if self.machine.memory[self.machine.dp] == 0 {
-                        self.machine.ip = arg;
+                PackedOp::JUMPIFZERO { forward } => {
+                    if memory[dp] == 0 {
+                        ip += forward as usize;
                     }
                 }
-                Opcode::JUMPIFNZERO { arg } => {
-                    if self.machine.memory[self.machine.dp] != 0 {
-                        self.machine.ip = arg;
+                PackedOp::JUMPIFNZERO { back } => {
+                    if memory[dp] != 0 {
+                        ip -= back as usize;
                     }
                 }
-                Opcode::SETTO { arg } => {
-                    self.machine.memory[self.machine.dp] = arg as u8;
+                PackedOp::SETTO { arg, offset } => {
+                    let Some(pos) = cell_at(dp, offset) else {
+                        break 'execution true;
+                    };
+                    memory[pos] = arg;
                 }
-                Opcode::SCANBY { arg } => {
-                    while self.machine.memory[self.machine.dp] != 0 {
-                        self.machine.dp = (self.machine.dp as i32 + arg) as usize;
-                    }
+                // a scan that starts on a zero cell doesn't need a search
+                PackedOp::SCANBY { .. } if memory[dp] == 0 => {}
+                PackedOp::SCANBY { arg } => {
+                    let Some(pos) = find_zero(memory, dp, arg.into()) else {
+                        break 'execution true;
+                    };
+                    dp = pos;
                 }
-                Opcode::MULTI { arg1: x, arg2: y } => {
-                    let placement_index = (self.machine.dp as i32 + x).max(0);
-                    self.machine.memory[placement_index as usize] +=
-                        y as u8 * self.machine.memory[self.machine.dp];
+                PackedOp::MULTI { arg1: x, arg2: y } => {
+                    let placement_index = dp.saturating_add_signed(x.into());
+                    if placement_index >= TAPE_SIZE {
+                        break 'execution true;
+                    }
+                    let product = memory[dp].wrapping_mul(y);
+                    memory[placement_index] = memory[placement_index].wrapping_add(product);
                 }
-                Opcode::MOVINGCHANGE {
+                PackedOp::MOVINGCHANGE {
                     arg1: x,
                     arg2: y,
                     arg3: z,
                 } => {
-                    while self.machine.memory[self.machine.dp] != 0 {
-                        self.machine.dp = (self.machine.dp as i32 + x) as usize;
-                        self.machine.memory[self.machine.dp] += y as u8;
-                        self.machine.dp = (self.machine.dp as i32 + z) as usize;
+                    while memory[dp] != 0 {
+                        let Some(pos) = cell_at(dp, x) else {
+                            break 'execution true;
+                        };
+                        memory[pos] = memory[pos].wrapping_add(y);
+                        let Some(pos) = cell_at(pos, z) else {
+                            break 'execution true;
+                        };
+                        dp = pos;
                     }
                 }
-                Opcode::MOVINGSET {
+                PackedOp::MOVINGSET {
                     arg1: x,
                     arg2: y,
                     arg3: z,
                 } => {
-                    while self.machine.memory[self.machine.dp] != 0 {
-                        self.machine.dp = (self.machine.dp as i32 + x) as usize;
-                        self.machine.memory[self.machine.dp] = y as u8;
-                        self.machine.dp = (self.machine.dp as i32 + z) as usize;
+                    while memory[dp] != 0 {
+                        let Some(pos) = cell_at(dp, x) else {
+                            break 'execution true;
+                        };
+                        memory[pos] = y;
+                        let Some(pos) = cell_at(pos, z) else {
+                            break 'execution true;
+                        };
+                        dp = pos;
+                    }
+                }
+                PackedOp::PRODUCT { arg1, arg2, arg3 } => {
+                    let (Some(target), Some(other)) = (cell_at(dp, arg1), cell_at(dp, arg2)) else {
+                        break 'execution true;
+                    };
+                    let product = memory[dp].wrapping_mul(memory[other]).wrapping_mul(arg3);
+                    memory[target] = memory[target].wrapping_add(product);
+                }
+                PackedOp::TRIANGLE { arg1, arg2 } => {
+                    let Some(target) = cell_at(dp, arg1) else {
+                        break 'execution true;
+                    };
+                    let sum = triangle(memory[dp]).wrapping_mul(arg2);
+                    memory[target] = memory[target].wrapping_add(sum);
+                }
+                PackedOp::COPY { arg1, arg2 } => {
+                    let (Some(target), Some(temp)) = (cell_at(dp, arg1), cell_at(dp, arg2)) else {
+                        break 'execution true;
+                    };
+                    memory[target] = memory[target].wrapping_add(memory[dp]);
+                    memory[dp] = memory[dp].wrapping_add(memory[temp]);
+                    memory[temp] = 0;
+                }
+                PackedOp::CARRY { arg1, arg2, arg3 } => {
+                    let (Some(target), Some(carried)) = (cell_at(dp, arg1), cell_at(dp, arg2))
+                    else {
+                        break 'execution true;
+                    };
+                    let (x, y) = carry(memory[dp], arg3);
+                    memory[target] = memory[target].wrapping_add(x);
+                    memory[carried] = memory[carried].wrapping_add(y);
+                    memory[dp] = 0;
+                }
+                PackedOp::AND { arg1, arg2 } => {
+                    let (Some(other), Some(target)) = (cell_at(dp, arg1), cell_at(dp, arg2)) else {
+                        break 'execution true;
+                    };
+                    if memory[dp] != 0 {
+                        memory[target] = u8::from(memory[other] != 0);
+                        memory[other] = 0;
+                        memory[dp] = 0;
                     }
                 }
+                PackedOp::COUNTDOWN { arg1, arg2 } => {
+                    let (Some(x), Some(y)) = (cell_at(dp, arg1), cell_at(dp, arg2)) else {
+                        break 'execution true;
+                    };
+                    if memory[dp] != 0 {
+                        (memory[x], memory[y]) = count_down(memory[x], memory[y]);
+                        memory[dp] = 0;
+                    }
+                }
+                PackedOp::DIVMOD { arg1, arg2, arg3 } => {
+                    let (Some(quotient), Some(divisor), Some(left)) =
+                        (cell_at(dp, arg1), cell_at(dp, arg2), cell_at(dp, arg3))
+                    else {
+                        break 'execution true;
+                    };
+                    // the loop it stands in for never ends, so neither does this
+                    let Some((x, y)) = divmod(memory[dp], memory[divisor], memory[left]) else {
+                        inst_evaluated += 1;
+                        continue;
+                    };
+                    memory[quotient] = memory[quotient].wrapping_add(x);
+                    memory[left] = y;
+                    memory[dp] = 0;
+                }
+                // the body leaves the cell at zero, so there is nothing to check
+                PackedOp::ENDIF => {}
+                PackedOp::PRINT { start, len } => {
+                    let start = start as usize;
+                    write_bytes(&self.stdout, &self.program.text[start..(start + usize::from(len))]);
+                }
             }
-            self.machine.ip += 1;
-            self.inst_evaluated += 1;
+            ip += 1;
+            inst_evaluated += 1;
+        };
+
+        self.machine.ip = ip;
+        self.machine.dp = dp;
+        self.inst_evaluated += inst_evaluated;
+
+        if off_tape {
+            self.leave_tape();
         }
 
         // make sure we flush out everything in the
@@ -101,20 +307,14 @@ impl BFExecuter for BFOpcodeInterpreter {
     }
 
     fn read_char(&mut self) {
-        let mut byte = [0_u8];
-        self.stdin
-            .lock()
-            .read_exact(&mut byte)
-            .expect("Expected to be able to read a single char");
-        self.machine.memory[self.machine.dp] = byte[0];
+        // this can't stop the program, so the cell is left as it is at the end of the input
+        if let Some(byte) = read_byte(self.stdin.lock()) {
+            self.machine.memory[self.machine.dp] = byte;
+        }
     }
 
     fn write_char(&mut self) {
-        let mut byte = [0_u8];
-        byte[0] = (self.machine.memory[self.machine.dp] & 0xFF) as u8;
-        self.stdout
-            .write_all(&byte)
-            .expect("Expected to be able to write a single char");
+        write_bytes(&self.stdout, &[self.machine.memory[self.machine.dp]]);
     }
 
     fn instruction_count(&mut self) -> usize {
@@ -123,5 +323,20 @@ impl BFExecuter for BFOpcodeInterpreter {
 
     fn reset_machine_state(&mut self) {
         self.machine = ProgramState::new();
+        self.error = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_end_of_the_input_reads_nothing() {
+        let mut input: &[u8] = b"ab";
+        assert_eq!(read_byte(&mut input), Some(b'a'));
+        assert_eq!(read_byte(&mut input), Some(b'b'));
+        assert_eq!(read_byte(&mut input), None);
+        assert_eq!(read_byte(&mut input), None);
     }
 }
@@ -1,59 +1,114 @@
 use crate::opcodes::Opcode::{JUMPIFNZERO, JUMPIFZERO, SCANBY};
+use crate::dataflow::written_offsets;
+use crate::ir::{Block, Node, body_opcodes, build_tree, lower_tree, rewrite_loops};
+use crate::passes::PassManager;
+use crate::span::Span;
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
 pub enum Opcode {
-    CHANGE { arg: i32 },
+    // the offset is where the opcode works relative to the data pointer, without moving it
+    CHANGE { arg: i32, offset: i32 },
     MOVE { arg: i32 },
-    PUTCHAR { arg: usize },
-    READCHAR { arg: usize },
+    PUTCHAR { arg: usize, offset: i32 },
+    READCHAR { arg: usize, offset: i32 },
     JUMPIFZERO { arg: usize },
     JUMPIFNZERO { arg: usize },
-    SETTO { arg: i32 },
+    SETTO { arg: i32, offset: i32 },
     SCANBY { arg: i32 },
     MULTI { arg1: i32, arg2: i32 },
     MOVINGCHANGE { arg1: i32, arg2: i32, arg3: i32 },
     MOVINGSET { arg1: i32, arg2: i32, arg3: i32 },
+    // prints the first len of the bytes, longer text is split over several of these
+    PRINT { bytes: [u8; PRINT_WIDTH], len: u8 },
+    // closes a loop that runs at most once, in place of the JUMPIFNZERO that would never jump
+    ENDIF { arg: usize },
+    // adds arg3 times the cell under the data pointer times the cell at arg2 to the cell at arg1
+    PRODUCT { arg1: i32, arg2: i32, arg3: i32 },
+    // adds arg2 times 1 + 2 + ... + the cell under the data pointer to the cell at arg1
+    TRIANGLE { arg1: i32, arg2: i32 },
+    // adds the cell under the data pointer to the cell at arg1, moves the cell at arg2 into the
+    // cell under the data pointer and clears the cell at arg2
+    COPY { arg1: i32, arg2: i32 },
+    // moves the cell under the data pointer into the cell at arg1, and if it was at least arg3
+    // takes arg3 back off that cell once and adds one to the cell at arg2, like carrying a digit
+    CARRY { arg1: i32, arg2: i32, arg3: i32 },
+    // if the cell under the data pointer isn't zero, sets the cell at arg2 to 1 if the cell at
+    // arg1 isn't zero and to 0 if it is, and clears the cell at arg1 and the one under the data
+    // pointer, so the cell at arg2 ends up telling whether both of them were non zero
+    AND { arg1: i32, arg2: i32 },
+    // if the cell under the data pointer isn't zero, clears it and takes one off the cells at
+    // arg1 and arg2, and then keeps taking one off both for as long as neither of them is zero
+    COUNTDOWN { arg1: i32, arg2: i32 },
+    // divides the cell under the data pointer by the cell at arg2 by taking it off until nothing
+    // is left, every time around the cell at arg2 is added to the cell at arg3 and the smaller
+    // of that and the cell under the data pointer is taken off both, and one is added to the cell
+    // at arg1, so starting with arg3 at zero leaves the quotient rounded up in arg1 and how much
+    // of the last subtraction was left over in arg3
+    DIVMOD { arg1: i32, arg2: i32, arg3: i32 },
+}
+
+// the most bytes a single PRINT holds, any more and every other opcode would get bigger too
+pub const PRINT_WIDTH: usize = 8;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct SpannedOpcode {
+    // an opcode together with the region of the source it was generated from
+    pub op: Opcode,
+    pub span: Span,
+}
+
+impl SpannedOpcode {
+    pub const fn new(op: Opcode, span: Span) -> Self {
+        Self { op, span }
+    }
 }
 
 pub fn compile_code(program_code: String) -> Vec<Opcode> {
     // current compilation of BF to Opcdoes
-    let code = tokenize_instructions(program_code);
-    let code = compress_foldable_opcodes(code);
-    let code = remove_pointless_code_alteration(code);
-    let code = gen_scanning_ops(code);
-    let code = loop_transformations(code);
+    strip_spans(&compile_code_with_spans(program_code))
+}
 
-    reset_bracket(code)
+pub fn compile_code_with_spans(program_code: String) -> Vec<SpannedOpcode> {
+    // same as compile_code, but every opcode remembers where in the source it came from
+    PassManager::default().compile(program_code)
 }
 
-pub fn tokenize_instructions(program_code: String) -> Vec<Opcode> {
+pub fn strip_spans(opcodes: &[SpannedOpcode]) -> Vec<Opcode> {
+    opcodes.iter().map(|x| x.op).collect()
+}
+
+pub fn tokenize_instructions(program_code: String) -> Vec<SpannedOpcode> {
     let mut loop_stack = vec![];
     let mut ops = vec![];
 
-    // remove anything that isn't a BF statement
+    // skip anything that isn't a BF statement, but remember where each statement is in the source
     let valid_chars = ['+', '-', '<', '>', ',', '.', '[', ']'];
-    let bf_valid_code = |c| valid_chars.contains(&c);
-
-    let mut program_code = program_code;
-    program_code.retain(bf_valid_code);
+    let program_chars = program_code
+        .char_indices()
+        .filter(|(_, c)| valid_chars.contains(c))
+        .collect::<Vec<(usize, char)>>();
 
     let mut pos = 0;
 
-    // this is a good place as any to intercept the clear loop detection
-    let program_code = program_code.replace("[-]", "C");
-    let program_code = program_code.replace("[+]", "C");
-
-    let program_chars = program_code.chars().collect::<Vec<char>>();
-
     while pos < program_chars.len() {
-        let curr = program_chars[pos];
+        let (offset, curr) = program_chars[pos];
+        let span = Span::new(offset, offset + 1);
 
         let mut emit = |x| {
-            ops.push(x);
+            ops.push(SpannedOpcode::new(x, span));
             ops.len() - 1
         };
 
         match curr {
+            // this is a good place as any to intercept the clear loop detection
+            '[' if is_clear_loop(&program_chars[pos..]) => {
+                let (close_offset, _) = program_chars[pos + 2];
+                ops.push(SpannedOpcode::new(
+                    Opcode::SETTO { arg: 0, offset: 0 },
+                    Span::new(offset, close_offset + 1),
+                ));
+                pos += 2;
+            }
             '[' => {
                 let ins_pos = emit(JUMPIFZERO { arg: 0 });
                 loop_stack.push(ins_pos);
@@ -62,28 +117,25 @@ pub fn tokenize_instructions(program_code: String) -> Vec<Opcode> {
                 let open_instr = loop_stack[loop_stack.len() - 1];
                 loop_stack.pop();
                 let close_instr = emit(JUMPIFNZERO { arg: open_instr });
-                ops[open_instr] = Opcode::JUMPIFZERO { arg: close_instr };
+                ops[open_instr].op = Opcode::JUMPIFZERO { arg: close_instr };
             }
             '+' => {
-                ops.push(Opcode::CHANGE { arg: 1 });
+                emit(Opcode::CHANGE { arg: 1, offset: 0 });
             }
             '-' => {
-                ops.push(Opcode::CHANGE { arg: -1 });
+                emit(Opcode::CHANGE { arg: -1, offset: 0 });
             }
             '<' => {
-                ops.push(Opcode::MOVE { arg: -1 });
+                emit(Opcode::MOVE { arg: -1 });
             }
             '>' => {
-                ops.push(Opcode::MOVE { arg: 1 });
+                emit(Opcode::MOVE { arg: 1 });
             }
             '.' => {
-                ops.push(Opcode::PUTCHAR { arg: 1 });
+                emit(Opcode::PUTCHAR { arg: 1, offset: 0 });
             }
             ',' => {
-                ops.push(Opcode::READCHAR { arg: 1 });
-            }
-            'C' => {
-                ops.push(Opcode::SETTO { arg: 0 });
+                emit(Opcode::READCHAR { arg: 1, offset: 0 });
             }
             _ => {}
         }
@@ -94,7 +146,12 @@ pub fn tokenize_instructions(program_code: String) -> Vec<Opcode> {
     ops
 }
 
-pub fn compress_foldable_opcodes(opcodes: Vec<Opcode>) -> Vec<Opcode> {
+fn is_clear_loop(program_chars: &[(usize, char)]) -> bool {
+    // looks for [-] or [+] at the start of the given code
+    matches!(program_chars, [(_, '['), (_, '-' | '+'), (_, ']'), ..])
+}
+
+pub fn compress_foldable_opcodes(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
     if opcodes.len() <= 1 {
         return opcodes;
     }
@@ -102,42 +159,59 @@ pub fn compress_foldable_opcodes(opcodes: Vec<Opcode>) -> Vec<Opcode> {
     let mut output = vec![*opcodes.first().unwrap()];
 
     for &opcode in opcodes.iter().skip(1) {
-        match opcode {
-            Opcode::CHANGE { arg: arg1 } => match output[output.len() - 1] {
-                Opcode::CHANGE { arg: arg2 } => {
+        // folding can cancel out everything before this opcode, e.g. +-.
+        let Some(&last) = output.last() else {
+            output.push(opcode);
+            continue;
+        };
+        let span = last.span.merge(opcode.span);
+
+        match opcode.op {
+            // changes, output and input only fold together if they are on the same cell
+            Opcode::CHANGE { arg: arg1, offset } => match last.op {
+                Opcode::CHANGE { arg: arg2, offset: offset2 } if offset == offset2 => {
                     output.pop();
                     if arg1 + arg2 != 0 {
-                        output.push(Opcode::CHANGE { arg: arg1 + arg2 });
+                        output.push(SpannedOpcode::new(
+                            Opcode::CHANGE { arg: arg1 + arg2, offset },
+                            span,
+                        ));
                     }
                 }
-                _ => output.push(Opcode::CHANGE { arg: arg1 }),
+                _ => output.push(opcode),
             },
-            Opcode::MOVE { arg: arg1 } => match output[output.len() - 1] {
+            Opcode::MOVE { arg: arg1 } => match last.op {
                 Opcode::MOVE { arg: arg2 } => {
                     output.pop();
                     if arg1 + arg2 != 0 {
-                        output.push(Opcode::MOVE { arg: arg1 + arg2 });
+                        output.push(SpannedOpcode::new(Opcode::MOVE { arg: arg1 + arg2 }, span));
                     }
                 }
-                _ => output.push(Opcode::MOVE { arg: arg1 }),
+                _ => output.push(opcode),
             },
-            Opcode::PUTCHAR { arg: arg1 } => match output[output.len() - 1] {
-                Opcode::PUTCHAR { arg: arg2 } => {
+            Opcode::PUTCHAR { arg: arg1, offset } => match last.op {
+                Opcode::PUTCHAR { arg: arg2, offset: offset2 } if offset == offset2 => {
                     output.pop();
                     if arg1 + arg2 != 0 {
-                        output.push(Opcode::PUTCHAR { arg: arg1 + arg2 });
+                        output.push(SpannedOpcode::new(
+                            Opcode::PUTCHAR { arg: arg1 + arg2, offset },
+                            span,
+                        ));
                     }
                 }
-                _ => output.push(Opcode::PUTCHAR { arg: arg1 }),
+                _ => output.push(opcode),
             },
-            Opcode::READCHAR { arg: arg1 } => match output[output.len() - 1] {
-                Opcode::READCHAR { arg: arg2 } => {
+            Opcode::READCHAR { arg: arg1, offset } => match last.op {
+                Opcode::READCHAR { arg: arg2, offset: offset2 } if offset == offset2 => {
                     output.pop();
                     if arg1 + arg2 != 0 {
-                        output.push(Opcode::READCHAR { arg: arg1 + arg2 });
+                        output.push(SpannedOpcode::new(
+                            Opcode::READCHAR { arg: arg1 + arg2, offset },
+                            span,
+                        ));
                     }
                 }
-                _ => output.push(Opcode::READCHAR { arg: arg1 }),
+                _ => output.push(opcode),
             },
 
             _ => output.push(opcode),
@@ -147,7 +221,7 @@ pub fn compress_foldable_opcodes(opcodes: Vec<Opcode>) -> Vec<Opcode> {
     output
 }
 
-pub fn remove_pointless_code_alteration(opcodes: Vec<Opcode>) -> Vec<Opcode> {
+pub fn remove_pointless_code_alteration(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
     // opcode sequences that have the following ... ,CHANGE(x),SetTo(x), ...
     // can be reduced to ..., SetTo(x), ..., we are clearing that location anyway
 
@@ -161,19 +235,27 @@ pub fn remove_pointless_code_alteration(opcodes: Vec<Opcode>) -> Vec<Opcode> {
     let mut output = vec![*opcodes.first().unwrap()];
 
     for &opcode in opcodes.iter().skip(1) {
-        match opcode {
-            Opcode::SETTO { .. } => match output[output.len() - 1] {
-                Opcode::CHANGE { .. } | Opcode::SETTO { .. } => {
+        let last = output[output.len() - 1];
+        let span = last.span.merge(opcode.span);
+
+        match opcode.op {
+            Opcode::SETTO { offset, .. } => match last.op {
+                Opcode::CHANGE { offset: offset2, .. } | Opcode::SETTO { offset: offset2, .. }
+                    if offset == offset2 =>
+                {
                     output.pop();
-                    output.push(opcode);
+                    output.push(SpannedOpcode::new(opcode.op, span));
                 }
                 _ => output.push(opcode),
             },
 
-            Opcode::CHANGE { arg } => match output[output.len() - 1] {
-                Opcode::SETTO { arg: arg2 } => {
+            Opcode::CHANGE { arg, offset } => match last.op {
+                Opcode::SETTO { arg: arg2, offset: offset2 } if offset == offset2 => {
                     output.pop();
-                    output.push(Opcode::SETTO { arg: arg + arg2 });
+                    output.push(SpannedOpcode::new(
+                        Opcode::SETTO { arg: wrap_cell_value(arg + arg2), offset },
+                        span,
+                    ));
                 }
                 _ => output.push(opcode),
             },
@@ -184,7 +266,41 @@ pub fn remove_pointless_code_alteration(opcodes: Vec<Opcode>) -> Vec<Opcode> {
     output
 }
 
-pub fn gen_scanning_ops(opcodes: Vec<Opcode>) -> Vec<Opcode> {
+pub fn gen_clearing_ops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // the tokenizer only sees [-] and [+], but after folding a loop like [-+-] or [+++] also just
+    // clears the cell, ..., JumpIfZero, Change(odd k), JumpIfNotZero, ... becomes ..., SetTo(0), ...
+
+    let mut output: Vec<SpannedOpcode> = Vec::with_capacity(opcodes.len());
+
+    for &opcode in &opcodes {
+        let is_clear_loop = matches!(
+            (opcode.op, output.as_slice()),
+            (
+                JUMPIFNZERO { .. },
+                [
+                    ..,
+                    SpannedOpcode { op: JUMPIFZERO { .. }, .. },
+                    SpannedOpcode { op: Opcode::CHANGE { arg, offset: 0 }, .. },
+                ],
+            ) if arg % 2 != 0
+        );
+
+        if is_clear_loop {
+            output.pop();
+            let open = output.pop().unwrap();
+            output.push(SpannedOpcode::new(
+                Opcode::SETTO { arg: 0, offset: 0 },
+                open.span.merge(opcode.span),
+            ));
+        } else {
+            output.push(opcode);
+        }
+    }
+
+    output
+}
+
+pub fn gen_scanning_ops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
     // given the following code ..., JumpIfZero, Move(K), JumpifNotZero, ...
     // this can be replaced with the following opcodes ..., ScanBy(k), ....
 
@@ -192,16 +308,19 @@ pub fn gen_scanning_ops(opcodes: Vec<Opcode>) -> Vec<Opcode> {
         return opcodes;
     }
 
-    let mut output: Vec<Opcode> = vec![opcodes[0], opcodes[1]];
+    let mut output: Vec<SpannedOpcode> = vec![opcodes[0], opcodes[1]];
 
     for &opcode in opcodes.iter().skip(2) {
-        match opcode {
-            JUMPIFNZERO { .. } => match output[output.len() - 1] {
+        match opcode.op {
+            JUMPIFNZERO { .. } => match output[output.len() - 1].op {
                 Opcode::MOVE { arg } => {
-                    if matches!(output[output.len() - 2], JUMPIFZERO { .. }) {
-                        output.pop();
+                    if matches!(output[output.len() - 2].op, JUMPIFZERO { .. }) {
                         output.pop();
-                        output.push(SCANBY { arg });
+                        let open = output.pop().unwrap();
+                        output.push(SpannedOpcode::new(
+                            SCANBY { arg },
+                            open.span.merge(opcode.span),
+                        ));
                     } else {
                         output.push(opcode);
                     }
@@ -215,153 +334,112 @@ pub fn gen_scanning_ops(opcodes: Vec<Opcode>) -> Vec<Opcode> {
     output
 }
 
-pub fn find_lowest_level_loops(opcodes: &[Opcode]) -> Vec<(usize, usize)> {
-    // basically scan from every open bracket and if we hit a closing bracket before
-    // we hit another open bracket we know this is the lowest level possible which opens up some
-    // possibilities for optimization
+pub fn find_dead_loops(ops: &[SpannedOpcode]) -> Vec<(usize, usize)> {
+    // a loop can't be entered if the cell it tests is known to be zero, this is the case
+    // at the start of the program, directly after another loop or after a clear loop,
+    // clear loops (SETTO(0)) on a cell that is already zero are reported as (pos, pos)
 
-    let mut all_brackets = vec![];
+    let mut dead_loops = vec![];
 
-    for &op in opcodes {
-        match op {
-            JUMPIFNZERO { .. } | JUMPIFZERO { .. } => all_brackets.push(op),
-            _ => {}
-        }
-    }
+    let mut cell_is_zero = true;
+    // until something writes to the tape, every cell we move to is zero
+    let mut tape_untouched = true;
 
-    let mut output = vec![];
+    let mut pos = 0;
 
-    for (&op1, &op2) in all_brackets.iter().zip(all_brackets.iter().skip(1)) {
-        if let (JUMPIFZERO { arg: x }, JUMPIFNZERO { arg: y }) = (op1, op2) {
-            output.push((y, x))
+    while pos < ops.len() {
+        match ops[pos].op {
+            Opcode::JUMPIFZERO { arg } => {
+                if cell_is_zero {
+                    // the cell stays zero, as the loop is jumped over
+                    dead_loops.push((pos, arg));
+                    pos = arg;
+                } else {
+                    // if we get into the body of the loop the cell can't be zero
+                    cell_is_zero = false;
+                }
+            }
+            Opcode::JUMPIFNZERO { .. } | Opcode::ENDIF { .. } => cell_is_zero = true,
+            Opcode::MOVE { .. } => cell_is_zero = tape_untouched,
+            Opcode::SETTO { arg: 0, offset: 0 } if cell_is_zero => {
+                dead_loops.push((pos, pos));
+            }
+            Opcode::SETTO { arg, offset } => {
+                if offset == 0 {
+                    cell_is_zero = arg == 0;
+                }
+                tape_untouched &= arg == 0;
+            }
+            Opcode::CHANGE { offset, .. } | Opcode::READCHAR { offset, .. } => {
+                if offset == 0 {
+                    cell_is_zero = false;
+                }
+                tape_untouched = false;
+            }
+            _ => {}
         }
+
+        pos += 1;
     }
 
-    output
+    dead_loops
 }
 
-pub fn is_simple_loop(opcodes: &[Opcode], start: usize, end: usize) -> bool {
-    // check if this is a loop with a net of no movement on the data pointer
-    // and that there aren't any other operations other than MOVE and CHANGE
+pub fn remove_dead_loops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    let opcodes = reset_bracket(opcodes);
 
-    let mut movement = 0;
+    let dead_loops = find_dead_loops(&opcodes);
+    let nothing = vec![vec![]; dead_loops.len()];
 
-    for op in opcodes.iter().take(end).skip(start + 1) {
-        match &op {
-            Opcode::MOVE { arg } => {
-                movement += arg;
-            }
-            Opcode::CHANGE { .. } => {}
-            _ => {
-                return false;
-            }
-        }
+    replace_segments(opcodes, &dead_loops, &nothing)
+}
+
+pub fn remove_dead_code(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // loops that can never be entered (which is how BF is usually commented out) can go, and so
+    // can everything after the last input or output, as nothing it does can be seen
+    let opcodes = remove_dead_loops(opcodes);
+
+    let Some(last_io) = opcodes
+        .iter()
+        .rposition(|x| {
+            matches!(
+                x.op,
+                Opcode::PUTCHAR { .. } | Opcode::READCHAR { .. } | Opcode::PRINT { .. }
+            )
+        })
+    else {
+        return vec![];
+    };
+
+    let bracket_depth = |op: Opcode| match op {
+        JUMPIFZERO { .. } => 1,
+        JUMPIFNZERO { .. } | Opcode::ENDIF { .. } => -1,
+        _ => 0,
+    };
+
+    // if the last input or output is in a loop, the loops around it may still run it again
+    let mut depth = opcodes[..last_io].iter().map(|x| bracket_depth(x.op)).sum::<i32>();
+    let mut end = last_io + 1;
+
+    while depth > 0 {
+        depth += bracket_depth(opcodes[end].op);
+        end += 1;
     }
 
-    movement == 0
+    opcodes.into_iter().take(end).collect()
 }
 
-pub fn find_transform_if_multi_loop(
-    opcodes: &Vec<Opcode>,
-    start: usize,
-    end: usize,
-) -> Option<Vec<Opcode>> {
+fn transform_multi_loop_body(loop_body: &[Opcode]) -> Option<Vec<Opcode>> {
+    if let Some(replacement) = transform_multiplication_loop(loop_body) {
+        return Some(replacement);
+    }
+
     // look for loops that look like the following
-    // [CHANGE(1)MOVE(K)CHANGE(L)MOVE(-K)]
-    match opcodes.as_slice()[(start + 1)..end] {
-        [
-            Opcode::CHANGE { arg: -1 },
-            Opcode::MOVE { arg: x },
-            Opcode::CHANGE { arg: l },
-            Opcode::MOVE { arg: y },
-        ] if y + x == 0 => Some(vec![
-            Opcode::MULTI { arg1: x, arg2: l },
-            Opcode::SETTO { arg: 0 },
-        ]),
-        [
-            Opcode::CHANGE { arg: -1 },
-            Opcode::MOVE { arg: m1 },
-            Opcode::CHANGE { arg: a1 },
-            Opcode::MOVE { arg: m2 },
-            Opcode::CHANGE { arg: a2 },
-            Opcode::MOVE { arg: m3 },
-        ] if m1 + m2 + m3 == 0 => Some(vec![
-            Opcode::MULTI { arg1: m1, arg2: a1 },
-            Opcode::MULTI {
-                arg1: m2 + m1,
-                arg2: a2,
-            },
-            Opcode::SETTO { arg: 0 },
-        ]),
-        [
-            Opcode::CHANGE { arg: -1 },
-            Opcode::MOVE { arg: m1 },
-            Opcode::CHANGE { arg: a1 },
-            Opcode::MOVE { arg: m2 },
-            Opcode::CHANGE { arg: a2 },
-            Opcode::MOVE { arg: m3 },
-            Opcode::CHANGE { arg: a3 },
-            Opcode::MOVE { arg: m4 },
-        ] if m1 + m2 + m3 + m4 == 0 => Some(vec![
-            Opcode::MULTI { arg1: m1, arg2: a1 },
-            Opcode::MULTI {
-                arg1: m2 + m1,
-                arg2: a2,
-            },
-            Opcode::MULTI {
-                arg1: m3 + m2 + m1,
-                arg2: a3,
-            },
-            Opcode::SETTO { arg: 0 },
-        ]),
-        [
-            Opcode::MOVE { arg: x },
-            Opcode::CHANGE { arg: l },
-            Opcode::MOVE { arg: y },
-            Opcode::CHANGE { arg: -1 },
-        ] if y + x == 0 => Some(vec![
-            Opcode::MULTI { arg1: x, arg2: l },
-            Opcode::SETTO { arg: 0 },
-        ]),
-        [
-            Opcode::MOVE { arg: m1 },
-            Opcode::CHANGE { arg: a1 },
-            Opcode::MOVE { arg: m2 },
-            Opcode::CHANGE { arg: a2 },
-            Opcode::MOVE { arg: m3 },
-            Opcode::CHANGE { arg: -1 },
-        ] if m1 + m2 + m3 == 0 => Some(vec![
-            Opcode::MULTI { arg1: m1, arg2: a1 },
-            Opcode::MULTI {
-                arg1: m1 + m2,
-                arg2: a2,
-            },
-            Opcode::SETTO { arg: 0 },
-        ]),
-        [
-            Opcode::MOVE { arg: m1 },
-            Opcode::CHANGE { arg: a1 },
-            Opcode::MOVE { arg: m2 },
-            Opcode::CHANGE { arg: a2 },
-            Opcode::MOVE { arg: m3 },
-            Opcode::CHANGE { arg: a3 },
-            Opcode::MOVE { arg: m4 },
-            Opcode::CHANGE { arg: -1 },
-        ] if m1 + m2 + m3 + m4 == 0 => Some(vec![
-            Opcode::MULTI { arg1: m1, arg2: a1 },
-            Opcode::MULTI {
-                arg1: m1 + m2,
-                arg2: a2,
-            },
-            Opcode::MULTI {
-                arg1: m1 + m2 + m3,
-                arg2: a3,
-            },
-            Opcode::SETTO { arg: 0 },
-        ]),
+    // [MOVE(K)CHANGE(L)MOVE(M)] or [MOVE(K)SETTO(L)MOVE(M)]
+    match *loop_body {
         [
             Opcode::MOVE { arg: m1 },
-            Opcode::CHANGE { arg: m2 },
+            Opcode::CHANGE { arg: m2, offset: 0 },
             Opcode::MOVE { arg: m3 },
         ] => Some(vec![Opcode::MOVINGCHANGE {
             arg1: m1,
@@ -370,7 +448,7 @@ pub fn find_transform_if_multi_loop(
         }]),
         [
             Opcode::MOVE { arg: m1 },
-            Opcode::SETTO { arg: m2 },
+            Opcode::SETTO { arg: m2, offset: 0 },
             Opcode::MOVE { arg: m3 },
         ] => Some(vec![Opcode::MOVINGSET {
             arg1: m1,
@@ -381,32 +459,297 @@ pub fn find_transform_if_multi_loop(
     }
 }
 
-pub fn loop_transformations(opcodes: Vec<Opcode>) -> Vec<Opcode> {
-    // ensure that the bracket opcodes are in a valid state
-    let opcodes = reset_bracket(opcodes);
+pub fn transform_multiplication_loop(loop_body: &[Opcode]) -> Option<Vec<Opcode>> {
+    // a loop with only MOVE and CHANGE that ends up where it started, e.g. [->+>+++<<] or [>+<-],
+    // adds a fixed amount to each cell it touches on every pass, so it can be replaced by a MULTI
+    // for each of those cells followed by clearing the counter
 
-    let lll = find_lowest_level_loops(&opcodes);
+    let mut offset = 0;
+    // the total change to each cell per pass, in the order the cells are first touched
+    let mut deltas: Vec<(i32, i32)> = vec![];
 
-    let mut regions_to_replace = vec![];
-    let mut replacements = vec![];
+    for &op in loop_body {
+        match op {
+            Opcode::MOVE { arg } => offset += arg,
+            Opcode::CHANGE {
+                arg,
+                offset: change_offset,
+            } => match deltas.iter_mut().find(|(x, _)| *x == offset + change_offset) {
+                Some((_, delta)) => *delta += arg,
+                None => deltas.push((offset + change_offset, arg)),
+            },
+            _ => return None,
+        }
+    }
 
-    for (x, y) in &lll {
-        let repl = find_transform_if_multi_loop(&opcodes, *x, *y);
+    if offset != 0 {
+        return None;
+    }
+
+    let step = deltas
+        .iter()
+        .find(|(x, _)| *x == 0)
+        .map_or(0, |(_, delta)| delta.rem_euclid(256));
+
+    // with wrapping cells the loop runs n times, where counter + n * step = 0 (mod 256), this has
+    // exactly one answer for any odd step, n = counter * -(1 / step), for an even step the loop
+    // might never end
+    if step % 2 == 0 {
+        return None;
+    }
+
+    let inverse_step = (1..256).find(|x| (x * step) % 256 == 1)?;
+    let factor = -inverse_step;
 
-        if let Some(replacement) = repl {
-            regions_to_replace.push((*x, *y));
-            replacements.push(replacement);
+    let mut replacement = deltas
+        .iter()
+        .filter(|(x, delta)| *x != 0 && delta.rem_euclid(256) != 0)
+        .map(|&(x, delta)| Opcode::MULTI {
+            arg1: x,
+            arg2: wrap_cell_value(delta * factor),
+        })
+        .collect::<Vec<_>>();
+
+    replacement.push(Opcode::SETTO { arg: 0, offset: 0 });
+
+    Some(replacement)
+}
+
+pub const fn triangle(value: u8) -> u8 {
+    // 1 + 2 + ... + value, wrapped around like a cell
+    (value as u32 * (value as u32 + 1) / 2) as u8
+}
+
+pub const fn carry(value: u8, limit: u8) -> (u8, u8) {
+    // what CARRY adds to the cell it moves into and the cell it carries into, like the chain of
+    // loops it stands in for it only ever takes the limit off once
+    let carried = (value >= limit) as u8;
+    (value.wrapping_sub(limit.wrapping_mul(carried)), carried)
+}
+
+pub const fn count_down(x: u8, y: u8) -> (u8, u8) {
+    // what COUNTDOWN leaves in its two cells, both are counted down once before they are checked
+    let (x, y) = (x.wrapping_sub(1), y.wrapping_sub(1));
+    let taken = if x < y { x } else { y };
+    (x - taken, y - taken)
+}
+
+pub const fn divmod(value: u8, divisor: u8, left: u8) -> Option<(u8, u8)> {
+    // what DIVMOD adds to the quotient and leaves in the cell the divisor is added to, or None if
+    // the loop it stands in for never ends, which is when there is nothing to take off each time
+    if value == 0 {
+        return Some((0, left));
+    }
+
+    // the first time around the divisor is added to whatever was there before
+    let left = left.wrapping_add(divisor);
+    let taken = if value < left { value } else { left };
+    let value = value - taken;
+
+    if value == 0 {
+        return Some((1, left - taken));
+    }
+
+    if divisor == 0 {
+        return None;
+    }
+
+    // after that the cell starts at zero every time, so the divisor is taken off whole every
+    // time but the last
+    let times = value.div_ceil(divisor);
+    let left = (times as u16 * divisor as u16 - value as u16) as u8;
+    Some((times.wrapping_add(1), left))
+}
+
+pub const fn wrap_cell_value(value: i32) -> i32 {
+    // the value the same as this one for a 8 bit cell, between -128 and 127
+    value as i8 as i32
+}
+
+pub fn loop_transformations(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // only loops without any loops inside of them can become MULTI or MOVING* opcodes
+    let tree = build_tree(&opcodes);
+
+    let tree = rewrite_loops(tree, &mut |body, span| {
+        let replacement = transform_multi_loop_body(&body_opcodes(body)?)?;
+
+        Some(
+            replacement
+                .into_iter()
+                .map(|op| Node::Op(SpannedOpcode::new(op, span)))
+                .collect(),
+        )
+    });
+
+    lower_tree(&tree)
+}
+
+fn zeroes_own_cell(body: &[Node]) -> bool {
+    // whether a loop body always leaves the cell the loop tests at zero, it has to end up where
+    // it started and the last thing to write to that cell has to clear it
+    if written_offsets(body).is_none() {
+        return false;
+    }
+
+    let mut offset = 0;
+    let mut is_zero = false;
+
+    for node in body {
+        match node {
+            Node::Op(op) => match op.op {
+                Opcode::MOVE { arg } => offset += arg,
+                Opcode::SETTO { arg, offset: x } if offset + x == 0 => is_zero = arg == 0,
+                Opcode::CHANGE { offset: x, .. } | Opcode::READCHAR { offset: x, .. }
+                    if offset + x == 0 =>
+                {
+                    is_zero = false;
+                }
+                Opcode::MULTI { arg1, .. }
+                | Opcode::PRODUCT { arg1, .. }
+                | Opcode::TRIANGLE { arg1, .. }
+                    if offset + arg1 == 0 =>
+                {
+                    is_zero = false;
+                }
+                Opcode::COPY { arg1, arg2 } => {
+                    // the cell under the data pointer only ends up zero if both were
+                    if offset + arg2 == 0 {
+                        is_zero = true;
+                    } else if offset == 0 || offset + arg1 == 0 {
+                        is_zero = false;
+                    }
+                }
+                Opcode::CARRY { arg1, arg2, .. }
+                | Opcode::AND { arg1, arg2 }
+                | Opcode::COUNTDOWN { arg1, arg2 }
+                | Opcode::DIVMOD {
+                    arg1, arg3: arg2, ..
+                } => {
+                    if offset == 0 {
+                        is_zero = true;
+                    } else if offset + arg1 == 0 || offset + arg2 == 0 {
+                        is_zero = false;
+                    }
+                }
+                _ => {}
+            },
+            // a loop on the same cell only stops once it is zero
+            Node::Loop { .. } | Node::If { .. } if offset == 0 => is_zero = true,
+            Node::Loop { body, .. } | Node::If { body, .. } => {
+                let written = written_offsets(body).unwrap_or_default();
+
+                if written.iter().any(|x| offset + x == 0) {
+                    is_zero = false;
+                }
+            }
         }
     }
 
-    replace_segments(opcodes, &regions_to_replace, &replacements)
+    is_zero
+}
+
+fn find_if_loops(block: Block) -> Block {
+    block
+        .into_iter()
+        .map(|node| match node {
+            Node::Loop { body, open, close } => {
+                let body = find_if_loops(body);
+
+                if zeroes_own_cell(&body) {
+                    Node::If { body, open, close }
+                } else {
+                    Node::Loop { body, open, close }
+                }
+            }
+            Node::If { body, open, close } => Node::If {
+                body: find_if_loops(body),
+                open,
+                close,
+            },
+            op => op,
+        })
+        .collect()
+}
+
+pub fn gen_if_ops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // a loop that leaves the cell it tests at zero every time through can only run once, e.g.
+    // [>+<[-]], so it is closed with an ENDIF instead of a jump back to the start
+    let tree = build_tree(&opcodes);
+    lower_tree(&find_if_loops(tree))
+}
+
+pub fn defer_pointer_moves(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // most moves only exist to get to a neighbouring cell, so instead of moving we keep track of
+    // how far we would have moved and give the opcodes that touch the tape an offset,
+    // e.g. >+>>-<. becomes CHANGE(1 at 1), CHANGE(-1 at 3), PUTCHAR(1 at 2), MOVE(2)
+    // anything that needs the data pointer to really be somewhere (loops, scans, MULTI, ...)
+    // gets the moves we have been holding back first
+
+    let mut output = Vec::with_capacity(opcodes.len());
+    // the moves held back, as how far they have gone in total after each one and its span
+    let mut pending: Vec<(i32, Span)> = vec![];
+
+    let flush = |output: &mut Vec<SpannedOpcode>, pending: &mut Vec<(i32, Span)>| {
+        if let Some(&(offset, span)) = pending.last()
+            && offset != 0
+        {
+            let span = pending[0].1.merge(span);
+            output.push(SpannedOpcode::new(Opcode::MOVE { arg: offset }, span));
+        }
+        pending.clear();
+    };
+
+    for opcode in opcodes {
+        let offset = pending.last().map_or(0, |x| x.0);
+
+        let op = match opcode.op {
+            Opcode::MOVE { arg } => {
+                pending.push((offset + arg, opcode.span));
+                continue;
+            }
+            Opcode::CHANGE { arg, offset: x } => Opcode::CHANGE {
+                arg,
+                offset: x + offset,
+            },
+            Opcode::SETTO { arg, offset: x } => Opcode::SETTO {
+                arg,
+                offset: x + offset,
+            },
+            Opcode::PUTCHAR { arg, offset: x } => Opcode::PUTCHAR {
+                arg,
+                offset: x + offset,
+            },
+            Opcode::READCHAR { arg, offset: x } => Opcode::READCHAR {
+                arg,
+                offset: x + offset,
+            },
+            op => {
+                flush(&mut output, &mut pending);
+                output.push(SpannedOpcode::new(op, opcode.span));
+                continue;
+            }
+        };
+
+        // the opcode also covers the first of the moves it stands in for that goes as far as its
+        // offset, if the cell is off the tape that is the move that would have left it
+        let first_far_enough = pending
+            .iter()
+            .find(|&&(x, _)| (offset < 0 && x <= offset) || (offset > 0 && x >= offset));
+        let span = first_far_enough.map_or(opcode.span, |&(_, x)| x.merge(opcode.span));
+
+        output.push(SpannedOpcode::new(op, span));
+    }
+
+    flush(&mut output, &mut pending);
+
+    reset_bracket(output)
 }
 
-pub fn replace_segments(
-    opcodes: Vec<Opcode>,
+pub fn replace_segments<T: Copy>(
+    opcodes: Vec<T>,
     from: &[(usize, usize)],
-    to: &[Vec<Opcode>],
-) -> Vec<Opcode> {
+    to: &[Vec<T>],
+) -> Vec<T> {
     // replace the segments in the opcodes with the new opcodes segments, this is inclusive
     // e.g. given [A, B, C] with from [(0, 1)] and to [[X, Y]] we get [X, Y, C]
 
@@ -453,7 +796,7 @@ pub fn replace_segments(
     result
 }
 
-pub fn reset_bracket(opcodes: Vec<Opcode>) -> Vec<Opcode> {
+pub fn reset_bracket(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
     let mut pos = 0;
     let mut ops = vec![];
     let mut loop_stack = vec![];
@@ -462,11 +805,11 @@ pub fn reset_bracket(opcodes: Vec<Opcode>) -> Vec<Opcode> {
         let curr = opcodes[pos];
 
         let mut emit = |x| {
-            ops.push(x);
+            ops.push(SpannedOpcode::new(x, curr.span));
             ops.len() - 1
         };
 
-        match curr {
+        match curr.op {
             JUMPIFZERO { .. } => {
                 let ins_pos = emit(JUMPIFZERO { arg: 0 });
                 loop_stack.push(ins_pos);
@@ -475,7 +818,12 @@ pub fn reset_bracket(opcodes: Vec<Opcode>) -> Vec<Opcode> {
                 let open_instr = loop_stack[loop_stack.len() - 1];
                 loop_stack.pop();
                 let close_instr = emit(JUMPIFNZERO { arg: open_instr });
-                ops[open_instr] = Opcode::JUMPIFZERO { arg: close_instr };
+                ops[open_instr].op = Opcode::JUMPIFZERO { arg: close_instr };
+            }
+            Opcode::ENDIF { .. } => {
+                let open_instr = loop_stack.pop().unwrap();
+                let close_instr = emit(Opcode::ENDIF { arg: open_instr });
+                ops[open_instr].op = Opcode::JUMPIFZERO { arg: close_instr };
             }
             _ => {
                 ops.push(curr);
@@ -487,3 +835,252 @@ pub fn reset_bracket(opcodes: Vec<Opcode>) -> Vec<Opcode> {
 
     ops
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bf_executor::BFExecuter;
+    use crate::opcode_interpreter::BFOpcodeInterpreter;
+    use crate::partial_eval::run_to_end;
+    use crate::passes::Pass;
+    use crate::state::TAPE_SIZE;
+
+    fn compile_with(source: &str, manager: &PassManager) -> Vec<SpannedOpcode> {
+        // checked against what the program printed before
+        let opcodes = manager.compile(source.to_string());
+        let expected = run_to_end(&PassManager::new().compile(source.to_string()), 10_000_000);
+
+        assert!(expected.is_some(), "{:?} doesn't finish", source);
+        assert_eq!(run_to_end(&opcodes, 10_000_000), expected, "{:?}", source);
+        opcodes
+    }
+
+    fn compile(source: &str, level: u8) -> Vec<Opcode> {
+        // with the if pass on top of the level
+        strip_spans(&compile_with(
+            source,
+            &PassManager::from_flags(level, &[Pass::GenIfs], &[]),
+        ))
+    }
+
+    fn compile_loops(source: &str) -> Vec<Opcode> {
+        // just the passes a loop needs to become MULTI
+        let passes = [Pass::CompressFoldable, Pass::LoopTransformations];
+        strip_spans(&compile_with(
+            source,
+            &PassManager::from_flags(0, &passes, &[]),
+        ))
+    }
+
+    fn count_loops(opcodes: &[Opcode]) -> usize {
+        opcodes
+            .iter()
+            .filter(|x| matches!(x, Opcode::JUMPIFZERO { .. }))
+            .count()
+    }
+
+    fn count_ifs(opcodes: &[Opcode]) -> usize {
+        opcodes
+            .iter()
+            .filter(|x| matches!(x, Opcode::ENDIF { .. }))
+            .count()
+    }
+
+    #[test]
+    fn loops_that_clear_their_cell_run_once() {
+        for level in 0..=2 {
+            assert_eq!(count_ifs(&compile("+++[>+<[-]]>.", level)), 1);
+            assert_eq!(count_ifs(&compile("+++[[-]>+<]>.", level)), 1);
+            // the inner loop only stops once the cell is zero
+            assert_eq!(count_ifs(&compile("+++[>++<[>+<-]]>>.", level)), 1);
+        }
+    }
+
+    #[test]
+    fn loops_that_might_run_again_are_kept() {
+        for level in 0..=2 {
+            assert_eq!(count_ifs(&compile("+++[>+<-]>.", level)), 0);
+            assert_eq!(count_ifs(&compile("+++[[-]+>+<-]>.", level)), 0);
+            // the body doesn't end up where it started
+            assert_eq!(count_ifs(&compile(">+>+[[-]<]>.", level)), 0);
+        }
+    }
+
+    #[test]
+    fn keeps_the_output() {
+        let sources = [
+            include_str!("../examples/hello_world.bf"),
+            include_str!("../examples/triangle.bf"),
+            include_str!("../examples/99.bf"),
+        ];
+
+        for source in sources {
+            for level in 0..=2 {
+                compile(source, level);
+            }
+        }
+    }
+
+    #[test]
+    fn multiplication_loops_become_multi() {
+        let opcodes = compile_loops("++[>+>+>+>+<<<<-]>.>.>.>.");
+        assert_eq!(
+            opcodes[1..6],
+            [
+                Opcode::MULTI { arg1: 1, arg2: 1 },
+                Opcode::MULTI { arg1: 2, arg2: 1 },
+                Opcode::MULTI { arg1: 3, arg2: 1 },
+                Opcode::MULTI { arg1: 4, arg2: 1 },
+                Opcode::SETTO { arg: 0, offset: 0 },
+            ]
+        );
+        assert_eq!(count_loops(&opcodes), 0);
+    }
+
+    #[test]
+    fn the_counter_can_be_changed_anywhere_in_the_loop() {
+        let opcodes = compile_loops("+++[>++<->>+++<<]>.>.");
+        assert_eq!(
+            opcodes[1..4],
+            [
+                Opcode::MULTI { arg1: 1, arg2: 2 },
+                Opcode::MULTI { arg1: 2, arg2: 3 },
+                Opcode::SETTO { arg: 0, offset: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_to_the_same_cell_are_added_up() {
+        let opcodes = compile_loops("++[>+>+<+<-]>.>.");
+        assert_eq!(
+            opcodes[1..4],
+            [
+                Opcode::MULTI { arg1: 1, arg2: 2 },
+                Opcode::MULTI { arg1: 2, arg2: 1 },
+                Opcode::SETTO { arg: 0, offset: 0 },
+            ]
+        );
+
+        // a cell that is changed and changed back isn't touched at all
+        let opcodes = compile_loops("++[>+>+<-<-]>.>.");
+        assert_eq!(
+            opcodes[1..3],
+            [
+                Opcode::MULTI { arg1: 2, arg2: 1 },
+                Opcode::SETTO { arg: 0, offset: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn odd_steps_run_a_known_number_of_times() {
+        // counting down by 3 from 3 runs once, so the factor is 1 / -3, which is -85 for a byte
+        let opcodes = compile_loops("+++[>+<---]>.");
+        assert_eq!(opcodes[1], Opcode::MULTI { arg1: 1, arg2: -85 });
+
+        // counting up by 3 from 1 wraps around after 85 times
+        let opcodes = compile_loops("+[>+<+++]>.");
+        assert_eq!(opcodes[1], Opcode::MULTI { arg1: 1, arg2: 85 });
+
+        for source in [
+            "+++++[>++<-----]>.",
+            "+++++++[>+>-<<+++++++]>.>.",
+            "-[>+<---------]>.",
+        ] {
+            assert_eq!(count_loops(&compile_loops(source)), 0, "{}", source);
+        }
+    }
+
+    #[test]
+    fn even_steps_are_kept_as_loops() {
+        // these might never end, depending on the counter
+        for source in ["++++[>+<--]>.", "++++[>+<++++]>.", "++++++[>+<-->++<]>."] {
+            assert_eq!(count_loops(&compile_loops(source)), 1, "{}", source);
+        }
+    }
+
+    fn compile_lazy(source: &str) -> Vec<Opcode> {
+        let passes = [Pass::CompressFoldable, Pass::DeferPointerMoves];
+        strip_spans(&compile_with(
+            source,
+            &PassManager::from_flags(0, &passes, &[]),
+        ))
+    }
+
+    #[test]
+    fn moves_become_offsets() {
+        assert_eq!(
+            compile_lazy(">+>>-<."),
+            [
+                Opcode::CHANGE { arg: 1, offset: 1 },
+                Opcode::CHANGE { arg: -1, offset: 3 },
+                Opcode::PUTCHAR { arg: 1, offset: 2 },
+                Opcode::MOVE { arg: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_are_made_before_a_loop_and_start_again_inside_it() {
+        // the body moves back to where it started, so nothing has to move before the ]
+        assert_eq!(
+            compile_lazy("+>+[->+<]>>+."),
+            [
+                Opcode::CHANGE { arg: 1, offset: 0 },
+                Opcode::CHANGE { arg: 1, offset: 1 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::JUMPIFZERO { arg: 6 },
+                Opcode::CHANGE { arg: -1, offset: 0 },
+                Opcode::CHANGE { arg: 1, offset: 1 },
+                Opcode::JUMPIFNZERO { arg: 3 },
+                Opcode::CHANGE { arg: 1, offset: 2 },
+                Opcode::PUTCHAR { arg: 1, offset: 2 },
+                Opcode::MOVE { arg: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_are_made_before_the_end_of_a_loop() {
+        assert_eq!(
+            compile_lazy("+>+>+<<[>]>."),
+            [
+                Opcode::CHANGE { arg: 1, offset: 0 },
+                Opcode::CHANGE { arg: 1, offset: 1 },
+                Opcode::CHANGE { arg: 1, offset: 2 },
+                Opcode::JUMPIFZERO { arg: 5 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::JUMPIFNZERO { arg: 3 },
+                Opcode::PUTCHAR { arg: 1, offset: 1 },
+                Opcode::MOVE { arg: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn leaving_the_tape_is_reported_at_the_move() {
+        let error_span = |level, source: &str| {
+            let manager = PassManager::from_flags(level, &[], &[]);
+            let mut interpreter =
+                BFOpcodeInterpreter::with_spans(&manager.compile(source.to_string()));
+            interpreter.execute();
+            interpreter.runtime_error().and_then(|x| x.span)
+        };
+
+        let far_right = format!("+{}+", ">".repeat(TAPE_SIZE));
+
+        for source in ["+<+.", "+>+<<<-", "+[-]<<.", ">>>>><+<<<<<<<.", &far_right] {
+            // the move that leaves the tape is folded in with the moves next to it, the span
+            // starts on that run of moves
+            let expected = error_span(0, source).unwrap();
+            let span = error_span(2, source).unwrap();
+            let run = &source[span.start..=expected.start];
+            assert!(
+                run.chars().all(|c| c == '<') || run.chars().all(|c| c == '>'),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::opcodes::Opcode;
+
+// the opcodes the way the VM runs them, one for every opcode so that the instruction pointer
+// means the same thing in both, the operands are only as wide as they need to be for a tape of
+// 30000 cells and jumps only store how far they go
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum PackedOp {
+    CHANGE { arg: u8, offset: i16 },
+    MOVE { arg: i16 },
+    PUTCHAR { arg: u32, offset: i16 },
+    READCHAR { arg: u32, offset: i16 },
+    JUMPIFZERO { forward: u32 },
+    JUMPIFNZERO { back: u32 },
+    SETTO { arg: u8, offset: i16 },
+    SCANBY { arg: i16 },
+    MULTI { arg1: i16, arg2: u8 },
+    MOVINGCHANGE { arg1: i16, arg2: u8, arg3: i16 },
+    MOVINGSET { arg1: i16, arg2: u8, arg3: i16 },
+    // the text is kept to the side, start is where it begins in there
+    PRINT { start: u32, len: u8 },
+    ENDIF,
+    PRODUCT { arg1: i16, arg2: i16, arg3: u8 },
+    TRIANGLE { arg1: i16, arg2: u8 },
+    COPY { arg1: i16, arg2: i16 },
+    CARRY { arg1: i16, arg2: i16, arg3: u8 },
+    AND { arg1: i16, arg2: i16 },
+    COUNTDOWN { arg1: i16, arg2: i16 },
+    DIVMOD { arg1: i16, arg2: i16, arg3: i16 },
+}
+
+// half the size of an Opcode, so twice as many of them fit in a cache line
+const _: () = assert!(size_of::<PackedOp>() == 8);
+
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct PackedProgram {
+    pub code: Vec<PackedOp>,
+    // the text of every PRINT, one after the other
+    pub text: Vec<u8>,
+}
+
+fn narrow(value: i32) -> i16 {
+    // anything further than the tape leaves it from any cell, so it goes just as far off it
+    value.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16
+}
+
+fn distance(from: usize, to: usize) -> u32 {
+    u32::try_from(from.abs_diff(to)).expect("Expected jumps to be shorter than 4G opcodes")
+}
+
+fn count(arg: usize) -> u32 {
+    u32::try_from(arg).expect("Expected fewer than 4G chars to be read or written at once")
+}
+
+impl PackedProgram {
+    #[must_use]
+    pub fn new(program: &[Opcode]) -> Self {
+        // the jumps have to point at their partners, as they are stored as the distance to them,
+        // and cell values are stored as the byte they wrap around to
+        let mut packed = Self::default();
+
+        for (ip, &op) in program.iter().enumerate() {
+            let packed_op = match op {
+                Opcode::CHANGE { arg, offset } => PackedOp::CHANGE {
+                    arg: arg as u8,
+                    offset: narrow(offset),
+                },
+                Opcode::MOVE { arg } => PackedOp::MOVE { arg: narrow(arg) },
+                Opcode::PUTCHAR { arg, offset } => PackedOp::PUTCHAR {
+                    arg: count(arg),
+                    offset: narrow(offset),
+                },
+                Opcode::READCHAR { arg, offset } => PackedOp::READCHAR {
+                    arg: count(arg),
+                    offset: narrow(offset),
+                },
+                Opcode::JUMPIFZERO { arg } => PackedOp::JUMPIFZERO {
+                    forward: distance(ip, arg),
+                },
+                Opcode::JUMPIFNZERO { arg } => PackedOp::JUMPIFNZERO {
+                    back: distance(ip, arg),
+                },
+                Opcode::SETTO { arg, offset } => PackedOp::SETTO {
+                    arg: arg as u8,
+                    offset: narrow(offset),
+                },
+                Opcode::SCANBY { arg } => PackedOp::SCANBY { arg: narrow(arg) },
+                Opcode::MULTI { arg1, arg2 } => PackedOp::MULTI {
+                    arg1: narrow(arg1),
+                    arg2: arg2 as u8,
+                },
+                Opcode::MOVINGCHANGE { arg1, arg2, arg3 } => PackedOp::MOVINGCHANGE {
+                    arg1: narrow(arg1),
+                    arg2: arg2 as u8,
+                    arg3: narrow(arg3),
+                },
+                Opcode::MOVINGSET { arg1, arg2, arg3 } => PackedOp::MOVINGSET {
+                    arg1: narrow(arg1),
+                    arg2: arg2 as u8,
+                    arg3: narrow(arg3),
+                },
+                Opcode::PRINT { bytes, len } => {
+                    let start = count(packed.text.len());
+                    packed.text.extend_from_slice(&bytes[..usize::from(len)]);
+                    PackedOp::PRINT { start, len }
+                }
+                Opcode::ENDIF { .. } => PackedOp::ENDIF,
+                Opcode::PRODUCT { arg1, arg2, arg3 } => PackedOp::PRODUCT {
+                    arg1: narrow(arg1),
+                    arg2: narrow(arg2),
+                    arg3: arg3 as u8,
+                },
+                Opcode::TRIANGLE { arg1, arg2 } => PackedOp::TRIANGLE {
+                    arg1: narrow(arg1),
+                    arg2: arg2 as u8,
+                },
+                Opcode::COPY { arg1, arg2 } => PackedOp::COPY {
+                    arg1: narrow(arg1),
+                    arg2: narrow(arg2),
+                },
+                Opcode::CARRY { arg1, arg2, arg3 } => PackedOp::CARRY {
+                    arg1: narrow(arg1),
+                    arg2: narrow(arg2),
+                    arg3: arg3 as u8,
+                },
+                Opcode::AND { arg1, arg2 } => PackedOp::AND {
+                    arg1: narrow(arg1),
+                    arg2: narrow(arg2),
+                },
+                Opcode::COUNTDOWN { arg1, arg2 } => PackedOp::COUNTDOWN {
+                    arg1: narrow(arg1),
+                    arg2: narrow(arg2),
+                },
+                Opcode::DIVMOD { arg1, arg2, arg3 } => PackedOp::DIVMOD {
+                    arg1: narrow(arg1),
+                    arg2: narrow(arg2),
+                    arg3: narrow(arg3),
+                },
+            };
+
+            packed.code.push(packed_op);
+        }
+
+        packed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::{PRINT_WIDTH, strip_spans};
+    use crate::passes::PassManager;
+    use crate::state::TAPE_SIZE;
+    use crate::validation::validate;
+
+    fn print(text: &[u8]) -> Opcode {
+        let mut bytes = [0; PRINT_WIDTH];
+        bytes[..text.len()].copy_from_slice(text);
+        Opcode::PRINT {
+            bytes,
+            len: text.len() as u8,
+        }
+    }
+
+    #[test]
+    fn jumps_are_stored_as_how_far_they_go() {
+        let program = [
+            Opcode::JUMPIFZERO { arg: 3 },
+            Opcode::CHANGE { arg: -1, offset: 0 },
+            Opcode::MOVE { arg: 1 },
+            Opcode::JUMPIFNZERO { arg: 0 },
+            Opcode::JUMPIFZERO { arg: 5 },
+            Opcode::ENDIF { arg: 4 },
+        ];
+        let packed = PackedProgram::new(&program);
+
+        assert_eq!(packed.code.len(), program.len());
+        assert_eq!(packed.code[0], PackedOp::JUMPIFZERO { forward: 3 });
+        assert_eq!(packed.code[3], PackedOp::JUMPIFNZERO { back: 3 });
+        assert_eq!(packed.code[4], PackedOp::JUMPIFZERO { forward: 1 });
+        assert_eq!(packed.code[5], PackedOp::ENDIF);
+    }
+
+    #[test]
+    fn cell_values_wrap_around_to_a_byte() {
+        let packed = PackedProgram::new(&[
+            Opcode::CHANGE { arg: -1, offset: 2 },
+            Opcode::SETTO {
+                arg: -56,
+                offset: 0,
+            },
+            Opcode::MULTI { arg1: 1, arg2: -3 },
+        ]);
+
+        assert_eq!(
+            packed.code[0],
+            PackedOp::CHANGE {
+                arg: 255,
+                offset: 2
+            }
+        );
+        assert_eq!(
+            packed.code[1],
+            PackedOp::SETTO {
+                arg: 200,
+                offset: 0
+            }
+        );
+        assert_eq!(packed.code[2], PackedOp::MULTI { arg1: 1, arg2: 253 });
+    }
+
+    #[test]
+    fn the_text_of_every_print_is_kept_to_the_side() {
+        let packed = PackedProgram::new(&[print(b"Hello"), Opcode::MOVE { arg: 1 }, print(b"!\n")]);
+
+        assert_eq!(packed.text, b"Hello!\n");
+        assert_eq!(packed.code[0], PackedOp::PRINT { start: 0, len: 5 });
+        assert_eq!(packed.code[2], PackedOp::PRINT { start: 5, len: 2 });
+    }
+
+    #[test]
+    fn valid_operands_are_packed_as_they_are() {
+        // everything validation lets through fits, so nothing is clamped or panics
+        let edge = TAPE_SIZE as i32 - 1;
+        let program = [
+            Opcode::MOVE { arg: -edge },
+            Opcode::SCANBY { arg: edge },
+            Opcode::PUTCHAR {
+                arg: u32::MAX as usize,
+                offset: edge,
+            },
+            Opcode::READCHAR {
+                arg: 1,
+                offset: -edge,
+            },
+            Opcode::MOVINGSET {
+                arg1: edge,
+                arg2: 255,
+                arg3: -edge,
+            },
+            Opcode::PRODUCT {
+                arg1: -edge,
+                arg2: edge,
+                arg3: -128,
+            },
+        ];
+        assert_eq!(validate(&program), Ok(()));
+
+        let edge = edge as i16;
+        assert_eq!(
+            PackedProgram::new(&program).code,
+            [
+                PackedOp::MOVE { arg: -edge },
+                PackedOp::SCANBY { arg: edge },
+                PackedOp::PUTCHAR {
+                    arg: u32::MAX,
+                    offset: edge
+                },
+                PackedOp::READCHAR {
+                    arg: 1,
+                    offset: -edge
+                },
+                PackedOp::MOVINGSET {
+                    arg1: edge,
+                    arg2: 255,
+                    arg3: -edge
+                },
+                PackedOp::PRODUCT {
+                    arg1: -edge,
+                    arg2: edge,
+                    arg3: 128
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn compiled_programs_are_packed_one_for_one() {
+        for level in 0..=3 {
+            let manager = PassManager::from_flags(level, &[], &[]);
+            let source = include_str!("../examples/mandelbrot.bf").to_string();
+            let opcodes = strip_spans(&manager.compile(source));
+
+            assert_eq!(validate(&opcodes), Ok(()));
+            assert_eq!(PackedProgram::new(&opcodes).code.len(), opcodes.len());
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use crate::opcodes::{
+    Opcode, SpannedOpcode, carry, count_down, divmod, reset_bracket, triangle, wrap_cell_value,
+};
+use crate::state::{ProgramState, TAPE_SIZE};
+
+// how many opcodes are run at compile time before giving up on reaching the first input
+const MAX_STEPS: usize = 1_000_000;
+
+struct Evaluator {
+    machine: ProgramState,
+    output: Vec<u8>,
+}
+
+impl Evaluator {
+    const fn new() -> Self {
+        Self {
+            machine: ProgramState::new(),
+            output: vec![],
+        }
+    }
+
+    fn cell_at(&self, offset: i32) -> Option<usize> {
+        let pos = (self.machine.dp as i32 + offset) as usize;
+        self.machine.is_valid_dp_location(pos).then_some(pos)
+    }
+
+    fn move_by(&mut self, arg: i32) -> Option<()> {
+        self.machine.dp = self.cell_at(arg)?;
+        Some(())
+    }
+
+    fn step(&mut self, opcodes: &[SpannedOpcode]) -> Option<()> {
+        // runs a single opcode exactly like BFOpcodeInterpreter does, None if it leaves the tape
+        match opcodes[self.machine.ip].op {
+            Opcode::CHANGE { arg, offset } => {
+                let pos = self.cell_at(offset)?;
+                self.machine.memory[pos] = self.machine.memory[pos].wrapping_add(arg as u8);
+            }
+            Opcode::MOVE { arg } => self.move_by(arg)?,
+            Opcode::PUTCHAR { arg, offset } => {
+                let pos = self.cell_at(offset)?;
+                let value = self.machine.memory[pos];
+                self.output.extend(std::iter::repeat_n(value, arg));
+            }
+            Opcode::PRINT { bytes, len } => self.output.extend(&bytes[..usize::from(len)]),
+            Opcode::READCHAR { .. } => unreachable!("Expected input to stop the evaluation"),
+            Opcode::JUMPIFZERO { arg } => {
+                if self.machine.memory[self.machine.dp] == 0 {
+                    self.machine.ip = arg;
+                }
+            }
+            Opcode::JUMPIFNZERO { arg } => {
+                if self.machine.memory[self.machine.dp] != 0 {
+                    self.machine.ip = arg;
+                }
+            }
+            Opcode::ENDIF { .. } => {}
+            Opcode::PRODUCT { arg1, arg2, arg3 } => {
+                let target = self.cell_at(arg1)?;
+                let other = self.cell_at(arg2)?;
+                let product = self.machine.memory[self.machine.dp]
+                    .wrapping_mul(self.machine.memory[other])
+                    .wrapping_mul(arg3 as u8);
+                self.machine.memory[target] = self.machine.memory[target].wrapping_add(product);
+            }
+            Opcode::TRIANGLE { arg1, arg2 } => {
+                let target = self.cell_at(arg1)?;
+                let sum = triangle(self.machine.memory[self.machine.dp]).wrapping_mul(arg2 as u8);
+                self.machine.memory[target] = self.machine.memory[target].wrapping_add(sum);
+            }
+            Opcode::COPY { arg1, arg2 } => {
+                let target = self.cell_at(arg1)?;
+                let temp = self.cell_at(arg2)?;
+                let value = self.machine.memory[self.machine.dp];
+                self.machine.memory[target] = self.machine.memory[target].wrapping_add(value);
+                self.machine.memory[self.machine.dp] = value.wrapping_add(self.machine.memory[temp]);
+                self.machine.memory[temp] = 0;
+            }
+            Opcode::CARRY { arg1, arg2, arg3 } => {
+                let target = self.cell_at(arg1)?;
+                let carried = self.cell_at(arg2)?;
+                let (x, y) = carry(self.machine.memory[self.machine.dp], arg3 as u8);
+                self.machine.memory[target] = self.machine.memory[target].wrapping_add(x);
+                self.machine.memory[carried] = self.machine.memory[carried].wrapping_add(y);
+                self.machine.memory[self.machine.dp] = 0;
+            }
+            Opcode::AND { arg1, arg2 } => {
+                let other = self.cell_at(arg1)?;
+                let target = self.cell_at(arg2)?;
+                if self.machine.memory[self.machine.dp] != 0 {
+                    self.machine.memory[target] = u8::from(self.machine.memory[other] != 0);
+                    self.machine.memory[other] = 0;
+                    self.machine.memory[self.machine.dp] = 0;
+                }
+            }
+            Opcode::COUNTDOWN { arg1, arg2 } => {
+                let x = self.cell_at(arg1)?;
+                let y = self.cell_at(arg2)?;
+                if self.machine.memory[self.machine.dp] != 0 {
+                    let memory = &mut self.machine.memory;
+                    (memory[x], memory[y]) = count_down(memory[x], memory[y]);
+                    memory[self.machine.dp] = 0;
+                }
+            }
+            Opcode::DIVMOD { arg1, arg2, arg3 } => {
+                let quotient = self.cell_at(arg1)?;
+                let divisor = self.cell_at(arg2)?;
+                let left = self.cell_at(arg3)?;
+                let memory = &mut self.machine.memory;
+
+                // a loop that never ends runs the same opcode again and again until we give up
+                let Some((x, y)) = divmod(memory[self.machine.dp], memory[divisor], memory[left])
+                else {
+                    return Some(());
+                };
+                memory[quotient] = memory[quotient].wrapping_add(x);
+                memory[left] = y;
+                memory[self.machine.dp] = 0;
+            }
+            Opcode::SETTO { arg, offset } => {
+                let pos = self.cell_at(offset)?;
+                self.machine.memory[pos] = arg as u8;
+            }
+            Opcode::SCANBY { arg } => {
+                while self.machine.memory[self.machine.dp] != 0 {
+                    self.move_by(arg)?;
+                }
+            }
+            Opcode::MULTI { arg1, arg2 } => {
+                let pos = (self.machine.dp as i32 + arg1).max(0) as usize;
+
+                if !self.machine.is_valid_dp_location(pos) {
+                    return None;
+                }
+
+                let product = self.machine.memory[self.machine.dp].wrapping_mul(arg2 as u8);
+                self.machine.memory[pos] = self.machine.memory[pos].wrapping_add(product);
+            }
+            Opcode::MOVINGCHANGE { arg1, arg2, arg3 } => {
+                while self.machine.memory[self.machine.dp] != 0 {
+                    self.move_by(arg1)?;
+                    let dp = self.machine.dp;
+                    self.machine.memory[dp] = self.machine.memory[dp].wrapping_add(arg2 as u8);
+                    self.move_by(arg3)?;
+                }
+            }
+            Opcode::MOVINGSET { arg1, arg2, arg3 } => {
+                while self.machine.memory[self.machine.dp] != 0 {
+                    self.move_by(arg1)?;
+                    let dp = self.machine.dp;
+                    self.machine.memory[dp] = arg2 as u8;
+                    self.move_by(arg3)?;
+                }
+            }
+        }
+
+        self.machine.ip += 1;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+pub fn run_to_end(opcodes: &[SpannedOpcode], max_steps: usize) -> Option<Vec<u8>> {
+    // runs a program that never reads input to the end and gives back what it printed, for the
+    // passes to be checked against, None if it leaves the tape or doesn't end in time
+    let opcodes = reset_bracket(opcodes.to_vec());
+    let mut evaluator = Evaluator::new();
+
+    for _ in 0..max_steps {
+        if evaluator.machine.ip >= opcodes.len() {
+            return Some(evaluator.output);
+        }
+
+        evaluator.step(&opcodes)?;
+    }
+
+    None
+}
+
+fn find_top_level(opcodes: &[SpannedOpcode]) -> Vec<bool> {
+    // whether each opcode is outside of every loop, only there can the program be cut in two
+    let mut depth = 0;
+
+    opcodes
+        .iter()
+        .map(|x| {
+            let top_level = depth == 0;
+
+            match x.op {
+                Opcode::JUMPIFZERO { .. } => depth += 1,
+                Opcode::JUMPIFNZERO { .. } | Opcode::ENDIF { .. } => depth -= 1,
+                _ => {}
+            }
+
+            top_level
+        })
+        .collect()
+}
+
+fn find_cut(opcodes: &[SpannedOpcode]) -> Option<(usize, usize)> {
+    // runs the program as far as it goes without input, and gives back the last opcode outside
+    // of every loop that it got to, with how many steps it took to get there
+    let top_level = find_top_level(opcodes);
+    let mut evaluator = Evaluator::new();
+    let mut cut = (0, 0);
+
+    for steps in 0..MAX_STEPS {
+        let ip = evaluator.machine.ip;
+
+        if ip >= opcodes.len() {
+            return Some((ip, steps));
+        }
+
+        if top_level[ip] {
+            cut = (ip, steps);
+        }
+
+        if matches!(opcodes[ip].op, Opcode::READCHAR { .. }) {
+            break;
+        }
+
+        // leaving the tape has to be left for the VM to report
+        evaluator.step(opcodes)?;
+    }
+
+    Some(cut)
+}
+
+pub fn evaluate_prefix(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // everything the program does before it first reads input is the same every time it runs,
+    // so it can be done now, leaving only its output and what it left on the tape, e.g.
+    // ++++++++[>++++++++<-]>+.,  becomes  SETTO 65, PUTCHAR, SETTO 0, SETTO 65 @1, MOVE 1, ,
+    let opcodes = reset_bracket(opcodes);
+
+    let Some((cut, steps)) = find_cut(&opcodes) else {
+        return opcodes;
+    };
+
+    if cut == 0 {
+        return opcodes;
+    }
+
+    let mut evaluator = Evaluator::new();
+
+    for _ in 0..steps {
+        let _ = evaluator.step(&opcodes);
+    }
+
+    let span = opcodes[0].span.merge(opcodes[cut - 1].span);
+    let mut output = vec![];
+    let mut emit = |op| output.push(SpannedOpcode::new(op, span));
+
+    // the output is printed from the first cell, as it is set to what it should be afterwards
+    for run in evaluator.output.chunk_by(|a, b| a == b) {
+        emit(Opcode::SETTO {
+            arg: wrap_cell_value(i32::from(run[0])),
+            offset: 0,
+        });
+        emit(Opcode::PUTCHAR {
+            arg: run.len(),
+            offset: 0,
+        });
+    }
+
+    // nothing can see the tape once the program has finished
+    if cut < opcodes.len() {
+        for pos in 0..TAPE_SIZE {
+            let value = evaluator.machine.memory[pos];
+
+            if value != 0 || (pos == 0 && !evaluator.output.is_empty()) {
+                emit(Opcode::SETTO {
+                    arg: wrap_cell_value(i32::from(value)),
+                    offset: pos as i32,
+                });
+            }
+        }
+
+        if evaluator.machine.dp != 0 {
+            emit(Opcode::MOVE {
+                arg: evaluator.machine.dp as i32,
+            });
+        }
+    }
+
+    output.extend_from_slice(&opcodes[cut..]);
+    reset_bracket(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::strip_spans;
+    use crate::passes::{Pass, PassManager};
+
+    fn compile(source: &str, level: u8) -> Vec<Opcode> {
+        let manager = PassManager::from_flags(level, &[Pass::EvaluatePrefix], &[]);
+        strip_spans(&manager.compile(source.to_string()))
+    }
+
+    #[test]
+    fn programs_without_input_become_their_output() {
+        let sources = [
+            include_str!("../examples/hello_world.bf"),
+            "++++++++[>++++++++<-]>+.+.<++++++++++.",
+        ];
+
+        for source in sources {
+            let expected = run_to_end(&PassManager::new().compile(source.to_string()), MAX_STEPS);
+
+            for level in 0..=2 {
+                let opcodes = compile(source, level);
+                assert!(
+                    opcodes.iter().all(|x| matches!(
+                        x,
+                        Opcode::SETTO { offset: 0, .. } | Opcode::PUTCHAR { offset: 0, .. }
+                    )),
+                    "{:?} at -O{}",
+                    source,
+                    level
+                );
+
+                let spanned = PassManager::from_flags(level, &[Pass::EvaluatePrefix], &[])
+                    .compile(source.to_string());
+                assert_eq!(run_to_end(&spanned, MAX_STEPS), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn stops_at_the_first_input() {
+        assert_eq!(
+            compile(">+++.,.", 0),
+            [
+                Opcode::SETTO { arg: 3, offset: 0 },
+                Opcode::PUTCHAR { arg: 1, offset: 0 },
+                Opcode::SETTO { arg: 0, offset: 0 },
+                Opcode::SETTO { arg: 3, offset: 1 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::READCHAR { arg: 1, offset: 0 },
+                Opcode::PUTCHAR { arg: 1, offset: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_loop_with_input_in_it_is_kept_whole() {
+        // the cut can only be made outside of every loop, so the loop starts over from its top
+        assert_eq!(
+            compile("++[>,.<-]", 1),
+            [
+                Opcode::SETTO { arg: 2, offset: 0 },
+                Opcode::JUMPIFZERO { arg: 7 },
+                Opcode::MOVE { arg: 1 },
+                Opcode::READCHAR { arg: 1, offset: 0 },
+                Opcode::PUTCHAR { arg: 1, offset: 0 },
+                Opcode::MOVE { arg: -1 },
+                Opcode::CHANGE { arg: -1, offset: 0 },
+                Opcode::JUMPIFNZERO { arg: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn leaving_the_tape_is_left_for_the_vm() {
+        assert_eq!(
+            compile("+.<+.", 1),
+            [
+                Opcode::CHANGE { arg: 1, offset: 0 },
+                Opcode::PUTCHAR { arg: 1, offset: 0 },
+                Opcode::MOVE { arg: -1 },
+                Opcode::CHANGE { arg: 1, offset: 0 },
+                Opcode::PUTCHAR { arg: 1, offset: 0 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,597 @@
+use crate::dataflow::{gen_print_ops, propagate_constants};
+use crate::idioms::gen_idiom_ops;
+use crate::opcodes::{
+    Opcode, SpannedOpcode, compress_foldable_opcodes, defer_pointer_moves, gen_clearing_ops,
+    gen_if_ops, gen_scanning_ops, loop_transformations, remove_dead_code,
+    remove_pointless_code_alteration, reset_bracket, strip_spans, tokenize_instructions,
+};
+use crate::partial_eval::evaluate_prefix;
+use crate::polynomial::fold_nested_loops;
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::Write;
+use std::str::FromStr;
+
+// the most times the pipeline is run over the code when iterating to a fixed point
+const MAX_ITERATIONS: usize = 16;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Pass {
+    CompressFoldable,
+    RemovePointless,
+    RemoveDeadCode,
+    GenClearing,
+    GenScanning,
+    LoopTransformations,
+    FoldNestedLoops,
+    GenIdioms,
+    GenIfs,
+    PropagateConstants,
+    DeferPointerMoves,
+    EvaluatePrefix,
+    GenPrinting,
+}
+
+impl Pass {
+    // the order the passes are run in, no matter which ones are turned on
+    pub const ALL: [Self; 13] = [
+        Self::CompressFoldable,
+        Self::RemovePointless,
+        Self::RemoveDeadCode,
+        Self::GenClearing,
+        Self::GenScanning,
+        Self::LoopTransformations,
+        Self::FoldNestedLoops,
+        Self::GenIdioms,
+        Self::GenIfs,
+        Self::PropagateConstants,
+        Self::DeferPointerMoves,
+        Self::EvaluatePrefix,
+        Self::GenPrinting,
+    ];
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::CompressFoldable => "fold",
+            Self::RemovePointless => "pointless",
+            Self::RemoveDeadCode => "dead-code",
+            Self::GenClearing => "clear",
+            Self::GenScanning => "scan",
+            Self::LoopTransformations => "loops",
+            Self::FoldNestedLoops => "nested",
+            Self::GenIdioms => "idioms",
+            Self::GenIfs => "if",
+            Self::PropagateConstants => "constants",
+            Self::DeferPointerMoves => "lazy-moves",
+            Self::EvaluatePrefix => "prefix",
+            Self::GenPrinting => "print",
+        }
+    }
+
+    #[must_use]
+    pub fn run(self, opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+        match self {
+            Self::CompressFoldable => compress_foldable_opcodes(opcodes),
+            Self::RemovePointless => remove_pointless_code_alteration(opcodes),
+            Self::RemoveDeadCode => remove_dead_code(opcodes),
+            Self::GenClearing => gen_clearing_ops(opcodes),
+            Self::GenScanning => gen_scanning_ops(opcodes),
+            Self::LoopTransformations => loop_transformations(opcodes),
+            Self::FoldNestedLoops => fold_nested_loops(opcodes),
+            Self::GenIdioms => gen_idiom_ops(opcodes),
+            Self::GenIfs => gen_if_ops(opcodes),
+            Self::PropagateConstants => propagate_constants(opcodes),
+            Self::DeferPointerMoves => defer_pointer_moves(opcodes),
+            Self::EvaluatePrefix => evaluate_prefix(opcodes),
+            Self::GenPrinting => gen_print_ops(opcodes),
+        }
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|x| x.name() == name).ok_or_else(|| {
+            let names = Self::ALL.map(Self::name).join(", ");
+            format!("unknown pass {:?}, the passes are {}", name, names)
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PassManager {
+    enabled: Vec<Pass>,
+    fixed_point: bool,
+}
+
+impl PassManager {
+    #[must_use]
+    pub const fn new() -> Self {
+        // nothing turned on, this is the same as -O0
+        Self {
+            enabled: vec![],
+            fixed_point: false,
+        }
+    }
+
+    #[must_use]
+    pub fn with_level(level: u8) -> Self {
+        // -O0 only tokenizes, -O1 folds runs of the same opcode, -O2 is the usual pipeline and
+        // -O3 adds the extra passes and keeps going until nothing changes
+        let mut manager = Self::new();
+
+        let passes: &[Pass] = match level {
+            0 => &[],
+            1 => &[Pass::CompressFoldable, Pass::RemovePointless],
+            2 => &[
+                Pass::CompressFoldable,
+                Pass::RemovePointless,
+                Pass::GenScanning,
+                Pass::LoopTransformations,
+                Pass::GenIdioms,
+                Pass::DeferPointerMoves,
+            ],
+            _ => &Pass::ALL,
+        };
+
+        for &pass in passes {
+            manager.enable(pass);
+        }
+
+        manager.fixed_point = level >= 3;
+        manager
+    }
+
+    #[must_use]
+    pub fn from_flags(level: u8, enabled: &[Pass], disabled: &[Pass]) -> Self {
+        // what the -O, --enable-pass and --disable-pass flags ask for, the passes are turned on
+        // and then off by name on top of the level
+        let mut manager = Self::with_level(level);
+
+        for &pass in enabled {
+            manager.enable(pass);
+        }
+
+        for &pass in disabled {
+            manager.disable(pass);
+        }
+
+        manager
+    }
+
+    pub fn enable(&mut self, pass: Pass) {
+        if !self.enabled.contains(&pass) {
+            self.enabled.push(pass);
+        }
+    }
+
+    pub fn disable(&mut self, pass: Pass) {
+        self.enabled.retain(|&x| x != pass);
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self, pass: Pass) -> bool {
+        self.enabled.contains(&pass)
+    }
+
+    #[must_use]
+    pub fn pipeline(&self) -> Vec<Pass> {
+        // the enabled passes, in the order they are run
+        Pass::ALL
+            .into_iter()
+            .filter(|&x| self.is_enabled(x))
+            .collect()
+    }
+
+    #[must_use]
+    pub fn run(&self, opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+        self.run_with_report(opcodes).0
+    }
+
+    #[must_use]
+    pub fn run_with_report(
+        &self,
+        opcodes: Vec<SpannedOpcode>,
+    ) -> (Vec<SpannedOpcode>, OptimizationReport) {
+        let mut report = OptimizationReport {
+            initial: OpcodeCounts::new(&opcodes),
+            passes: vec![],
+        };
+
+        let code = self.run_with_observer(opcodes, &mut |pass, iteration, before, after| {
+            report.passes.push(PassStats {
+                pass,
+                iteration,
+                before: OpcodeCounts::new(before),
+                after: OpcodeCounts::new(after),
+                loops_replaced: OpcodeCounts::loops_replaced(before, after),
+            });
+        });
+
+        (code, report)
+    }
+
+    pub fn run_with_observer<F>(
+        &self,
+        opcodes: Vec<SpannedOpcode>,
+        observer: &mut F,
+    ) -> Vec<SpannedOpcode>
+    where
+        F: FnMut(Pass, usize, &[SpannedOpcode], &[SpannedOpcode]),
+    {
+        // the observer sees the code before and after every pass that is run, along with which
+        // run through the pipeline it was (starting at 1), the jump targets are only fixed up
+        // at the very end, so they can be out of date in between passes
+        let pipeline = self.pipeline();
+        let iterations = if self.fixed_point { MAX_ITERATIONS } else { 1 };
+
+        let mut code = opcodes;
+
+        // one pass can leave behind something another pass can use, e.g. clearing [-+-] can
+        // make the loop around it a scan loop, so run the whole pipeline again until it settles
+        for iteration in 1..=iterations {
+            let before = strip_spans(&code);
+
+            for &pass in &pipeline {
+                let code_before = code.clone();
+                code = pass.run(code);
+
+                observer(pass, iteration, &code_before, &code);
+            }
+
+            if strip_spans(&code) == before {
+                break;
+            }
+        }
+
+        reset_bracket(code)
+    }
+
+    #[must_use]
+    pub fn compile(&self, program_code: String) -> Vec<SpannedOpcode> {
+        self.run(tokenize_instructions(program_code))
+    }
+
+    #[must_use]
+    pub fn compile_with_report(
+        &self,
+        program_code: String,
+    ) -> (Vec<SpannedOpcode>, OptimizationReport) {
+        self.run_with_report(tokenize_instructions(program_code))
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::with_level(2)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct OpcodeCounts {
+    pub opcodes: usize,
+    // the opcodes that loops get turned into, a multiplication loop is a group of MULTI with the
+    // SETTO that clears the counter, so it is counted once as a MULTI and not as a SETTO
+    pub setto: usize,
+    pub scanby: usize,
+    pub multi: usize,
+    pub movingchange: usize,
+    pub movingset: usize,
+}
+
+impl OpcodeCounts {
+    #[must_use]
+    pub fn new(opcodes: &[SpannedOpcode]) -> Self {
+        let mut counts = Self {
+            opcodes: opcodes.len(),
+            ..Self::default()
+        };
+
+        let mut last = None;
+
+        for opcode in opcodes {
+            match (last, opcode.op) {
+                (Some(Opcode::MULTI { .. }), Opcode::SETTO { .. } | Opcode::MULTI { .. }) => {}
+                (_, Opcode::SETTO { .. }) => counts.setto += 1,
+                (_, Opcode::SCANBY { .. }) => counts.scanby += 1,
+                (_, Opcode::MULTI { .. }) => counts.multi += 1,
+                (_, Opcode::MOVINGCHANGE { .. }) => counts.movingchange += 1,
+                (_, Opcode::MOVINGSET { .. }) => counts.movingset += 1,
+                _ => {}
+            }
+
+            last = Some(opcode.op);
+        }
+
+        counts
+    }
+
+    #[must_use]
+    pub fn loops_replaced(before: &[SpannedOpcode], after: &[SpannedOpcode]) -> Self {
+        // a loop that a pass turns into other opcodes gives them the span of the whole loop, so
+        // the loops it replaced are the ones with an opcode of that span afterwards, the SETTO
+        // after a group of MULTI has the same span and is counted with them, and a SETTO some
+        // other pass made out of a CHANGE isn't counted at all
+        let mut open = vec![];
+        let mut loops = HashSet::new();
+
+        for opcode in before {
+            match opcode.op {
+                Opcode::JUMPIFZERO { .. } => open.push(opcode.span),
+                Opcode::JUMPIFNZERO { .. } | Opcode::ENDIF { .. } => {
+                    if let Some(span) = open.pop() {
+                        loops.insert(span.merge(opcode.span));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut counts = Self {
+            opcodes: before.len().saturating_sub(after.len()),
+            ..Self::default()
+        };
+
+        for opcode in after {
+            if !loops.remove(&opcode.span) {
+                continue;
+            }
+
+            match opcode.op {
+                Opcode::SETTO { .. } => counts.setto += 1,
+                Opcode::SCANBY { .. } => counts.scanby += 1,
+                Opcode::MULTI { .. } => counts.multi += 1,
+                Opcode::MOVINGCHANGE { .. } => counts.movingchange += 1,
+                Opcode::MOVINGSET { .. } => counts.movingset += 1,
+                _ => {}
+            }
+        }
+
+        counts
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct PassStats {
+    pub pass: Pass,
+    // which run through the pipeline this was, starting at 1
+    pub iteration: usize,
+    pub before: OpcodeCounts,
+    pub after: OpcodeCounts,
+    // how many loops the pass turned into each loop opcode, along with how many opcodes it took
+    // out
+    pub loops_replaced: OpcodeCounts,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct OptimizationReport {
+    // the counts straight out of the tokenizer, which already turns [-] and [+] into SETTO
+    pub initial: OpcodeCounts,
+    pub passes: Vec<PassStats>,
+}
+
+impl OptimizationReport {
+    #[must_use]
+    pub fn final_counts(&self) -> OpcodeCounts {
+        self.passes.last().map_or(self.initial, |x| x.after)
+    }
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // e.g.
+        // pass        iter  before   after  SETTO  SCANBY  MULTI  MOVINGCHANGE  MOVINGSET
+        // tokenize       -       -    1024      3       0      0             0          0
+        // fold           1    1024     412      0       0      0             0          0
+        let mut table = format!(
+            "{:<12}{:>4}{:>8}{:>8}{:>7}{:>8}{:>7}{:>14}{:>11}\n",
+            "pass", "iter", "before", "after", "SETTO", "SCANBY", "MULTI", "MOVINGCHANGE", "MOVINGSET"
+        );
+
+        let initial = self.initial;
+        let _ = writeln!(
+            table,
+            "{:<12}{:>4}{:>8}{:>8}{:>7}{:>8}{:>7}{:>14}{:>11}",
+            "tokenize",
+            "-",
+            "-",
+            initial.opcodes,
+            initial.setto,
+            initial.scanby,
+            initial.multi,
+            initial.movingchange,
+            initial.movingset
+        );
+
+        for stats in &self.passes {
+            let replaced = stats.loops_replaced;
+            let _ = writeln!(
+                table,
+                "{:<12}{:>4}{:>8}{:>8}{:>7}{:>8}{:>7}{:>14}{:>11}",
+                stats.pass.name(),
+                stats.iteration,
+                stats.before.opcodes,
+                stats.after.opcodes,
+                replaced.setto,
+                replaced.scanby,
+                replaced.multi,
+                replaced.movingchange,
+                replaced.movingset
+            );
+        }
+
+        let last = self.final_counts();
+        let _ = writeln!(
+            table,
+            "{} opcodes down to {}, with {} SETTO, {} SCANBY, {} MULTI, {} MOVINGCHANGE and {} MOVINGSET",
+            initial.opcodes,
+            last.opcodes,
+            last.setto,
+            last.scanby,
+            last.multi,
+            last.movingchange,
+            last.movingset
+        );
+
+        write!(f, "{}", table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_level_has_its_passes() {
+        assert_eq!(PassManager::with_level(0).pipeline(), []);
+        assert_eq!(
+            PassManager::with_level(1).pipeline(),
+            [Pass::CompressFoldable, Pass::RemovePointless]
+        );
+        assert_eq!(
+            PassManager::with_level(2).pipeline(),
+            [
+                Pass::CompressFoldable,
+                Pass::RemovePointless,
+                Pass::GenScanning,
+                Pass::LoopTransformations,
+                Pass::GenIdioms,
+                Pass::DeferPointerMoves,
+            ]
+        );
+        assert_eq!(PassManager::with_level(3).pipeline(), Pass::ALL);
+        assert_eq!(PassManager::with_level(9), PassManager::with_level(3));
+        assert_eq!(PassManager::default(), PassManager::with_level(2));
+        assert_eq!(PassManager::new(), PassManager::with_level(0));
+
+        // only -O3 runs the pipeline more than once
+        assert!(!PassManager::with_level(2).fixed_point);
+        assert!(PassManager::with_level(3).fixed_point);
+    }
+
+    #[test]
+    fn flags_turn_passes_on_and_off_on_top_of_the_level() {
+        let manager = PassManager::from_flags(
+            1,
+            &[Pass::GenPrinting, Pass::GenClearing],
+            &[Pass::RemovePointless],
+        );
+        // in the order they are run, not the order they were given in
+        assert_eq!(
+            manager.pipeline(),
+            [Pass::CompressFoldable, Pass::GenClearing, Pass::GenPrinting]
+        );
+        assert!(!manager.fixed_point);
+
+        let manager = PassManager::from_flags(3, &[], &[Pass::GenScanning, Pass::EvaluatePrefix]);
+        assert!(!manager.is_enabled(Pass::GenScanning));
+        assert!(!manager.is_enabled(Pass::EvaluatePrefix));
+        assert_eq!(manager.pipeline().len(), Pass::ALL.len() - 2);
+        assert!(manager.fixed_point);
+
+        // turning a pass off wins over turning it on
+        let manager = PassManager::from_flags(0, &[Pass::GenIfs], &[Pass::GenIfs]);
+        assert_eq!(manager.pipeline(), []);
+    }
+
+    #[test]
+    fn passes_are_found_by_name() {
+        for pass in Pass::ALL {
+            assert_eq!(pass.name().parse::<Pass>(), Ok(pass));
+            assert_eq!(pass.to_string(), pass.name());
+        }
+
+        let error = "loop".parse::<Pass>().unwrap_err();
+        assert!(error.starts_with("unknown pass \"loop\", the passes are fold, pointless"));
+        assert!("Fold".parse::<Pass>().is_err());
+        assert!("".parse::<Pass>().is_err());
+    }
+
+    #[test]
+    fn the_fixed_point_stops_after_sixteen_runs() {
+        // every run the prefix pass gets through about one more of these loops, as it gives up
+        // after a million steps, so there is always more to do
+        let segment = format!("{}[-->--[-->--[-->+<]<]<]", "+".repeat(16));
+        let source = segment.repeat(40) + ".";
+
+        let manager = PassManager::with_level(3);
+        let mut iterations = 0;
+        let opcodes = manager
+            .run_with_observer(tokenize_instructions(source), &mut |_, iteration, _, _| {
+                iterations = iterations.max(iteration)
+            });
+
+        assert_eq!(iterations, MAX_ITERATIONS);
+        assert_ne!(
+            strip_spans(&manager.run(opcodes.clone())),
+            strip_spans(&opcodes)
+        );
+
+        // a program that settles stops once a run changes nothing
+        let mut iterations = 0;
+        let opcodes = manager.run_with_observer(
+            tokenize_instructions(String::from(",[->+<]>.")),
+            &mut |_, iteration, _, _| iterations = iterations.max(iteration),
+        );
+        assert!(iterations < MAX_ITERATIONS);
+        assert_eq!(
+            strip_spans(&manager.run(opcodes.clone())),
+            strip_spans(&opcodes)
+        );
+    }
+
+    #[test]
+    fn the_report_counts_the_loops_each_pass_replaces() {
+        let manager = PassManager::from_flags(0, &[Pass::LoopTransformations], &[]);
+        let (opcodes, report) = manager.compile_with_report(String::from("[-]>[->+<]"));
+
+        // the tokenizer already makes the first loop a SETTO, the MULTI and its SETTO are one loop
+        let counts = |setto, multi, opcodes| OpcodeCounts {
+            opcodes,
+            setto,
+            multi,
+            ..OpcodeCounts::default()
+        };
+        assert_eq!(report.initial, counts(1, 0, 8));
+        assert_eq!(report.passes[0].loops_replaced, counts(0, 1, 4));
+        assert_eq!(report.final_counts(), counts(1, 1, opcodes.len()));
+
+        let passes = [Pass::CompressFoldable, Pass::GenClearing, Pass::GenScanning];
+        let (_, report) = PassManager::from_flags(0, &passes, &[])
+            .compile_with_report(String::from("+[-+-]+[>]"));
+        let replaced = report.passes.iter().map(|x| (x.pass, x.loops_replaced));
+        assert_eq!(
+            replaced.collect::<Vec<_>>(),
+            [
+                (Pass::CompressFoldable, counts(0, 0, 2)),
+                (Pass::GenClearing, counts(1, 0, 2)),
+                (
+                    Pass::GenScanning,
+                    OpcodeCounts {
+                        opcodes: 2,
+                        scanby: 1,
+                        ..OpcodeCounts::default()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_report_only_counts_loops() {
+        // the constants pass turns the CHANGE after a clear into SETTO, which aren't loops
+        let manager = PassManager::from_flags(0, &[Pass::PropagateConstants], &[]);
+        let (opcodes, report) = manager.compile_with_report(String::from(",[-]+++."));
+
+        assert!(strip_spans(&opcodes).contains(&Opcode::SETTO { arg: 3, offset: 0 }));
+        assert_eq!(report.passes[0].loops_replaced.setto, 0);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::ir::{Block, Node, body_opcodes, build_tree, lower_tree, rewrite_loops};
+use crate::opcodes::{Opcode, SpannedOpcode, wrap_cell_value};
+use crate::span::Span;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct Affine {
+    // a constant plus a multiple of some of the cells as they were at the start of the loop body,
+    // everything wraps around like the cells do
+    constant: u8,
+    terms: BTreeMap<i32, u8>,
+}
+
+impl Affine {
+    const fn constant(value: u8) -> Self {
+        Self {
+            constant: value,
+            terms: BTreeMap::new(),
+        }
+    }
+
+    fn cell(offset: i32) -> Self {
+        Self {
+            constant: 0,
+            terms: BTreeMap::from([(offset, 1)]),
+        }
+    }
+
+    fn add(&mut self, other: &Self, factor: u8) {
+        self.constant = self.constant.wrapping_add(other.constant.wrapping_mul(factor));
+
+        for (&offset, &coefficient) in &other.terms {
+            let term = self.terms.entry(offset).or_insert(0);
+            *term = term.wrapping_add(coefficient.wrapping_mul(factor));
+
+            if *term == 0 {
+                self.terms.remove(&offset);
+            }
+        }
+    }
+
+    fn substitute(&self, values: &BTreeMap<i32, u8>) -> Self {
+        // the same expression with some of the cells known
+        let mut output = Self::constant(self.constant);
+
+        for (&offset, &coefficient) in &self.terms {
+            match values.get(&offset) {
+                Some(&value) => output.add(&Self::constant(value), coefficient),
+                None => output.add(&Self::cell(offset), coefficient),
+            }
+        }
+
+        output
+    }
+
+    fn as_constant(&self) -> Option<u8> {
+        self.terms.is_empty().then_some(self.constant)
+    }
+}
+
+fn run_body(body: &[Opcode]) -> Option<BTreeMap<i32, Affine>> {
+    // what one pass through a loop body does to every cell it touches, in terms of the cells
+    // at the start of it, only for bodies that end up where they started
+    let mut cells: BTreeMap<i32, Affine> = BTreeMap::new();
+    let mut pos = 0;
+
+    let value = |cells: &BTreeMap<i32, Affine>, offset: i32| {
+        cells
+            .get(&offset)
+            .cloned()
+            .unwrap_or_else(|| Affine::cell(offset))
+    };
+
+    for &op in body {
+        match op {
+            Opcode::MOVE { arg } => pos += arg,
+            Opcode::CHANGE { arg, offset } => {
+                let mut new_value = value(&cells, pos + offset);
+                new_value.add(&Affine::constant(arg as u8), 1);
+                cells.insert(pos + offset, new_value);
+            }
+            Opcode::SETTO { arg, offset } => {
+                cells.insert(pos + offset, Affine::constant(arg as u8));
+            }
+            Opcode::MULTI { arg1, arg2 } => {
+                let source = value(&cells, pos);
+                let mut new_value = value(&cells, pos + arg1);
+                new_value.add(&source, arg2 as u8);
+                cells.insert(pos + arg1, new_value);
+            }
+            _ => return None,
+        }
+    }
+
+    (pos == 0).then_some(cells)
+}
+
+fn closed_form(body: &[Node], span: Span) -> Option<Block> {
+    // a loop that counts its first cell down by one, and otherwise only adds multiples of cells
+    // it doesn't change to other cells, can be worked out in one go, e.g. the nested
+    // multiplication [>[->+>+<<]>>[-<<+>>]<<<-] adds the first cell times the second to the
+    // third, and [[->+>+<<]>>[-<<+>>]<<-] adds 1 + 2 + ... + the first cell to the second
+    let ops = body_opcodes(body)?;
+    let cells = run_body(&ops)?;
+
+    // cells that are set to a constant every time have that value from the second time on, if
+    // anything depends on them before that, the first pass has to be run on its own
+    let constants = cells
+        .iter()
+        .filter(|&(&x, _)| x != 0)
+        .filter_map(|(&x, value)| Some((x, value.as_constant()?)))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut counter = Affine::cell(0);
+    counter.add(&Affine::constant(1), u8::MAX);
+
+    let counter_value = cells.get(&0)?;
+
+    if counter_value.substitute(&constants) != counter {
+        return None;
+    }
+
+    let mut needs_first_pass = *counter_value != counter;
+    let mut updates = vec![];
+
+    for (&x, value) in &cells {
+        if x == 0 || constants.contains_key(&x) {
+            continue;
+        }
+
+        let substituted = value.substitute(&constants);
+        needs_first_pass |= substituted != *value;
+
+        // what gets added to the cell every pass, the cell itself has to be left out of it
+        let mut delta = substituted.clone();
+        delta.add(&Affine::cell(x), u8::MAX);
+
+        if delta.terms.keys().any(|&y| y == x) {
+            return None;
+        }
+
+        updates.push((x, delta));
+    }
+
+    // anything the updates read other than the counter has to stay the same the whole time
+    let is_unchanged = |y: i32| {
+        !cells.contains_key(&y)
+            || updates
+                .iter()
+                .any(|(x, delta)| *x == y && *delta == Affine::constant(0))
+    };
+
+    if updates
+        .iter()
+        .any(|(_, delta)| delta.terms.keys().any(|&y| y != 0 && !is_unchanged(y)))
+    {
+        return None;
+    }
+
+    let mut replacement = vec![];
+
+    for (x, delta) in &updates {
+        for (&y, &factor) in &delta.terms {
+            if y == 0 {
+                // the counter is c, c - 1, ..., 1 over the passes, which adds up to c(c + 1) / 2
+                replacement.push(Opcode::TRIANGLE {
+                    arg1: *x,
+                    arg2: wrap_cell_value(i32::from(factor)),
+                });
+            } else {
+                replacement.push(Opcode::PRODUCT {
+                    arg1: *x,
+                    arg2: y,
+                    arg3: wrap_cell_value(i32::from(factor)),
+                });
+            }
+        }
+
+        if delta.constant != 0 {
+            replacement.push(Opcode::MULTI {
+                arg1: *x,
+                arg2: wrap_cell_value(i32::from(delta.constant)),
+            });
+        }
+    }
+
+    for (&x, &value) in &constants {
+        replacement.push(Opcode::SETTO {
+            arg: wrap_cell_value(i32::from(value)),
+            offset: x,
+        });
+    }
+
+    replacement.push(Opcode::SETTO { arg: 0, offset: 0 });
+
+    let mut replacement = replacement
+        .into_iter()
+        .map(|x| Node::Op(SpannedOpcode::new(x, span)))
+        .collect::<Block>();
+
+    if needs_first_pass {
+        let mut first_pass = body.to_vec();
+        first_pass.extend(replacement);
+        replacement = first_pass;
+    }
+
+    // a loop that never runs doesn't set anything, so setting the constants has to wait until
+    // we know it does, after which the body runs once at most
+    if needs_first_pass || !constants.is_empty() {
+        return Some(vec![Node::If {
+            body: replacement,
+            open: span,
+            close: span,
+        }]);
+    }
+
+    Some(replacement)
+}
+
+pub fn fold_nested_loops(opcodes: Vec<SpannedOpcode>) -> Vec<SpannedOpcode> {
+    // once the loops inside a loop are gone, the loop around them might be simple enough to
+    // work out in one go as well, so this works from the innermost loops outwards
+    let tree = build_tree(&opcodes);
+
+    let tree = rewrite_loops(tree, &mut |body, span| closed_form(body, span));
+
+    lower_tree(&tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::strip_spans;
+    use crate::partial_eval::run_to_end;
+    use crate::passes::{Pass, PassManager};
+
+    fn compile(source: &str) -> Vec<Opcode> {
+        // the nested pass needs the inner loops to be MULTI already, so it runs on top of -O2,
+        // checked against what the program printed before
+        let manager = PassManager::from_flags(2, &[Pass::FoldNestedLoops], &[]);
+        let opcodes = manager.compile(source.to_string());
+        let expected = run_to_end(&PassManager::new().compile(source.to_string()), 10_000_000);
+
+        assert!(expected.is_some(), "{:?} doesn't finish", source);
+        assert_eq!(run_to_end(&opcodes, 10_000_000), expected, "{:?}", source);
+        strip_spans(&opcodes)
+    }
+
+    #[test]
+    fn nested_multiplication_becomes_a_product() {
+        let opcodes = compile("+++++>+++++++<[>[->+>+<<]>>[-<<+>>]<<<-]>>.");
+        assert!(opcodes.contains(&Opcode::PRODUCT {
+            arg1: 2,
+            arg2: 1,
+            arg3: 1
+        }));
+        assert!(
+            !opcodes
+                .iter()
+                .any(|x| matches!(x, Opcode::JUMPIFNZERO { .. }))
+        );
+    }
+
+    #[test]
+    fn counting_down_into_a_sum_becomes_a_triangle() {
+        let opcodes = compile("++++++++++[[->+>+<<]>>[-<<+>>]<<-]>.");
+        assert!(opcodes.contains(&Opcode::TRIANGLE { arg1: 1, arg2: 1 }));
+    }
+
+    #[test]
+    fn wraps_around_like_the_cells() {
+        compile("++++++++++++++++++++>+++++++++++++++++++++<[>[->+>+<<]>>[-<<+>>]<<<-]>>.");
+        compile("+++++++++++++++++++++++++++++[[->+>+<<]>>[-<<+>>]<<-]>.");
+        compile("-[[->+>+<<]>>[-<<+>>]<<-]>.");
+    }
+
+    #[test]
+    fn cells_set_in_the_body_need_a_first_pass() {
+        // the cell at 2 is only 5 from the second time around, so the first pass runs on its own
+        let opcodes = compile("+++>++<[>>[-]+++++<[->>+<<]<-]>>>.<.");
+        assert!(opcodes.iter().any(|x| matches!(x, Opcode::ENDIF { .. })));
+    }
+
+    #[test]
+    fn loops_that_change_what_they_read_are_kept() {
+        // the second cell is doubled every time, which isn't a product
+        let opcodes = compile("+++>+<[>[->++<]>[-<+>]<<-]>.");
+        assert!(!opcodes.iter().any(|x| matches!(x, Opcode::PRODUCT { .. })));
+    }
+
+    #[test]
+    fn keeps_the_output() {
+        compile(include_str!("../examples/triangle.bf"));
+        compile(include_str!("../examples/99.bf"));
+    }
+}
@@ -0,0 +1,259 @@
+use memchr::{memchr, memrchr};
+
+// the number of cells looked at in one go by the strided scans, one bit each in a mask
+const BLOCK: usize = 64;
+
+// scans by more than this land on too few cells in a block to be worth it
+const MAX_STRIDE: usize = 16;
+
+#[cfg(not(target_arch = "x86_64"))]
+const fn zero_bytes(word: u64) -> u64 {
+    // the low bit of every byte of the word that is zero, unlike the usual has-a-zero trick this
+    // is exact, so a zero doesn't make the byte above it look like one too
+    let low = 0x7f7f_7f7f_7f7f_7f7f;
+    (!(((word & low) + low) | word | low)) >> 7
+}
+
+#[cfg(target_arch = "x86_64")]
+fn zero_mask(block: &[u8]) -> u64 {
+    // a bit for every zero cell in the block, sixteen cells to a vector compare, SSE2 is always
+    // there on x86_64
+    use std::arch::x86_64::{
+        __m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_setzero_si128,
+    };
+
+    block.chunks_exact(16).enumerate().fold(0, |mask, (i, chunk)| {
+        // SAFETY: the chunk is 16 bytes long, and the load doesn't need them to be aligned
+        let found = unsafe {
+            let cells = _mm_loadu_si128(chunk.as_ptr().cast::<__m128i>());
+            _mm_movemask_epi8(_mm_cmpeq_epi8(cells, _mm_setzero_si128()))
+        };
+        mask | (u64::from(found as u16) << (i * 16))
+    })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn zero_mask(block: &[u8]) -> u64 {
+    // a bit for every zero cell in the block, eight cells at a time, the multiply gathers the
+    // low bit of every byte into the top byte
+    block.chunks_exact(8).enumerate().fold(0, |mask, (i, chunk)| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(chunk);
+        let zeros = zero_bytes(u64::from_le_bytes(bytes));
+        mask | ((zeros.wrapping_mul(0x0102_0408_1020_4080) >> 56) << (i * 8))
+    })
+}
+
+const fn step_masks() -> [u64; MAX_STRIDE + 1] {
+    // a bit for every step-th cell in a block, starting from the first one, for every step
+    let mut masks = [0; MAX_STRIDE + 1];
+    let mut step = 1;
+
+    while step <= MAX_STRIDE {
+        let mut lane = 0;
+
+        while lane < BLOCK {
+            masks[step] |= 1 << lane;
+            lane += step;
+        }
+
+        step += 1;
+    }
+
+    masks
+}
+
+const STEP_MASKS: [u64; MAX_STRIDE + 1] = step_masks();
+
+const fn block_stride(step: usize) -> usize {
+    // how far it is from the first cell of a block to the first cell the scan lands on past it,
+    // starting the next block there keeps the scan lined up with the same mask
+    ((BLOCK - 1) / step + 1) * step
+}
+
+fn find_zero_forward(memory: &[u8], start: usize, step: usize) -> Option<usize> {
+    // most scans are short, those are over before a block would be worth setting up
+    let lanes = STEP_MASKS[step];
+    let mut pos = start;
+
+    for _ in 0..(BLOCK / step) {
+        if memory[pos] == 0 {
+            return Some(pos);
+        }
+
+        pos += step;
+
+        if pos >= memory.len() {
+            return None;
+        }
+    }
+
+    while pos + BLOCK <= memory.len() {
+        let found = zero_mask(&memory[pos..(pos + BLOCK)]) & lanes;
+
+        if found != 0 {
+            return Some(pos + found.trailing_zeros() as usize);
+        }
+
+        pos += block_stride(step);
+    }
+
+    find_zero_stepping(memory, pos, step as i32)
+}
+
+fn find_zero_backward(memory: &[u8], start: usize, step: usize) -> Option<usize> {
+    // the same as going forward, with the block ending on the cell instead of starting on it
+    let lanes = STEP_MASKS[step].reverse_bits();
+    let mut pos = start;
+
+    for _ in 0..(BLOCK / step) {
+        if memory[pos] == 0 {
+            return Some(pos);
+        }
+
+        pos = pos.checked_sub(step)?;
+    }
+
+    while pos + 1 >= BLOCK {
+        let first = pos + 1 - BLOCK;
+        let found = zero_mask(&memory[first..=pos]) & lanes;
+
+        if found != 0 {
+            return Some(first + (BLOCK - 1 - found.leading_zeros() as usize));
+        }
+
+        pos = pos.checked_sub(block_stride(step))?;
+    }
+
+    find_zero_stepping(memory, pos, -(step as i32))
+}
+
+fn find_zero_stepping(memory: &[u8], start: usize, step: i32) -> Option<usize> {
+    // one cell at a time, for the steps the faster searches don't cover and what is left over
+    let mut pos = start;
+
+    while pos < memory.len() {
+        if memory[pos] == 0 {
+            return Some(pos);
+        }
+
+        // going left of the first cell wraps around to past the last one
+        pos = pos.wrapping_add_signed(step as isize);
+    }
+
+    None
+}
+
+// kept out of line so that the VM's loop stays small enough to keep what it needs in registers
+#[inline(never)]
+pub fn find_zero(memory: &[u8], start: usize, step: i32) -> Option<usize> {
+    // where a SCANBY starting on the cell at start stops, the first zero cell in steps of step,
+    // or None if it runs off either end of the memory first
+    if start >= memory.len() {
+        return None;
+    }
+
+    let stride = step.unsigned_abs() as usize;
+
+    match step {
+        1 => memchr(0, &memory[start..]).map(|x| start + x),
+        -1 => memrchr(0, &memory[..=start]),
+        _ if stride > MAX_STRIDE || stride == 0 => find_zero_stepping(memory, start, step),
+        _ if step > 0 => find_zero_forward(memory, start, stride),
+        _ => find_zero_backward(memory, start, stride),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_find_zero(memory: &[u8], start: usize, step: i32) -> Option<usize> {
+        // the scan as BF runs it, one step at a time
+        let mut pos = start as isize;
+
+        while 0 <= pos && pos < memory.len() as isize {
+            if memory[pos as usize] == 0 {
+                return Some(pos as usize);
+            }
+
+            pos += step as isize;
+        }
+
+        None
+    }
+
+    fn check(memory: &[u8]) {
+        for stride in 1..=(MAX_STRIDE as i32 + 2) {
+            for step in [stride, -stride] {
+                for start in 0..memory.len() {
+                    assert_eq!(
+                        find_zero(memory, start, step),
+                        naive_find_zero(memory, start, step),
+                        "start {} step {} on {} cells",
+                        start,
+                        step,
+                        memory.len()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn finds_a_zero_at_the_edges_of_a_block() {
+        // the last cell a short scan looks at, and the first and last cells of the blocks past it
+        for len in [1, 63, 64, 65, 128, 200] {
+            check(&vec![1; len]);
+
+            for zero in [0, 1, 62, 63, 64, 65, 127, 128, 129, 191, 192, 199] {
+                if zero < len {
+                    let mut memory = vec![1; len];
+                    memory[zero] = 0;
+                    check(&memory);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn finds_the_nearest_of_many_zeros() {
+        // a tape with a zero in about every twentieth cell, in no particular pattern
+        let mut seed = 12345u32;
+        let memory = (0..300)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                u8::from(!(seed >> 16).is_multiple_of(20))
+            })
+            .collect::<Vec<_>>();
+
+        assert!(memory.contains(&0));
+        check(&memory);
+    }
+
+    #[test]
+    fn runs_off_the_ends_of_the_tape() {
+        let memory = vec![1; 100];
+        assert_eq!(find_zero(&memory, 100, 1), None);
+        assert_eq!(find_zero(&memory, 99, 4), None);
+        assert_eq!(find_zero(&memory, 0, -4), None);
+        assert_eq!(find_zero(&memory, 3, -16), None);
+    }
+
+    #[test]
+    fn masks_every_zero_in_a_block() {
+        for zero in 0..BLOCK {
+            let mut block = [7; BLOCK];
+            block[zero] = 0;
+            assert_eq!(zero_mask(&block), 1 << zero);
+        }
+
+        assert_eq!(zero_mask(&[0; BLOCK]), u64::MAX);
+        assert_eq!(zero_mask(&[1; BLOCK]), 0);
+        // a zero cell doesn't make the cell above it look like a zero
+        let mut block = [1; BLOCK];
+        block[10] = 0;
+        block[11] = 0x80;
+        assert_eq!(zero_mask(&block), 1 << 10);
+    }
+}
@@ -60,37 +60,33 @@ impl BFExecuter for BFSimpleInterpreter {
                 '.' => {
                     self.write_char();
                 }
-                '[' => {
-                    if self.machine.memory[self.machine.dp] == 0 {
-                        let mut depth = 1;
-                        while depth != 0 {
-                            self.machine.ip += 1;
-                            match *self.program.get(self.machine.ip).unwrap() {
-                                '[' => {
-                                    depth += 1;
-                                }
-                                ']' => {
-                                    depth -= 1;
-                                }
-                                _ => {}
+                '[' if self.machine.memory[self.machine.dp] == 0 => {
+                    let mut depth = 1;
+                    while depth != 0 {
+                        self.machine.ip += 1;
+                        match *self.program.get(self.machine.ip).unwrap() {
+                            '[' => {
+                                depth += 1;
                             }
+                            ']' => {
+                                depth -= 1;
+                            }
+                            _ => {}
                         }
                     }
                 }
-                ']' => {
-                    if self.machine.memory[self.machine.dp] != 0 {
-                        let mut depth = 1;
-                        while depth != 0 {
-                            self.machine.ip -= 1;
-                            match *self.program.get(self.machine.ip).unwrap() {
-                                ']' => {
-                                    depth += 1;
-                                }
-                                '[' => {
-                                    depth -= 1;
-                                }
-                                _ => {}
+                ']' if self.machine.memory[self.machine.dp] != 0 => {
+                    let mut depth = 1;
+                    while depth != 0 {
+                        self.machine.ip -= 1;
+                        match *self.program.get(self.machine.ip).unwrap() {
+                            ']' => {
+                                depth += 1;
+                            }
+                            '[' => {
+                                depth -= 1;
                             }
+                            _ => {}
                         }
                     }
                 }
@@ -109,8 +105,7 @@ impl BFExecuter for BFSimpleInterpreter {
     }
 
     fn write_char(&mut self) {
-        let mut byte = [0_u8];
-        byte[0] = (self.machine.memory[self.machine.dp] & 0xFF) as u8;
+        let byte = [self.machine.memory[self.machine.dp]];
         self.stdout
             .write_all(&byte)
             .expect("Wrote out the char correctly");
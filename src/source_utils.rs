@@ -1,3 +1,6 @@
+use crate::span::{LineIndex, SourcePosition};
+use std::fmt::Write;
+
 pub fn remove_no_coding_symbols(program_code: String) -> String {
     // removes anything that isn't a BF statement
     let valid_chars = ['+', '-', '<', '>', ',', '.', '[', ']'];
@@ -9,36 +12,118 @@ pub fn remove_no_coding_symbols(program_code: String) -> String {
     program_code
 }
 
-pub fn check_program_brackets(program_code: &str) -> bool {
-    // checks if the source has malformed brackets e.g. []][ or [[] or [[][][
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum BracketErrorKind {
+    UnmatchedOpen,
+    UnmatchedClose,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct BracketDiagnostic {
+    pub kind: BracketErrorKind,
+    // byte offset of the offending bracket in the source
+    pub offset: usize,
+    pub position: SourcePosition,
+}
+
+impl BracketDiagnostic {
+    #[must_use]
+    pub fn render(&self, line_index: &LineIndex) -> String {
+        // gives a message along with the offending line and a caret under the bracket, e.g.
+        // error: unmatched '[' at line 2, column 3
+        //  2 | ++[>+<
+        //    |   ^
+        let message = match self.kind {
+            BracketErrorKind::UnmatchedOpen => "unmatched '['",
+            BracketErrorKind::UnmatchedClose => "unmatched ']'",
+        };
+
+        let mut output = format!(
+            "error: {} at line {}, column {}\n",
+            message, self.position.line, self.position.column
+        );
+        output.push_str(&render_source_snippet(line_index, self.position, 1));
+
+        output
+    }
+}
+
+pub fn render_source_snippet(
+    line_index: &LineIndex,
+    position: SourcePosition,
+    width: usize,
+) -> String {
+    // shows the line at the position with carets under the width characters starting there
+    let line_text = line_index.line_text(position.line);
+    let line_number = position.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+
+    // keep any tabs in front of the position so the caret lines up in a terminal, columns are 1
+    // based but a 0 is treated as the start of the line rather than underflowing
+    let before = position.column.saturating_sub(1);
+    let caret_padding = line_text
+        .chars()
+        .take(before)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
 
-    // if this ever goes negative then we know we have more closing than opening brackets
-    // which is illegal
-    let mut bracket_open_count = 0;
+    // don't underline past the end of the line
+    let remaining = line_text.chars().count().saturating_sub(before);
+    let carets = "^".repeat(width.min(remaining).max(1));
 
-    for c in program_code.chars() {
+    let mut output = String::new();
+    let _ = writeln!(output, " {} | {}", line_number, line_text);
+    let _ = writeln!(output, " {} | {}{}", gutter, caret_padding, carets);
+
+    output
+}
+
+pub fn find_bracket_errors(program_code: &str) -> Vec<BracketDiagnostic> {
+    // works on the raw source, so that the positions match what the user sees in their editor
+    let line_index = LineIndex::new(program_code);
+    let mut open_brackets = vec![];
+    let mut errors = vec![];
+
+    let report = |kind, offset| BracketDiagnostic {
+        kind,
+        offset,
+        position: line_index.position(offset),
+    };
+
+    for (offset, c) in program_code.char_indices() {
         match c {
-            '[' => {
-                bracket_open_count += 1;
-            }
+            '[' => open_brackets.push(offset),
             ']' => {
-                if bracket_open_count == 0 {
-                    return false;
+                // if there was nothing open, there is nothing for this bracket to close
+                let closed = open_brackets.pop();
+                if closed.is_none() {
+                    errors.push(report(BracketErrorKind::UnmatchedClose, offset));
                 }
-                bracket_open_count -= 1;
             }
             _ => {}
         }
     }
 
-    // if we open and close the same number of brackets, this is valid bracket order
-    bracket_open_count == 0
+    // anything left open at the end of the program never got closed
+    for offset in open_brackets {
+        errors.push(report(BracketErrorKind::UnmatchedOpen, offset));
+    }
+
+    errors.sort_by_key(|x| x.offset);
+    errors
+}
+
+pub fn render_bracket_errors(program_code: &str, errors: &[BracketDiagnostic]) -> String {
+    let line_index = LineIndex::new(program_code);
+    errors.iter().map(|x| x.render(&line_index)).collect()
 }
 
 pub fn get_c_file_prefix() -> String{
-    String::from(r#"#include <stdio.h>
+    String::from(r#"#define _GNU_SOURCE
+#include <stdio.h>
 #include <stdint.h>
 #include <stdlib.h>
+#include <string.h>
 
 #define MEMSIZE 30000
 #define IS_CHECK_ADDR
@@ -51,6 +136,10 @@ pub fn get_c_file_prefix() -> String{
 #define _INLINE inline
 #endif
 
+#ifndef max
+#define max(a, b) (((a) > (b)) ? (a) : (b))
+#endif
+
 _INLINE
 void check_addr(uint32_t addr) {
 	if (addr >= MEMSIZE) {
@@ -60,7 +149,7 @@ void check_addr(uint32_t addr) {
 }
 
 _INLINE
-void change(int32_t* m, uint32_t p, int32_t amount) {
+void change(uint8_t* m, uint32_t p, int32_t amount) {
 	m[p] += amount;
 }
 
@@ -75,7 +164,7 @@ uint32_t move(uint32_t p, int32_t amount) {
 }
 
 _INLINE
-void display_char(int32_t* m, uint32_t p) {
+void display_char(uint8_t* m, uint32_t p) {
 	putchar(m[p]);
 }
 
@@ -85,18 +174,18 @@ int32_t read_char() {
 }
 
 _INLINE
-void set_to(int32_t* m, uint32_t p, int32_t amount) {
+void set_to(uint8_t* m, uint32_t p, int32_t amount) {
 	m[p] = amount;
 }
 
 _INLINE
-void multi(int32_t* m, uint32_t p, int32_t x, int32_t y) {
+void multi(uint8_t* m, uint32_t p, int32_t x, int32_t y) {
 	int32_t pi = max((int32_t)p + x,0);
 	m[pi] += y * m[p];
 }
 
 _INLINE
-uint32_t scan_by(int32_t* m, uint32_t p, int32_t arg) {
+uint32_t scan_by(uint8_t* m, uint32_t p, int32_t arg) {
 	uint32_t new_p = p;
 
 	while (m[new_p] != 0) {
@@ -107,7 +196,37 @@ uint32_t scan_by(int32_t* m, uint32_t p, int32_t arg) {
 }
 
 _INLINE
-uint32_t moving_change(int32_t* m, uint32_t p, int32_t x, int32_t y, int32_t z) {
+uint32_t scan_right(uint8_t* m, uint32_t p) {
+	uint8_t* found = memchr(m + p, 0, MEMSIZE - p);
+
+	#ifdef CHECK_ADDR
+	if (found == NULL) {
+		check_addr(MEMSIZE);
+	}
+	#endif
+
+	return found - m;
+}
+
+_INLINE
+uint32_t scan_left(uint8_t* m, uint32_t p) {
+	#ifdef __GLIBC__
+	uint8_t* found = memrchr(m, 0, p + 1);
+
+	#ifdef CHECK_ADDR
+	if (found == NULL) {
+		check_addr(-1);
+	}
+	#endif
+
+	return found - m;
+	#else
+	return scan_by(m, p, -1);
+	#endif
+}
+
+_INLINE
+uint32_t moving_change(uint8_t* m, uint32_t p, int32_t x, int32_t y, int32_t z) {
 
 	uint32_t new_p = p;
 
@@ -121,7 +240,7 @@ uint32_t moving_change(int32_t* m, uint32_t p, int32_t x, int32_t y, int32_t z)
 }
 
 _INLINE
-uint32_t moving_set(int32_t* m, uint32_t p, int32_t x, int32_t y, int32_t z) {
+uint32_t moving_set(uint8_t* m, uint32_t p, int32_t x, int32_t y, int32_t z) {
 
 	uint32_t new_p = p;
 
@@ -134,9 +253,158 @@ uint32_t moving_set(int32_t* m, uint32_t p, int32_t x, int32_t y, int32_t z) {
 	return new_p;
 }
 
+_INLINE
+void product(uint8_t* m, uint32_t p, int32_t x, int32_t y, int32_t factor) {
+	m[p + x] += factor * m[p] * m[p + y];
+}
+
+_INLINE
+void triangle(uint8_t* m, uint32_t p, int32_t x, int32_t factor) {
+	m[p + x] += factor * (m[p] * (m[p] + 1) / 2);
+}
+
+_INLINE
+void copy(uint8_t* m, uint32_t p, int32_t x, int32_t temp) {
+	m[p + x] += m[p];
+	m[p] += m[p + temp];
+	m[p + temp] = 0;
+}
+
+_INLINE
+void carry(uint8_t* m, uint32_t p, int32_t x, int32_t y, int32_t limit) {
+	uint8_t carried = m[p] >= (uint8_t)limit;
+	m[p + x] += m[p] - limit * carried;
+	m[p + y] += carried;
+	m[p] = 0;
+}
+
+_INLINE
+void and_into(uint8_t* m, uint32_t p, int32_t x, int32_t target) {
+	if (m[p] != 0) {
+		m[p + target] = m[p + x] != 0;
+		m[p + x] = 0;
+		m[p] = 0;
+	}
+}
+
+_INLINE
+void count_down(uint8_t* m, uint32_t p, int32_t x, int32_t y) {
+	if (m[p] != 0) {
+		m[p + x] -= 1;
+		m[p + y] -= 1;
+		uint8_t taken = m[p + x] < m[p + y] ? m[p + x] : m[p + y];
+		m[p + x] -= taken;
+		m[p + y] -= taken;
+		m[p] = 0;
+	}
+}
+
+_INLINE
+void divmod(uint8_t* m, uint32_t p, int32_t q, int32_t divisor, int32_t left) {
+	// goes around at most once for every time the divisor fits, or forever if nothing is taken
+	while (m[p] != 0) {
+		m[p + q] += 1;
+		m[p + left] += m[p + divisor];
+		uint8_t taken = m[p] < m[p + left] ? m[p] : m[p + left];
+		m[p] -= taken;
+		m[p + left] -= taken;
+	}
+}
+
 int main() {
 
 	uint32_t p = 0;
-	int32_t m[MEMSIZE] = { 0 };
+	uint8_t m[MEMSIZE] = { 0 };
 	"#)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_an_unmatched_open_bracket() {
+        let source = "+[>+<";
+        let errors = find_bracket_errors(source);
+
+        assert_eq!(
+            errors,
+            [BracketDiagnostic {
+                kind: BracketErrorKind::UnmatchedOpen,
+                offset: 1,
+                position: SourcePosition { line: 1, column: 2 },
+            }]
+        );
+        assert_eq!(
+            render_bracket_errors(source, &errors),
+            "error: unmatched '[' at line 1, column 2\n 1 | +[>+<\n   |  ^\n"
+        );
+    }
+
+    #[test]
+    fn finds_an_unmatched_close_bracket_on_a_later_line() {
+        let source = "+++\n[-]\n  -]+";
+        let errors = find_bracket_errors(source);
+
+        assert_eq!(
+            errors,
+            [BracketDiagnostic {
+                kind: BracketErrorKind::UnmatchedClose,
+                offset: 11,
+                position: SourcePosition { line: 3, column: 4 },
+            }]
+        );
+        assert_eq!(
+            render_bracket_errors(source, &errors),
+            "error: unmatched ']' at line 3, column 4\n 3 |   -]+\n   |    ^\n"
+        );
+    }
+
+    #[test]
+    fn balanced_brackets_are_fine() {
+        assert!(find_bracket_errors("+[>[-]<]\n[]").is_empty());
+        // brackets in comments count, as BF has no other way to write comments
+        assert_eq!(find_bracket_errors("not ] closed").len(), 1);
+    }
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        // the comment is 4 characters, but 8 bytes
+        let source = "é→ü+ [";
+        let errors = find_bracket_errors(source);
+
+        assert_eq!(errors[0].offset, 9);
+        assert_eq!(errors[0].position, SourcePosition { line: 1, column: 6 });
+        assert_eq!(
+            render_bracket_errors(source, &errors),
+            "error: unmatched '[' at line 1, column 6\n 1 | é→ü+ [\n   |      ^\n"
+        );
+    }
+
+    #[test]
+    fn tabs_before_the_caret_are_kept() {
+        let source = "\t+\t]";
+        let errors = find_bracket_errors(source);
+
+        assert_eq!(errors[0].position, SourcePosition { line: 1, column: 4 });
+        assert_eq!(
+            render_bracket_errors(source, &errors),
+            "error: unmatched ']' at line 1, column 4\n 1 | \t+\t]\n   | \t \t^\n"
+        );
+    }
+
+    #[test]
+    fn snippets_stay_on_the_line() {
+        let line_index = LineIndex::new("++[-]\n>>");
+
+        // carets stop at the end of the line, and a column of 0 is the start of it
+        assert_eq!(
+            render_source_snippet(&line_index, SourcePosition { line: 1, column: 3 }, 10),
+            " 1 | ++[-]\n   |   ^^^\n"
+        );
+        assert_eq!(
+            render_source_snippet(&line_index, SourcePosition { line: 2, column: 0 }, 1),
+            " 2 | >>\n   | ^\n"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct Span {
+    // byte range start..end into the original source that some code came from
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    #[must_use]
+    pub fn merge(self, other: Self) -> Self {
+        // the smallest span that covers both spans
+        Self {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct SourcePosition {
+    // both line and column are 1 based, columns are counted in characters
+    pub line: usize,
+    pub column: usize,
+}
+
+pub struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> Self {
+        // record the byte offset of the start of every line so lookups are a binary search
+        let mut line_starts = vec![0];
+
+        for (pos, c) in source.char_indices() {
+            if c == '\n' {
+                line_starts.push(pos + 1);
+            }
+        }
+
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    #[must_use]
+    pub fn position(&self, offset: usize) -> SourcePosition {
+        let offset = offset.min(self.source.len());
+
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+
+        let line_start = self.line_starts[line];
+        let column = self
+            .source
+            .get(line_start..offset)
+            .map_or(offset - line_start, |x| x.chars().count());
+
+        SourcePosition {
+            line: line + 1,
+            column: column + 1,
+        }
+    }
+
+    #[must_use]
+    pub fn line_text(&self, line: usize) -> &'a str {
+        // the text of a 1 based line, without the line ending
+        let start = self.line_starts[line - 1];
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(self.source.len(), |&x| x - 1);
+
+        self.source[start..end].trim_end_matches('\r')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_one_based() {
+        let source = "+[\n-\r\n]>";
+        let line_index = LineIndex::new(source);
+
+        assert_eq!(
+            line_index.position(0),
+            SourcePosition { line: 1, column: 1 }
+        );
+        assert_eq!(
+            line_index.position(1),
+            SourcePosition { line: 1, column: 2 }
+        );
+        // the newline itself is still on the line it ends
+        assert_eq!(
+            line_index.position(2),
+            SourcePosition { line: 1, column: 3 }
+        );
+        assert_eq!(
+            line_index.position(3),
+            SourcePosition { line: 2, column: 1 }
+        );
+        assert_eq!(
+            line_index.position(6),
+            SourcePosition { line: 3, column: 1 }
+        );
+        assert_eq!(
+            line_index.position(7),
+            SourcePosition { line: 3, column: 2 }
+        );
+        assert_eq!(line_index.line_text(2), "-");
+        assert_eq!(line_index.line_text(3), "]>");
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let line_index = LineIndex::new("ab\nçé+");
+
+        assert_eq!(
+            line_index.position(7),
+            SourcePosition { line: 2, column: 3 }
+        );
+        // an offset in the middle of a character counts the bytes up to it
+        assert_eq!(
+            line_index.position(4),
+            SourcePosition { line: 2, column: 2 }
+        );
+    }
+
+    #[test]
+    fn offsets_past_the_end_are_the_end() {
+        let line_index = LineIndex::new("+\n+");
+
+        assert_eq!(
+            line_index.position(100),
+            SourcePosition { line: 2, column: 2 }
+        );
+        assert_eq!(
+            LineIndex::new("").position(0),
+            SourcePosition { line: 1, column: 1 }
+        );
+    }
+
+    #[test]
+    fn merged_spans_cover_both() {
+        assert_eq!(Span::new(3, 5).merge(Span::new(1, 4)), Span::new(1, 5));
+        assert_eq!(Span::new(0, 2).merge(Span::new(6, 8)), Span::new(0, 8));
+    }
+}
@@ -1,8 +1,11 @@
+// the number of cells on the tape
+pub const TAPE_SIZE: usize = 30000;
+
 #[derive(Debug, Copy, Clone)]
 pub struct ProgramState {
     pub ip: usize,
     pub dp: usize,
-    pub memory: [u8; 30000],
+    pub memory: [u8; TAPE_SIZE],
 }
 
 impl ProgramState {
@@ -10,13 +13,13 @@ impl ProgramState {
         Self {
             ip: 0,
             dp: 0,
-            memory: [0; 30000],
+            memory: [0; TAPE_SIZE],
         }
     }
 
     #[must_use]
     pub const fn is_valid_dp_location(&self, pos: usize) -> bool {
-        pos < 30000
+        pos < TAPE_SIZE
     }
 }
 
@@ -0,0 +1,305 @@
+use crate::opcodes::{Opcode, PRINT_WIDTH};
+use crate::state::TAPE_SIZE;
+use std::fmt;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ValidationError {
+    // a jump that points past the end of the program
+    JumpOutOfRange { ip: usize, target: usize },
+    // a jump that doesn't point at its partner, or a loop that is never opened or closed
+    UnmatchedJump { ip: usize },
+    // a move or offset that is further than the whole tape, it can only ever leave the tape
+    OffsetOutOfRange { ip: usize },
+    // a value to set a cell to (or multiply by) that doesn't fit in a cell, either as -128 to 127
+    // or 0 to 255, changes are left alone as they wrap around anyway
+    ValueOutOfRange { ip: usize },
+    // putting or reading 0 chars, nothing the compiler makes
+    ZeroCount { ip: usize },
+    // putting or reading more chars at once than the VM has room to count
+    CountOutOfRange { ip: usize },
+    // a PRINT that says it holds more bytes than it has room for
+    PrintTooLong { ip: usize },
+    // a CARRY at 0, which isn't a chain of loops BF could have
+    ZeroLimit { ip: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::JumpOutOfRange { ip, target } => write!(
+                f,
+                "the jump at instruction {} goes to {}, which is past the end of the program",
+                ip, target
+            ),
+            Self::UnmatchedJump { ip } => {
+                write!(f, "the jump at instruction {} does not have a matching jump", ip)
+            }
+            Self::OffsetOutOfRange { ip } => {
+                write!(f, "the offset at instruction {} is larger than the tape", ip)
+            }
+            Self::ValueOutOfRange { ip } => {
+                write!(f, "the value at instruction {} does not fit in a cell", ip)
+            }
+            Self::ZeroCount { ip } => {
+                write!(f, "the instruction {} reads or writes 0 chars", ip)
+            }
+            Self::CountOutOfRange { ip } => {
+                write!(f, "the instruction {} reads or writes more than {} chars", ip, u32::MAX)
+            }
+            Self::PrintTooLong { ip } => {
+                write!(f, "the PRINT at instruction {} is longer than {} bytes", ip, PRINT_WIDTH)
+            }
+            Self::ZeroLimit { ip } => {
+                write!(f, "the CARRY at instruction {} carries at 0", ip)
+            }
+        }
+    }
+}
+
+fn is_offset_in_range(offset: i32) -> bool {
+    // the VM keeps offsets as 16 bit numbers, which is only wider than the tape as long as the
+    // tape stays small enough
+    offset.unsigned_abs() < TAPE_SIZE as u32 && i16::try_from(offset).is_ok()
+}
+
+fn is_cell_value(value: i32) -> bool {
+    (-128..=255).contains(&value)
+}
+
+fn check_operands(ip: usize, op: Opcode) -> Result<(), ValidationError> {
+    let (offsets, values, count): (&[i32], &[i32], Option<usize>) = match op {
+        Opcode::CHANGE { offset, .. } => (&[offset], &[], None),
+        Opcode::MOVE { arg } | Opcode::SCANBY { arg } => (&[arg], &[], None),
+        Opcode::PUTCHAR { arg, offset } | Opcode::READCHAR { arg, offset } => {
+            (&[offset], &[], Some(arg))
+        }
+        Opcode::SETTO { arg, offset } => (&[offset], &[arg], None),
+        Opcode::MULTI { arg1, arg2 } => (&[arg1], &[arg2], None),
+        Opcode::MOVINGCHANGE { arg1, arg3, .. } => (&[arg1, arg3], &[], None),
+        Opcode::MOVINGSET { arg1, arg2, arg3 } => (&[arg1, arg3], &[arg2], None),
+        Opcode::PRODUCT { arg1, arg2, arg3 } => (&[arg1, arg2], &[arg3], None),
+        Opcode::TRIANGLE { arg1, arg2 } => (&[arg1], &[arg2], None),
+        Opcode::COPY { arg1, arg2 }
+        | Opcode::AND { arg1, arg2 }
+        | Opcode::COUNTDOWN { arg1, arg2 } => (&[arg1, arg2], &[], None),
+        Opcode::DIVMOD { arg1, arg2, arg3 } => (&[arg1, arg2, arg3], &[], None),
+        Opcode::CARRY { arg1, arg2, arg3 } => (&[arg1, arg2], &[arg3], None),
+        Opcode::PRINT { len, .. } => (&[], &[], Some(usize::from(len))),
+        Opcode::JUMPIFZERO { .. } | Opcode::JUMPIFNZERO { .. } | Opcode::ENDIF { .. } => {
+            (&[], &[], None)
+        }
+    };
+
+    if !offsets.iter().all(|&x| is_offset_in_range(x)) {
+        return Err(ValidationError::OffsetOutOfRange { ip });
+    }
+
+    if !values.iter().all(|&x| is_cell_value(x)) {
+        return Err(ValidationError::ValueOutOfRange { ip });
+    }
+
+    if count == Some(0) {
+        return Err(ValidationError::ZeroCount { ip });
+    }
+
+    if count.is_some_and(|x| u32::try_from(x).is_err()) {
+        return Err(ValidationError::CountOutOfRange { ip });
+    }
+
+    if let Opcode::PRINT { len, .. } = op
+        && usize::from(len) > PRINT_WIDTH
+    {
+        return Err(ValidationError::PrintTooLong { ip });
+    }
+
+    if let Opcode::CARRY { arg3, .. } = op
+        && arg3 as u8 == 0
+    {
+        return Err(ValidationError::ZeroLimit { ip });
+    }
+
+    Ok(())
+}
+
+pub fn validate(opcodes: &[Opcode]) -> Result<(), ValidationError> {
+    // checks that the VM can run the opcodes as they are, every jump has to point at its
+    // partner with the loops nested properly, and the operands have to make sense for the tape
+    let mut loop_stack = vec![];
+
+    for (ip, &op) in opcodes.iter().enumerate() {
+        check_operands(ip, op)?;
+
+        match op {
+            Opcode::JUMPIFZERO { arg } | Opcode::JUMPIFNZERO { arg } | Opcode::ENDIF { arg }
+                if arg >= opcodes.len() =>
+            {
+                return Err(ValidationError::JumpOutOfRange { ip, target: arg });
+            }
+            Opcode::JUMPIFZERO { arg } => {
+                if opcodes[arg] != (Opcode::JUMPIFNZERO { arg: ip })
+                    && opcodes[arg] != (Opcode::ENDIF { arg: ip })
+                {
+                    return Err(ValidationError::UnmatchedJump { ip });
+                }
+                loop_stack.push(ip);
+            }
+            Opcode::JUMPIFNZERO { arg } | Opcode::ENDIF { arg } => {
+                // the partners are checked from the opening side, this makes sure they nest
+                let open_instr = loop_stack.pop();
+
+                if open_instr != Some(arg) {
+                    return Err(ValidationError::UnmatchedJump { ip });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(ip) = loop_stack.pop() {
+        return Err(ValidationError::UnmatchedJump { ip });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::strip_spans;
+    use crate::passes::PassManager;
+
+    #[test]
+    fn accepts_what_the_compiler_makes() {
+        let source = include_str!("../examples/factor.bf");
+
+        for level in 0..=3 {
+            let opcodes = strip_spans(&PassManager::with_level(level).compile(source.to_string()));
+            assert_eq!(validate(&opcodes), Ok(()), "at -O{}", level);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_jumps() {
+        assert_eq!(
+            validate(&[
+                Opcode::JUMPIFZERO { arg: 5 },
+                Opcode::JUMPIFNZERO { arg: 0 }
+            ]),
+            Err(ValidationError::JumpOutOfRange { ip: 0, target: 5 })
+        );
+        assert_eq!(
+            validate(&[Opcode::JUMPIFZERO { arg: 1 }, Opcode::MOVE { arg: 1 }]),
+            Err(ValidationError::UnmatchedJump { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::MOVE { arg: 1 }, Opcode::JUMPIFNZERO { arg: 0 }]),
+            Err(ValidationError::UnmatchedJump { ip: 1 })
+        );
+        assert_eq!(
+            validate(&[
+                Opcode::JUMPIFZERO { arg: 3 },
+                Opcode::JUMPIFZERO { arg: 2 },
+                Opcode::JUMPIFNZERO { arg: 1 },
+                Opcode::JUMPIFNZERO { arg: 0 },
+            ]),
+            Ok(())
+        );
+        // every jump points at a jump of the right kind, but the loops cross instead of nesting
+        assert_eq!(
+            validate(&[
+                Opcode::JUMPIFZERO { arg: 2 },
+                Opcode::JUMPIFZERO { arg: 3 },
+                Opcode::JUMPIFNZERO { arg: 0 },
+                Opcode::JUMPIFNZERO { arg: 1 },
+            ]),
+            Err(ValidationError::UnmatchedJump { ip: 2 })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        let tape = TAPE_SIZE as i32;
+
+        assert_eq!(
+            validate(&[Opcode::MOVE { arg: -tape }]),
+            Err(ValidationError::OffsetOutOfRange { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::COPY {
+                arg1: 1,
+                arg2: tape
+            }]),
+            Err(ValidationError::OffsetOutOfRange { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::SETTO {
+                arg: 256,
+                offset: 0
+            }]),
+            Err(ValidationError::ValueOutOfRange { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::MULTI {
+                arg1: 1,
+                arg2: -129
+            }]),
+            Err(ValidationError::ValueOutOfRange { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::READCHAR { arg: 0, offset: 0 }]),
+            Err(ValidationError::ZeroCount { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::PUTCHAR {
+                arg: u32::MAX as usize + 1,
+                offset: 0
+            }]),
+            Err(ValidationError::CountOutOfRange { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::PUTCHAR {
+                arg: u32::MAX as usize,
+                offset: 0
+            }]),
+            Ok(())
+        );
+        assert_eq!(
+            validate(&[Opcode::PRINT {
+                bytes: [0; PRINT_WIDTH],
+                len: 0
+            }]),
+            Err(ValidationError::ZeroCount { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::PRINT {
+                bytes: [0; PRINT_WIDTH],
+                len: 9
+            }]),
+            Err(ValidationError::PrintTooLong { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::CARRY {
+                arg1: 1,
+                arg2: 2,
+                arg3: 256
+            }]),
+            Err(ValidationError::ValueOutOfRange { ip: 0 })
+        );
+        assert_eq!(
+            validate(&[Opcode::CARRY {
+                arg1: 1,
+                arg2: 2,
+                arg3: 0
+            }]),
+            Err(ValidationError::ZeroLimit { ip: 0 })
+        );
+        // changes wrap around, so any amount is fine
+        assert_eq!(
+            validate(&[Opcode::CHANGE {
+                arg: 1000,
+                offset: 0
+            }]),
+            Ok(())
+        );
+    }
+}